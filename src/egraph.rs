@@ -1,6 +1,7 @@
 use crate::*;
 use std::{
-    borrow::BorrowMut,
+    borrow::{BorrowMut, Cow},
+    collections::BTreeMap,
     fmt::{self, Debug, Display},
 };
 
@@ -84,6 +85,78 @@ pub struct EGraph<L: Language, N: Analysis<L>> {
     /// Only manually set it if you know what you're doing.
     #[cfg_attr(feature = "serde-1", serde(skip))]
     pub clean: bool,
+    /// Whether nested AC operators are flattened, see
+    /// [`EGraph::with_ac_canonicalization`].
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    ac_canonical: bool,
+    /// The changes made since the open snapshots, see [`EGraph::push`].
+    #[cfg_attr(feature = "serde-1", serde(skip))]
+    undo: Option<UndoLog<L, N::Data>>,
+    /// The contexts made by [`EGraph::with_assumption`].
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    contexts: Vec<Context<L>>,
+    /// The enodes added since the last rebuild, which it passes on to the
+    /// contexts
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    context_added: Vec<(L, Id)>,
+    /// The unions made since the last rebuild, likewise
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    context_unions: Vec<(Id, Id)>,
+    /// The context that [`EGraph::union`] unions in while
+    /// [`EGraph::apply_in`] applies rules.
+    #[cfg_attr(feature = "serde-1", serde(skip))]
+    firing_in: Option<ContextId>,
+}
+
+/// A change to an [`EGraph`] that [`EGraph::pop`] undoes.
+#[derive(Clone)]
+enum Undo<L, D> {
+    /// A memo entry was set; this is its old value
+    Memo(L, Option<Id>),
+    /// A class was made
+    Class(Id),
+    /// A parent was appended to a class
+    Parent(Id),
+    /// The data of a class before it changed
+    Data(Id, D),
+    /// The nodes of a class before rebuilding changed them
+    Nodes(Id, Vec<L>),
+    /// Class `from` was merged into class `into`, which had `nodes` nodes
+    /// and `parents` parents
+    Merge {
+        into: Id,
+        from: Id,
+        nodes: usize,
+        parents: usize,
+        into_data: D,
+        from_data: D,
+    },
+}
+
+#[derive(Clone)]
+struct Snapshot<L> {
+    undo_len: usize,
+    uf_size: usize,
+    uf_recorded: usize,
+    /// How far each context had got
+    contexts: Vec<ContextMark>,
+    /// Saved by the first rebuild after the snapshot
+    classes_by_op: Option<HashMap<std::mem::Discriminant<L>, HashSet<Id>>>,
+}
+
+#[derive(Clone)]
+struct UndoLog<L, D> {
+    snapshots: Vec<Snapshot<L>>,
+    undo: Vec<Undo<L, D>>,
+    // lets the e-graph save data without requiring `Clone` everywhere
+    clone_data: fn(&D) -> D,
+}
+
+impl<L, D> UndoLog<L, D> {
+    fn save_data(&mut self, id: Id, data: &D) {
+        let data = (self.clone_data)(data);
+        self.undo.push(Undo::Data(id, data));
+    }
 }
 
 #[cfg(feature = "serde-1")]
@@ -120,6 +193,12 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
             memo: Default::default(),
             analysis_pending: Default::default(),
             classes_by_op: Default::default(),
+            ac_canonical: false,
+            undo: None,
+            contexts: vec![],
+            context_added: vec![],
+            context_unions: vec![],
+            firing_in: None,
         }
     }
 
@@ -129,7 +208,15 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
     }
 
     /// Returns an mutating iterator over the eclasses in the egraph.
+    ///
+    /// While a snapshot is open (see [`EGraph::push`]), this saves the data
+    /// of every eclass.
     pub fn classes_mut(&mut self) -> impl ExactSizeIterator<Item = &mut EClass<L, N::Data>> {
+        if let Some(undo) = &mut self.undo {
+            for class in self.classes.values() {
+                undo.save_data(class.id, &class.data);
+            }
+        }
         self.classes.values_mut()
     }
 
@@ -201,6 +288,56 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
         self.explain.is_some()
     }
 
+    /// Flatten nested AC operators (see [`Language::is_ac`]) into n-ary
+    /// nodes with sorted children, both in the terms added by
+    /// [`add_expr`](EGraph::add_expr) and in the terms an [`Extractor`]
+    /// pulls out of this `EGraph` (see [`RecExpr::flatten_ac`]).
+    /// The children of AC enodes stay sorted as [`rebuild`](EGraph::rebuild)
+    /// canonicalizes them, and so do those of enodes passed to
+    /// [`add`](EGraph::add) or [`lookup`](EGraph::lookup).
+    ///
+    /// Only commutativity stays canonical after that: flattening happens
+    /// when terms are added, and an enode like `(+ x y)` is not flattened
+    /// again when `x` is later unioned with another sum.
+    /// Rules like `(+ ?a (+ ?b ?c)) => (+ (+ ?a ?b) ?c)` are still needed
+    /// to reassociate those.
+    ///
+    /// Only operators with a variable number of children are flattened.
+    /// [`Pattern`]s match n-ary nodes modulo AC as well, so rules written
+    /// with binary patterns still apply. Pattern variables are still only
+    /// bound to existing e-classes, though: `(+ ?a (f ?b))` can't match
+    /// `(+ x y (f z))` unless some e-class is the sum of `x` and `y`.
+    /// That is the price of the smaller e-graph.
+    ///
+    /// # Example
+    /// ```
+    /// # use egg::*;
+    /// define_language! {
+    ///     enum Math {
+    ///         #[ac] "+" = Add(Vec<Id>),
+    ///         Symbol(Symbol),
+    ///     }
+    /// }
+    /// let mut egraph = EGraph::<Math, ()>::default().with_ac_canonicalization();
+    /// let a = egraph.add_expr(&"(+ c (+ b a))".parse().unwrap());
+    /// let b = egraph.add_expr(&"(+ (+ a c) b)".parse().unwrap());
+    /// assert_eq!(a, b);
+    /// assert_eq!(egraph.total_size(), 4);
+    ///
+    /// egraph.rebuild();
+    /// let pattern: Pattern<Math> = "(+ ?x b ?y)".parse().unwrap();
+    /// assert_eq!(pattern.search(&egraph)[0].substs.len(), 2);
+    /// ```
+    pub fn with_ac_canonicalization(mut self) -> Self {
+        self.ac_canonical = true;
+        self
+    }
+
+    /// Check if AC canonicalization is enabled.
+    pub fn is_ac_canonicalization_enabled(&self) -> bool {
+        self.ac_canonical
+    }
+
     /// When explanations are enabled, this function
     /// produces an [`Explanation`] describing why two expressions are equivalent.
     ///
@@ -315,12 +452,20 @@ impl<L: Language, N: Analysis<L>> std::ops::Index<Id> for EGraph<L, N> {
 
 /// Given an `Id` using the `&mut egraph[id]` syntax, retrieve a mutable
 /// reference to the e-class.
+///
+/// While a snapshot is open (see [`EGraph::push`]), this saves the data of
+/// the e-class, so only changes to its data can be undone.
 impl<L: Language, N: Analysis<L>> std::ops::IndexMut<Id> for EGraph<L, N> {
     fn index_mut(&mut self, id: Id) -> &mut Self::Output {
         let id = self.find_mut(id);
-        self.classes
+        let class = self
+            .classes
             .get_mut(&id)
-            .unwrap_or_else(|| panic!("Invalid id {}", id))
+            .unwrap_or_else(|| panic!("Invalid id {}", id));
+        if let Some(undo) = &mut self.undo {
+            undo.save_data(id, &class.data);
+        }
+        class
     }
 }
 
@@ -346,6 +491,13 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
 
     /// Adds an expr to the egraph, and returns the uncanonicalized id of the top enode.
     fn add_expr_internal(&mut self, expr: &RecExpr<L>) -> Id {
+        let flat;
+        let expr = if self.ac_canonical {
+            flat = expr.flatten_ac();
+            &flat
+        } else {
+            expr
+        };
         let nodes = expr.as_ref();
         let mut new_ids = Vec::with_capacity(nodes.len());
         let mut new_node_q = Vec::with_capacity(nodes.len());
@@ -385,12 +537,16 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
         for node in nodes {
             match node {
                 ENodeOrVar::Var(var) => {
-                    let id = subst[*var];
+                    // sequence variables are spliced in by their parent
+                    let id = match var.is_sequence() {
+                        true => Id::from(0),
+                        false => subst[*var],
+                    };
                     new_ids.push(id);
                     new_node_q.push(false);
                 }
                 ENodeOrVar::ENode(node) => {
-                    let new_node = node.clone().map_children(|i| new_ids[usize::from(i)]);
+                    let new_node = pattern::instantiate_node(node, nodes, &new_ids, subst);
                     let size_before = self.unionfind.size();
                     let next_id = self.add_internal(new_node);
                     if self.unionfind.size() > size_before {
@@ -451,13 +607,31 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
     {
         let enode = enode.borrow_mut();
         enode.update_children(|id| self.find(id));
+        self.sort_ac_children(enode);
         self.memo.get(enode).copied()
     }
 
+    /// Sorts the children of an AC enode if AC canonicalization is on.
+    /// The children must already be canonical.
+    fn sort_ac_children(&self, enode: &mut L) {
+        if self.ac_canonical && enode.is_ac() {
+            enode.children_mut().sort_unstable();
+        }
+    }
+
     /// Lookup the eclass of the given [`RecExpr`].
     ///
     /// Equivalent to the last value in [`EGraph::lookup_expr_ids`].
+    /// If AC canonicalization is on, `expr` is flattened first, like in
+    /// [`add_expr`](EGraph::add_expr).
     pub fn lookup_expr(&self, expr: &RecExpr<L>) -> Option<Id> {
+        let flat;
+        let expr = if self.ac_canonical {
+            flat = expr.flatten_ac();
+            &flat
+        } else {
+            expr
+        };
         self.lookup_expr_ids(expr)
             .and_then(|ids| ids.last().copied())
     }
@@ -474,6 +648,78 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
         Some(new_ids)
     }
 
+    /// Checks whether two terms are equal in this e-graph, without adding
+    /// them to it.
+    ///
+    /// Returns `Some(true)` if they are equal, and `Some(false)` if both are
+    /// in the e-graph but in different eclasses.
+    /// The parts of the terms that aren't in the e-graph are compared by
+    /// congruence, so `(f a)` and `(f b)` are equal when `a` and `b` are,
+    /// even if neither is in the e-graph.
+    /// Otherwise, when some part of either term is missing, this returns
+    /// `None`, since adding it could make them equal.
+    /// If AC canonicalization is on, both terms are flattened and their AC
+    /// enodes sorted first, like in [`add_expr`](EGraph::add_expr).
+    ///
+    /// The e-graph must be [`rebuild`](EGraph::rebuild) after any change.
+    ///
+    /// # Example
+    /// ```
+    /// use egg::*;
+    /// let mut egraph = EGraph::<SymbolLang, ()>::default();
+    /// let a = egraph.add(SymbolLang::leaf("a"));
+    /// let b = egraph.add(SymbolLang::leaf("b"));
+    /// let c = egraph.add(SymbolLang::leaf("c"));
+    /// egraph.union(a, b);
+    /// egraph.rebuild();
+    ///
+    /// let q = |x: &str, y: &str| egraph.query_equal(&x.parse().unwrap(), &y.parse().unwrap());
+    /// assert_eq!(q("a", "b"), Some(true));
+    /// assert_eq!(q("a", "c"), Some(false));
+    /// assert_eq!(q("(g (f a))", "(g (f b))"), Some(true));
+    /// assert_eq!(q("(f a)", "(f c)"), None);
+    /// assert_eq!(egraph.number_of_classes(), 2);
+    /// ```
+    pub fn query_equal(&self, a: &RecExpr<L>, b: &RecExpr<L>) -> Option<bool> {
+        assert!(self.clean, "Can't query a dirty e-graph");
+        // the missing enodes get ids past the end of the union-find
+        let size = self.unionfind.size();
+        let present = |id: Id| usize::from(id) < size;
+        let mut missing = HashMap::<L, Id>::default();
+        let mut class_of = |expr: &RecExpr<L>| {
+            let flat;
+            let expr = if self.ac_canonical {
+                flat = expr.flatten_ac();
+                &flat
+            } else {
+                expr
+            };
+            let mut ids: Vec<Id> = Vec::with_capacity(expr.as_ref().len());
+            for node in expr.as_ref() {
+                let mut node = node.clone().map_children(|i| ids[usize::from(i)]);
+                node.update_children(|c| if present(c) { self.find(c) } else { c });
+                self.sort_ac_children(&mut node);
+                let id = if node.all(present) {
+                    self.memo.get(&node).map(|&id| self.find(id))
+                } else {
+                    None
+                };
+                let next = Id::from(size + missing.len());
+                ids.push(id.unwrap_or_else(|| *missing.entry(node).or_insert(next)));
+            }
+            *ids.last().unwrap()
+        };
+
+        let (a, b) = (class_of(a), class_of(b));
+        if a == b {
+            Some(true)
+        } else if present(a) && present(b) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
     /// Adds an enode to the [`EGraph`].
     ///
     /// When adding an enode, to the egraph, [`add`] it performs
@@ -516,8 +762,11 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
                 explain.add(original, id, id);
             }
 
-            // now that we updated explanations, run the analysis for the new eclass
+            // now that we updated explanations, run the analysis for the new eclass;
+            // its unions hold in the e-graph even while applying rules in a context
+            let firing_in = self.firing_in.take();
             N::modify(self, id);
+            self.firing_in = firing_in;
             self.clean = false;
             id
         }
@@ -537,13 +786,24 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
         // add this enode to the parent lists of its children
         enode.for_each(|child| {
             let tup = (enode.clone(), id);
-            self[child].parents.push(tup);
+            let child = self.find_mut(child);
+            self.classes.get_mut(&child).unwrap().parents.push(tup);
+            if let Some(undo) = &mut self.undo {
+                undo.undo.push(Undo::Parent(child));
+            }
         });
 
         // TODO is this needed?
         self.pending.push((enode.clone(), id));
+        if !self.contexts.is_empty() {
+            self.context_added.push((enode.clone(), id));
+        }
 
         self.classes.insert(id, class);
+        if let Some(undo) = &mut self.undo {
+            undo.undo.push(Undo::Class(id));
+            undo.undo.push(Undo::Memo(enode.clone(), None));
+        }
         assert!(self.memo.insert(enode, id).is_none());
 
         id
@@ -620,6 +880,9 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
         if self.explain.is_some() {
             panic!("Use union_instantiations when explanation mode is enabled.");
         }
+        if let Some(ctx) = self.firing_in {
+            return self.union_in(ctx, id1, id2);
+        }
         self.perform_union(id1, id2, None, false)
     }
 
@@ -651,12 +914,27 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
 
         // make id1 the new root
         self.unionfind.union(id1, id2);
+        if !self.contexts.is_empty() {
+            self.context_unions.push((id1, id2));
+        }
 
         assert_ne!(id1, id2);
         let class2 = self.classes.remove(&id2).unwrap();
         let class1 = self.classes.get_mut(&id1).unwrap();
         assert_eq!(id1, class1.id);
 
+        if let Some(undo) = &mut self.undo {
+            let merge = Undo::Merge {
+                into: id1,
+                from: id2,
+                nodes: class1.nodes.len(),
+                parents: class1.parents.len(),
+                into_data: (undo.clone_data)(&class1.data),
+                from_data: (undo.clone_data)(&class2.data),
+            };
+            undo.undo.push(merge);
+        }
+
         self.pending.extend(class2.parents.iter().cloned());
         let did_merge = self.analysis.merge(&mut class1.data, class2.data);
         if did_merge.0 {
@@ -718,17 +996,35 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
     #[inline(never)]
     fn rebuild_classes(&mut self) -> usize {
         let mut classes_by_op = std::mem::take(&mut self.classes_by_op);
+        if let Some(undo) = &mut self.undo {
+            let snapshot = undo.snapshots.last_mut().unwrap();
+            if snapshot.classes_by_op.is_none() {
+                snapshot.classes_by_op = Some(std::mem::take(&mut classes_by_op));
+            }
+        }
         classes_by_op.values_mut().for_each(|ids| ids.clear());
 
         let mut trimmed = 0;
         let uf = &mut self.unionfind;
+        let undo = &mut self.undo;
+        let ac = self.ac_canonical;
+        let sorted_ac = |n: &L| !ac || !n.is_ac() || n.children().windows(2).all(|w| w[0] <= w[1]);
 
         for class in self.classes.values_mut() {
             let old_len = class.len();
-            class
-                .nodes
-                .iter_mut()
-                .for_each(|n| n.update_children(|id| uf.find_mut(id)));
+            if let Some(undo) = undo {
+                let canonical = |n: &L| n.all(|id| uf.find(id) == id) && sorted_ac(n);
+                let sorted = class.nodes.windows(2).all(|w| w[0] < w[1]);
+                if !sorted || !class.nodes.iter().all(canonical) {
+                    undo.undo.push(Undo::Nodes(class.id, class.nodes.clone()));
+                }
+            }
+            class.nodes.iter_mut().for_each(|n| {
+                n.update_children(|id| uf.find_mut(id));
+                if ac && n.is_ac() {
+                    n.children_mut().sort_unstable();
+                }
+            });
             class.nodes.sort_unstable();
             class.nodes.dedup();
 
@@ -807,6 +1103,11 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
         while !self.pending.is_empty() || !self.analysis_pending.is_empty() {
             while let Some((mut node, class)) = self.pending.pop() {
                 node.update_children(|id| self.find_mut(id));
+                self.sort_ac_children(&mut node);
+                if let Some(undo) = &mut self.undo {
+                    let old = self.memo.get(&node).copied();
+                    undo.undo.push(Undo::Memo(node.clone(), old));
+                }
                 if let Some(memo_class) = self.memo.insert(node, class) {
                     let did_something = self.perform_union(
                         memo_class,
@@ -823,8 +1124,12 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
                 let node_data = N::make(self, &node);
                 let class = self.classes.get_mut(&class_id).unwrap();
 
+                let old_data = self.undo.as_ref().map(|u| (u.clone_data)(&class.data));
                 let did_merge = self.analysis.merge(&mut class.data, node_data);
                 if did_merge.0 {
+                    if let (Some(undo), Some(data)) = (&mut self.undo, old_data) {
+                        undo.undo.push(Undo::Data(class_id, data));
+                    }
                     self.analysis_pending.extend(class.parents.iter().cloned());
                     N::modify(self, class_id)
                 }
@@ -903,6 +1208,7 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
 
         debug_assert!(self.check_memo());
         self.clean = true;
+        self.close_contexts();
         n_unions
     }
 
@@ -915,44 +1221,1746 @@ impl<L: Language, N: Analysis<L>> EGraph<L, N> {
     }
 }
 
-struct EGraphDump<'a, L: Language, N: Analysis<L>>(&'a EGraph<L, N>);
+// Snapshots
+impl<L: Language, N: Analysis<L>> EGraph<L, N> {
+    /// Opens a snapshot of this e-graph that [`pop`](EGraph::pop) returns to.
+    ///
+    /// Snapshots nest, so each `pop` closes the most recent open one.
+    /// While any are open, the e-graph records how to undo its changes
+    /// (memo entries, new eclasses, unions, parent lists, analysis data and
+    /// the [contexts](EGraph::with_assumption)),
+    /// so a `pop` takes time proportional to the work done since its `push`
+    /// rather than to the size of the e-graph.
+    /// This is cheaper than [`Clone`] for trying out a branch of rewrites
+    /// and backtracking if it doesn't pan out.
+    ///
+    /// The e-graph must be [`clean`](EGraph::clean), and explanations must
+    /// be disabled.
+    /// Changes to eclasses through [`IndexMut`](std::ops::IndexMut) or
+    /// [`classes_mut`](EGraph::classes_mut) are only undone for their data,
+    /// and changes to the [`analysis`](EGraph::analysis) itself are not
+    /// undone at all.
+    ///
+    /// # Example
+    /// ```
+    /// use egg::{*, SymbolLang as S};
+    /// let mut egraph = EGraph::<S, ()>::default();
+    /// let ax = egraph.add_expr(&"(+ a x)".parse().unwrap());
+    /// let ay = egraph.add_expr(&"(+ a y)".parse().unwrap());
+    /// egraph.rebuild();
+    ///
+    /// egraph.push();
+    /// let x = egraph.add(S::leaf("x"));
+    /// let y = egraph.add(S::leaf("y"));
+    /// egraph.union(x, y);
+    /// egraph.add_expr(&"(f x)".parse().unwrap());
+    /// egraph.rebuild();
+    /// assert_eq!(egraph.find(ax), egraph.find(ay));
+    ///
+    /// egraph.pop();
+    /// assert_ne!(egraph.find(ax), egraph.find(ay));
+    /// assert_eq!(egraph.number_of_classes(), 5);
+    /// assert!(egraph.clean);
+    /// ```
+    pub fn push(&mut self)
+    where
+        N::Data: Clone,
+    {
+        assert!(self.clean, "Can't push a snapshot of a dirty e-graph");
+        assert!(
+            self.explain.is_none(),
+            "Snapshots don't support explanations"
+        );
+        self.unionfind.record();
+        let contexts = self.contexts.iter_mut().map(Context::mark).collect();
+        let undo = self.undo.get_or_insert_with(|| UndoLog {
+            snapshots: vec![],
+            undo: vec![],
+            clone_data: N::Data::clone,
+        });
+        undo.snapshots.push(Snapshot {
+            undo_len: undo.undo.len(),
+            uf_size: self.unionfind.size(),
+            uf_recorded: self.unionfind.recorded(),
+            contexts,
+            classes_by_op: None,
+        });
+    }
 
-impl<'a, L: Language, N: Analysis<L>> Debug for EGraphDump<'a, L, N> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut ids: Vec<Id> = self.0.classes().map(|c| c.id).collect();
-        ids.sort();
-        for id in ids {
-            let mut nodes = self.0[id].nodes.clone();
-            nodes.sort();
-            writeln!(f, "{} ({:?}): {:?}", id, self.0[id].data, nodes)?
+    /// Returns the e-graph to the state it was in at the most recent open
+    /// [`push`](EGraph::push), closing that snapshot.
+    ///
+    /// The e-graph is clean afterwards, even if it wasn't rebuilt since
+    /// the `push`.
+    /// Panics if no snapshot is open.
+    pub fn pop(&mut self) {
+        let mut undo = self.undo.take().expect("No snapshot to pop");
+        let snapshot = undo.snapshots.pop().unwrap();
+        for change in undo.undo.drain(snapshot.undo_len..).rev() {
+            self.undo_change(change);
+        }
+        self.unionfind.undo(snapshot.uf_size, snapshot.uf_recorded);
+        if let Some(classes_by_op) = snapshot.classes_by_op {
+            self.classes_by_op = classes_by_op;
+        }
+
+        // the contexts made since the push go, and the others are undone
+        let last = undo.snapshots.is_empty();
+        self.contexts.truncate(snapshot.contexts.len());
+        for (context, mark) in self.contexts.iter_mut().zip(&snapshot.contexts) {
+            context.undo(mark, last);
+        }
+        if last {
+            self.unionfind.stop_recording();
+        } else {
+            self.undo = Some(undo);
+        }
+
+        self.pending.clear();
+        self.analysis_pending.clear();
+        self.context_added.clear();
+        self.context_unions.clear();
+        self.clean = true;
+        debug_assert!(self.check_memo());
+    }
+
+    /// The number of open snapshots, see [`EGraph::push`].
+    pub fn snapshots(&self) -> usize {
+        self.undo.as_ref().map_or(0, |undo| undo.snapshots.len())
+    }
+
+    fn undo_change(&mut self, change: Undo<L, N::Data>) {
+        let classes = &mut self.classes;
+        match change {
+            Undo::Memo(node, Some(id)) => {
+                self.memo.insert(node, id);
+            }
+            Undo::Memo(node, None) => {
+                self.memo.remove(&node);
+            }
+            Undo::Class(id) => {
+                classes.remove(&id);
+            }
+            Undo::Parent(id) => {
+                classes.get_mut(&id).unwrap().parents.pop();
+            }
+            Undo::Data(id, data) => classes.get_mut(&id).unwrap().data = data,
+            Undo::Nodes(id, nodes) => classes.get_mut(&id).unwrap().nodes = nodes,
+            Undo::Merge {
+                into,
+                from,
+                nodes,
+                parents,
+                into_data,
+                from_data,
+            } => {
+                let class1 = classes.get_mut(&into).unwrap();
+                class1.data = into_data;
+                let class2 = EClass {
+                    id: from,
+                    nodes: split_concat(&mut class1.nodes, nodes),
+                    parents: split_concat(&mut class1.parents, parents),
+                    data: from_data,
+                };
+                classes.insert(from, class2);
+            }
         }
-        Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
+/// A translation of [`Id`]s from one e-graph, or from an earlier state of
+/// one, to another.
+///
+/// This is returned by [`EGraph::absorb`] and [`EGraph::retain_reachable`].
+/// Index it with an old id to get the new one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    ids: HashMap<Id, Id>,
+}
 
-    use super::*;
+impl IdMap {
+    /// The new id for `id`, or `None` if it has none.
+    pub fn get(&self, id: Id) -> Option<Id> {
+        self.ids.get(&id).copied()
+    }
 
-    #[test]
-    fn simple_add() {
-        use SymbolLang as S;
+    /// Returns the number of old ids that have a new one.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
 
-        crate::init_logger();
-        let mut egraph = EGraph::<S, ()>::default();
+    /// Returns `true` if no old id has a new one.
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
 
-        let x = egraph.add(S::leaf("x"));
-        let x2 = egraph.add(S::leaf("x"));
-        let _plus = egraph.add(S::new("+", vec![x, x2]));
+    /// Iterates over the old ids and their new ones.
+    pub fn iter(&self) -> impl Iterator<Item = (Id, Id)> + '_ {
+        self.ids.iter().map(|(&old, &new)| (old, new))
+    }
+}
 
-        egraph.union_instantiations(
-            &"x".parse().unwrap(),
-            &"y".parse().unwrap(),
-            &Default::default(),
-            "union x and y".to_string(),
+impl std::ops::Index<Id> for IdMap {
+    type Output = Id;
+    fn index(&self, id: Id) -> &Id {
+        self.ids
+            .get(&id)
+            .unwrap_or_else(|| panic!("Id {} has no new id", id))
+    }
+}
+
+// Garbage collection
+impl<L: Language, N: Analysis<L>> EGraph<L, N> {
+    /// Deletes the eclasses that none of the `roots` can reach, along with
+    /// their memo entries and the parent links to them, and renumbers the
+    /// rest from 0.
+    ///
+    /// This returns the new ids of the old ids of the remaining eclasses.
+    /// Ids held outside the e-graph must be translated with it;
+    /// ids inside the analysis data are translated by
+    /// [`Analysis::renumber`].
+    ///
+    /// The e-graph must be [`clean`](EGraph::clean), explanations must be
+    /// disabled, and there may be no open snapshots or
+    /// [contexts](EGraph::with_assumption).
+    ///
+    /// # Example
+    /// ```
+    /// use egg::{*, SymbolLang as S};
+    /// let mut egraph = EGraph::<S, ()>::default();
+    /// let garbage = egraph.add_expr(&"(g (h z))".parse().unwrap());
+    /// let root = egraph.add_expr(&"(f x y)".parse().unwrap());
+    /// egraph.rebuild();
+    ///
+    /// let ids = egraph.retain_reachable(&[root]);
+    /// assert_eq!(egraph.number_of_classes(), 3);
+    /// assert_eq!(ids.get(garbage), None);
+    /// assert_eq!(egraph.lookup_expr(&"(f x y)".parse().unwrap()), Some(ids[root]));
+    /// assert_eq!(egraph.lookup_expr(&"(h z)".parse().unwrap()), None);
+    /// ```
+    pub fn retain_reachable(&mut self, roots: &[Id]) -> IdMap {
+        assert!(self.clean, "Can't collect garbage in a dirty e-graph");
+        assert!(
+            self.explain.is_none(),
+            "Can't collect garbage with explanations enabled"
+        );
+        assert!(
+            self.undo.is_none(),
+            "Can't collect garbage while a snapshot is open"
+        );
+        assert!(
+            self.contexts.is_empty(),
+            "Can't collect garbage with contexts"
+        );
+
+        let (mut kept, _) = self.below(roots, usize::MAX);
+        kept.sort_unstable();
+
+        let new_ids: HashMap<Id, Id> = (kept.iter().enumerate())
+            .map(|(i, &id)| (id, Id::from(i)))
+            .collect();
+        let ids = (0..self.unionfind.size())
+            .map(Id::from)
+            .filter_map(|id| Some((id, *new_ids.get(&self.find(id))?)));
+        let ids = IdMap { ids: ids.collect() };
+        info!(
+            "Collecting garbage: keeping {} of {} eclasses",
+            kept.len(),
+            self.classes.len()
         );
+
+        let mut classes = std::mem::take(&mut self.classes);
+        self.unionfind = UnionFind::default();
+        self.memo.clear();
+        for id in kept {
+            let mut class = classes.remove(&id).unwrap();
+            class.id = self.unionfind.make_set();
+            for node in &mut class.nodes {
+                node.update_children(|child| ids[child]);
+                self.memo.insert(node.clone(), class.id);
+            }
+            class
+                .parents
+                .retain(|(_, parent)| ids.get(*parent).is_some());
+            for (node, parent) in &mut class.parents {
+                node.update_children(|child| ids[child]);
+                *parent = ids[*parent];
+            }
+            self.analysis.renumber(&mut class.data, &ids);
+            self.classes.insert(class.id, class);
+        }
+        for class_ids in self.classes_by_op.values_mut() {
+            *class_ids = class_ids
+                .iter()
+                .filter_map(|id| new_ids.get(id))
+                .copied()
+                .collect();
+        }
+
+        debug_assert!(self.check_memo());
+        ids
+    }
+}
+
+// Sub-e-graphs
+impl<L: Language, N: Analysis<L>> EGraph<L, N> {
+    /// Returns the eclasses at most `depth` steps below the `roots`, and
+    /// those one step further down.
+    fn below(&self, roots: &[Id], depth: usize) -> (Vec<Id>, Vec<Id>) {
+        let mut seen = HashSet::default();
+        let mut level: Vec<Id> = roots.iter().map(|&id| self.find(id)).collect();
+        level.retain(|&id| seen.insert(id));
+        let mut below = vec![];
+        for _ in 0..=depth {
+            if level.is_empty() {
+                break;
+            }
+            let mut next = vec![];
+            for id in &level {
+                for node in &self.classes[id].nodes {
+                    let children = node.children().iter().map(|&c| self.find(c));
+                    next.extend(children.filter(|&c| seen.insert(c)));
+                }
+            }
+            below.append(&mut level);
+            level = next;
+        }
+        (below, level)
+    }
+
+    /// Copies the eclasses that the `roots` can reach, with their enodes
+    /// and data, into a new e-graph.
+    ///
+    /// This returns the new e-graph and the new ids of the copied eclasses,
+    /// which are numbered from 0.
+    /// Ids inside the copied data are translated by [`Analysis::renumber`].
+    /// The e-graph must be [`clean`](EGraph::clean).
+    ///
+    /// # Example
+    /// ```
+    /// use egg::{*, SymbolLang as S};
+    /// let mut egraph = EGraph::<S, ()>::default();
+    /// let fx = egraph.add_expr(&"(f x)".parse().unwrap());
+    /// egraph.add_expr(&"(g (f x) y)".parse().unwrap());
+    /// egraph.rebuild();
+    ///
+    /// let (sub, ids) = egraph.subgraph(&[fx]);
+    /// assert_eq!(sub.number_of_classes(), 2);
+    /// assert_eq!(sub.lookup_expr(&"(f x)".parse().unwrap()), Some(ids[fx]));
+    /// ```
+    pub fn subgraph(&self, roots: &[Id]) -> (Self, IdMap)
+    where
+        N: Clone,
+        N::Data: Clone,
+    {
+        let (below, _) = self.below(roots, usize::MAX);
+        self.copy_classes(below, vec![], |_| unreachable!())
+    }
+
+    /// Like [`subgraph`](EGraph::subgraph), but only copies the eclasses at
+    /// most `depth` steps below the `roots`, so the result is small enough
+    /// to look at with [`Dot`].
+    ///
+    /// The children one step further down are replaced by placeholder
+    /// eclasses with a single leaf `<id>`, where `id` is the id of the
+    /// eclass it stands for, and the same data.
+    ///
+    /// # Example
+    /// ```
+    /// use egg::{*, SymbolLang as S};
+    /// let mut egraph = EGraph::<S, ()>::default();
+    /// let root = egraph.add_expr(&"(f (g (h x)) y)".parse().unwrap());
+    /// egraph.rebuild();
+    ///
+    /// let (sub, ids) = egraph.subgraph_to_depth(&[root], 1);
+    /// let h = egraph.lookup_expr(&"(h x)".parse().unwrap()).unwrap();
+    /// assert_eq!(sub.number_of_classes(), 4);
+    /// assert_eq!(sub[ids[h]].nodes, vec![S::leaf(format!("<{}>", h))]);
+    /// ```
+    pub fn subgraph_to_depth(&self, roots: &[Id], depth: usize) -> (Self, IdMap)
+    where
+        L: FromOp,
+        N: Clone,
+        N::Data: Clone,
+    {
+        let (below, cut) = self.below(roots, depth);
+        self.copy_classes(below, cut, |id| {
+            let op = format!("<{}>", id);
+            L::from_op(&op, vec![])
+                .unwrap_or_else(|e| panic!("Can't make a placeholder {}: {:?}", op, e))
+        })
+    }
+
+    /// Copies the `full` eclasses into a new e-graph, replacing the `cut`
+    /// ones by a `placeholder`.
+    fn copy_classes(
+        &self,
+        full: Vec<Id>,
+        cut: Vec<Id>,
+        placeholder: impl Fn(Id) -> L,
+    ) -> (Self, IdMap)
+    where
+        N: Clone,
+        N::Data: Clone,
+    {
+        assert!(self.clean, "Can't copy from a dirty e-graph");
+        let mut classes: Vec<(Id, bool)> = full.into_iter().map(|id| (id, false)).collect();
+        classes.extend(cut.into_iter().map(|id| (id, true)));
+        // keep the order of the ids, so the enodes stay sorted
+        classes.sort_unstable();
+
+        let new_ids: HashMap<Id, Id> = (classes.iter().enumerate())
+            .map(|(i, &(id, _))| (id, Id::from(i)))
+            .collect();
+        let ids = (0..self.unionfind.size())
+            .map(Id::from)
+            .filter_map(|id| Some((id, *new_ids.get(&self.find(id))?)));
+        let ids = IdMap { ids: ids.collect() };
+
+        let mut egraph = EGraph::new(self.analysis.clone());
+        egraph.ac_canonical = self.ac_canonical;
+        for (id, is_cut) in classes {
+            let class = &self[id];
+            let nodes = if is_cut {
+                vec![placeholder(id)]
+            } else {
+                let nodes = class.nodes.iter().cloned();
+                nodes.map(|n| n.map_children(|c| ids[c])).collect()
+            };
+            let mut data = class.data.clone();
+            egraph.analysis.renumber(&mut data, &ids);
+
+            let id = egraph.unionfind.make_set();
+            for node in &nodes {
+                egraph.memo.insert(node.clone(), id);
+            }
+            let class = EClass {
+                id,
+                nodes,
+                data,
+                parents: vec![],
+            };
+            egraph.classes.insert(id, class);
+        }
+
+        let mut parents = vec![];
+        for class in egraph.classes.values() {
+            for node in &class.nodes {
+                for &child in node.children() {
+                    parents.push((child, (node.clone(), class.id)));
+                }
+            }
+        }
+        for (child, parent) in parents {
+            egraph.classes.get_mut(&child).unwrap().parents.push(parent);
+        }
+
         egraph.rebuild();
+        (egraph, ids)
+    }
+}
+
+// Merging e-graphs
+impl<L: Language, N: Analysis<L>> EGraph<L, N> {
+    /// Adds everything in `other` to this e-graph.
+    ///
+    /// Every eclass of `other` is added again with its enodes, which are
+    /// unioned, and its data is merged into the new eclass with
+    /// [`Analysis::merge`], after its ids are translated with
+    /// [`Analysis::renumber`].
+    /// The analysis of `other` itself is dropped.
+    /// This returns the new id of every id of `other`, so callers can
+    /// translate their roots.
+    ///
+    /// If this e-graph has explanations enabled, `other` must too, and
+    /// the unions that justify its equivalences are replayed here with the
+    /// same justifications, so equivalences found in `other` are explained
+    /// by the rules that found them there.
+    ///
+    /// Like [`union`](EGraph::union), this modifies the e-graph, so call
+    /// [`rebuild`](EGraph::rebuild) afterwards.
+    ///
+    /// # Example
+    /// ```
+    /// use egg::{*, SymbolLang as S};
+    /// let mut left = EGraph::<S, ()>::default();
+    /// let a = left.add_expr(&"(f a)".parse().unwrap());
+    /// let b = left.add_expr(&"(f b)".parse().unwrap());
+    /// left.union(a, b);
+    /// left.rebuild();
+    ///
+    /// let mut right = EGraph::<S, ()>::default();
+    /// let c = right.add_expr(&"(f c)".parse().unwrap());
+    /// let b = right.add_expr(&"(f b)".parse().unwrap());
+    /// right.union(b, c);
+    /// right.rebuild();
+    ///
+    /// let ids = left.absorb(right);
+    /// left.rebuild();
+    /// assert_eq!(left.find(a), left.find(ids[c]));
+    /// assert_eq!(left.number_of_classes(), 4);
+    /// ```
+    pub fn absorb(&mut self, other: EGraph<L, N>) -> IdMap {
+        let explained = match (&self.explain, &other.explain) {
+            (Some(_), Some(explain)) => Some(explain),
+            (Some(_), None) => panic!("Can't absorb an e-graph without explanations"),
+            (None, _) => None,
+        };
+        assert!(
+            other.contexts.is_empty(),
+            "Can't absorb an e-graph with contexts"
+        );
+        // the new id of some enode of each of the classes of other
+        let classes = match explained {
+            Some(explain) => self.absorb_proofs(&other, explain),
+            None => self.absorb_classes(&other),
+        };
+
+        let ids = (0..other.unionfind.size())
+            .map(Id::from)
+            .map(|id| (id, self.find(classes[&other.find(id)])));
+        let ids = IdMap { ids: ids.collect() };
+
+        for (id, class) in other.classes {
+            let mut data = class.data;
+            self.analysis.renumber(&mut data, &ids);
+            let id = self.find_mut(classes[&id]);
+            let class1 = self.classes.get_mut(&id).unwrap();
+            if let Some(undo) = &mut self.undo {
+                undo.save_data(id, &class1.data);
+            }
+            let did_merge = self.analysis.merge(&mut class1.data, data);
+            if did_merge.0 {
+                self.analysis_pending.extend(class1.parents.iter().cloned());
+                N::modify(self, id);
+            }
+        }
+        ids
+    }
+
+    /// Adds the enodes of each class of `other` once the classes of their
+    /// children are added, unioning those of the same class.
+    fn absorb_classes(&mut self, other: &EGraph<L, N>) -> HashMap<Id, Id> {
+        let mut classes = HashMap::<Id, Id>::default();
+        let leaves = other.classes().flat_map(|class| {
+            let leaves = class.nodes.iter().filter(|n| n.is_leaf());
+            leaves.map(move |n| (n.clone(), class.id))
+        });
+        let mut todo: Vec<(L, Id)> = leaves.collect();
+
+        while let Some((node, class)) = todo.pop() {
+            let node = node.map_children(|child| classes[&other.find(child)]);
+            let id = self.add(node);
+            if let Some(&existing) = classes.get(&class) {
+                self.union(existing, id);
+                continue;
+            }
+            classes.insert(class, id);
+            for (parent, parent_id) in &other.classes[&class].parents {
+                if parent.all(|child| classes.contains_key(&other.find(child))) {
+                    todo.push((parent.clone(), other.find(*parent_id)));
+                }
+            }
+        }
+        classes
+    }
+
+    /// Adds the enodes of `other` in the order they were added there, then
+    /// replays the edges of its proof forest.
+    fn absorb_proofs(&mut self, other: &EGraph<L, N>, explain: &Explain<L>) -> HashMap<Id, Id> {
+        let mut ids: Vec<Id> = vec![];
+        for (node, ..) in explain.proof_edges() {
+            let node = node.clone().map_children(|child| ids[usize::from(child)]);
+            ids.push(self.add_internal(node));
+        }
+        for (i, (_, next, justification, forward)) in explain.proof_edges().enumerate() {
+            let (id, next) = (ids[i], ids[usize::from(next)]);
+            if id != next {
+                let (from, to) = if forward { (id, next) } else { (next, id) };
+                self.perform_union(from, to, Some(justification.clone()), false);
+            }
+        }
+
+        let ids = ids.into_iter().enumerate();
+        ids.map(|(i, id)| (other.find(Id::from(i)), id)).collect()
+    }
+}
+
+/// A context made by [`EGraph::with_assumption`], in which some extra
+/// unions hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde-1", derive(Serialize, Deserialize))]
+pub struct ContextId(usize);
+
+impl Display for ContextId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ctx{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde-1", derive(Serialize, Deserialize))]
+struct Context<L: Language> {
+    /// The unions made in this context
+    assumptions: Vec<(Id, Id)>,
+    /// The eclasses of the base e-graph that are equal here,
+    /// closed under congruence at each rebuild
+    unionfind: UnionFind,
+    /// Like the e-graph's memo, but canonical in this context
+    #[cfg_attr(feature = "serde-1", serde(with = "vectorize"))]
+    memo: HashMap<L, Id>,
+    /// The enodes using each eclass of this context as a child
+    parents: HashMap<Id, Vec<(L, Id)>>,
+    /// The unions of the e-graph a new context hasn't seen yet
+    unions: Vec<(Id, Id)>,
+    /// The enodes of the e-graph a new context hasn't seen yet
+    added: Vec<(L, Id)>,
+    /// Enodes whose children changed eclass in this context
+    pending: Vec<(L, Id)>,
+    /// The changes made since the open snapshots, see [`EGraph::push`]
+    #[cfg_attr(feature = "serde-1", serde(skip))]
+    undo: Option<Vec<ContextUndo<L>>>,
+}
+
+/// A change to a [`Context`] that [`EGraph::pop`] undoes.
+#[derive(Debug, Clone)]
+enum ContextUndo<L> {
+    /// A memo entry was set; this is its old value
+    Memo(L, Option<Id>),
+    /// A parent was appended to an eclass
+    Parent(Id),
+    /// The parents of `from` were appended to the `len` of `into`
+    Moved { from: Id, into: Id, len: usize },
+}
+
+/// How far a [`Context`] had got when a snapshot was taken.
+#[derive(Clone)]
+struct ContextMark {
+    assumptions: usize,
+    uf_size: usize,
+    uf_recorded: usize,
+    undo_len: usize,
+}
+
+impl<L: Language> Context<L> {
+    fn is_clean(&self) -> bool {
+        self.unions.is_empty() && self.added.is_empty() && self.pending.is_empty()
+    }
+
+    /// Starts recording changes, returning how far the context has got.
+    fn mark(&mut self) -> ContextMark {
+        self.unionfind.record();
+        let undo = self.undo.get_or_insert_with(Vec::new);
+        ContextMark {
+            assumptions: self.assumptions.len(),
+            uf_size: self.unionfind.size(),
+            uf_recorded: self.unionfind.recorded(),
+            undo_len: undo.len(),
+        }
+    }
+
+    /// Undoes the changes made since `mark`, and stops recording if `last`.
+    fn undo(&mut self, mark: &ContextMark, last: bool) {
+        let undo = self.undo.as_mut().unwrap();
+        for change in undo.drain(mark.undo_len..).rev() {
+            match change {
+                ContextUndo::Memo(node, Some(id)) => {
+                    self.memo.insert(node, id);
+                }
+                ContextUndo::Memo(node, None) => {
+                    self.memo.remove(&node);
+                }
+                ContextUndo::Parent(id) => {
+                    self.parents.get_mut(&id).unwrap().pop();
+                }
+                ContextUndo::Moved { from, into, len } => {
+                    let moved = self.parents.get_mut(&into).unwrap().split_off(len);
+                    self.parents.insert(from, moved);
+                }
+            }
+        }
+        self.unionfind.undo(mark.uf_size, mark.uf_recorded);
+        self.assumptions.truncate(mark.assumptions);
+        self.unions.clear();
+        self.added.clear();
+        self.pending.clear();
+        if last {
+            self.unionfind.stop_recording();
+            self.undo = None;
+        }
+    }
+
+    fn grow(&mut self, size: usize) {
+        while self.unionfind.size() < size {
+            self.unionfind.make_set();
+        }
+    }
+
+    /// Unions two eclasses, queueing the parents of the smaller one to be
+    /// recanonicalized.
+    fn union(&mut self, a: Id, b: Id) -> bool {
+        let (mut a, mut b) = (self.unionfind.find_mut(a), self.unionfind.find_mut(b));
+        if a == b {
+            return false;
+        }
+        let parents = |id| self.parents.get(&id).map_or(0, Vec::len);
+        if parents(a) < parents(b) {
+            std::mem::swap(&mut a, &mut b);
+        }
+        self.unionfind.union(a, b);
+        if let Some(moved) = self.parents.remove(&b) {
+            self.pending.extend(moved.iter().cloned());
+            let parents = self.parents.entry(a).or_default();
+            if let Some(undo) = &mut self.undo {
+                let len = parents.len();
+                undo.push(ContextUndo::Moved {
+                    from: b,
+                    into: a,
+                    len,
+                });
+            }
+            parents.extend(moved);
+        }
+        true
+    }
+
+    /// Makes the pending unions and those they cause by congruence, like
+    /// [`EGraph::rebuild`] does for the e-graph itself.
+    fn close(&mut self, size: usize, ac_canonical: bool, added: &[(L, Id)], unions: &[(Id, Id)]) {
+        self.grow(size);
+        let own_added = std::mem::take(&mut self.added);
+        for (node, id) in own_added.iter().chain(added) {
+            for &child in node.children() {
+                let child = self.unionfind.find_mut(child);
+                let parents = self.parents.entry(child).or_default();
+                parents.push((node.clone(), *id));
+                if let Some(undo) = &mut self.undo {
+                    undo.push(ContextUndo::Parent(child));
+                }
+            }
+            self.pending.push((node.clone(), *id));
+        }
+        let own_unions = std::mem::take(&mut self.unions);
+        for &(a, b) in own_unions.iter().chain(unions) {
+            self.union(a, b);
+        }
+
+        while let Some((mut node, id)) = self.pending.pop() {
+            node.update_children(|c| self.unionfind.find_mut(c));
+            if ac_canonical && node.is_ac() {
+                node.children_mut().sort_unstable();
+            }
+            if let Some(undo) = &mut self.undo {
+                let old = self.memo.get(&node).copied();
+                undo.push(ContextUndo::Memo(node.clone(), old));
+            }
+            if let Some(old) = self.memo.insert(node, id) {
+                self.union(old, id);
+            }
+        }
+    }
+}
+
+// Contexts
+impl<L: Language, N: Analysis<L>> EGraph<L, N> {
+    /// Makes a context in which `a` and `b` are equal, like the
+    /// `[] (= (var ?x) ?y)` guard of a UDP product makes the variable equal
+    /// to `?y` inside it.
+    ///
+    /// The e-graph itself doesn't change: only the `*_in` methods see the
+    /// unions of a context.
+    /// As with [`union`](EGraph::union), the enodes that become equal by
+    /// congruence are only found by the next [`rebuild`](EGraph::rebuild),
+    /// which also keeps each context up to date with the unions made
+    /// outside of it.
+    /// Contexts only relate eclasses: the analysis data isn't merged in a
+    /// context.
+    /// Explanations must be disabled.
+    ///
+    /// # Example
+    /// ```
+    /// use egg::*;
+    /// let mut egraph = EGraph::<SymbolLang, ()>::default();
+    /// let fx = egraph.add_expr(&"(f x)".parse().unwrap());
+    /// let fy = egraph.add_expr(&"(f y)".parse().unwrap());
+    /// let x = egraph.lookup(SymbolLang::leaf("x")).unwrap();
+    /// let y = egraph.lookup(SymbolLang::leaf("y")).unwrap();
+    ///
+    /// let ctx = egraph.with_assumption(x, y);
+    /// egraph.rebuild();
+    /// assert_eq!(egraph.find_in(ctx, fx), egraph.find_in(ctx, fy));
+    /// assert_ne!(egraph.find(fx), egraph.find(fy));
+    /// ```
+    pub fn with_assumption(&mut self, a: Id, b: Id) -> ContextId {
+        assert!(
+            self.explain.is_none(),
+            "Contexts don't support explanations"
+        );
+        let ctx = ContextId(self.contexts.len());
+        let context = self.new_context();
+        self.contexts.push(context);
+        self.union_in(ctx, a, b);
+        ctx
+    }
+
+    /// Makes a context with no assumptions, which the next rebuild closes.
+    fn new_context(&self) -> Context<L> {
+        let ids = (0..self.unionfind.size()).map(Id::from);
+        let nodes = self.classes.values().flat_map(|class| {
+            let nodes = class.nodes.iter().cloned();
+            nodes.map(move |node| (node, class.id))
+        });
+        Context {
+            assumptions: vec![],
+            unionfind: UnionFind::default(),
+            memo: HashMap::default(),
+            parents: HashMap::default(),
+            unions: ids.map(|id| (id, self.find(id))).collect(),
+            added: nodes.collect(),
+            pending: vec![],
+            undo: None,
+        }
+    }
+
+    /// Unions two eclasses in a context, leaving the e-graph itself as it is.
+    ///
+    /// Returns `false` if they were already equal in the context.
+    /// Like [`union`](EGraph::union), this leaves the congruences it causes
+    /// to the next [`rebuild`](EGraph::rebuild).
+    pub fn union_in(&mut self, ctx: ContextId, a: Id, b: Id) -> bool {
+        let (a, b) = (self.find_in(ctx, a), self.find_in(ctx, b));
+        if a == b {
+            return false;
+        }
+        let context = &mut self.contexts[ctx.0];
+        context.grow(self.unionfind.size());
+        context.union(a, b);
+        context.assumptions.push((a, b));
+        self.clean = false;
+        true
+    }
+
+    /// Canonicalizes an eclass id in a context.
+    ///
+    /// Ids that are equal in the e-graph are equal in every context.
+    /// Like [`find`](EGraph::find), this doesn't see congruences until the
+    /// next [`rebuild`](EGraph::rebuild).
+    pub fn find_in(&self, ctx: ContextId, id: Id) -> Id {
+        let unionfind = &self.contexts[ctx.0].unionfind;
+        let id = self.find(id);
+        if usize::from(id) < unionfind.size() {
+            unionfind.find(id)
+        } else {
+            id
+        }
+    }
+
+    /// Searches for the `rules` in the e-graph and applies them in a
+    /// context, so the unions they make only hold there.
+    ///
+    /// Returns the number of unions made.
+    /// The rules only see the e-graph itself when searching, so they miss
+    /// the matches that need the unions of the context.
+    /// The enodes they add are added to the e-graph, which is sound
+    /// because adding an enode doesn't make it equal to anything.
+    /// The unions the rules' appliers make are only made in the context,
+    /// but those made by [`Analysis::modify`], for the enodes they add or
+    /// at the next [`rebuild`](EGraph::rebuild), are made in the e-graph
+    /// itself.
+    /// The e-graph must be [`clean`](EGraph::clean).
+    /// [`Runner::with_rules_in`] does this at each iteration.
+    pub fn apply_in(&mut self, ctx: ContextId, rules: &[&Rewrite<L, N>]) -> usize {
+        assert!(self.clean, "Can't apply rules in a dirty e-graph");
+        let matches: Vec<_> = rules.iter().map(|rule| rule.search(self)).collect();
+        self.firing_in = Some(ctx);
+        let mut n = 0;
+        for (rule, matches) in rules.iter().zip(&matches) {
+            n += rule.apply(self, matches).len();
+        }
+        self.firing_in = None;
+        n
+    }
+
+    /// Closes the contexts under congruence with the unions and enodes
+    /// made since the last rebuild.
+    fn close_contexts(&mut self) {
+        let size = self.unionfind.size();
+        let added = std::mem::take(&mut self.context_added);
+        let unions = std::mem::take(&mut self.context_unions);
+        for context in &mut self.contexts {
+            if !context.is_clean() || !added.is_empty() || !unions.is_empty() {
+                context.close(size, self.ac_canonical, &added, &unions);
+            }
+        }
+    }
+}
+
+/// Statistics about the shape of an [`EGraph`], made by [`EGraph::stats`].
+///
+/// This implements [`Display`] for printing a summary, and with the
+/// `serde-1` feature, [`serde::Serialize`][ser] and `Deserialize`.
+///
+/// [ser]: https://docs.rs/serde/latest/serde/trait.Serialize.html
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-1", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct EGraphStats {
+    /// The number of eclasses.
+    pub classes: usize,
+    /// The number of enodes.
+    pub nodes: usize,
+    /// The number of enodes of each variant of the [`Language`], most
+    /// common first.
+    /// A variant is named by [`Language::variant_name`], or by the
+    /// enode's [`Display`] output if that is `None`, so every
+    /// [`SymbolLang`] operator is counted separately.
+    pub nodes_by_op: Vec<(String, usize)>,
+    /// The number of eclasses with each number of enodes.
+    pub class_sizes: BTreeMap<usize, usize>,
+    /// The ids and sizes of the biggest eclasses, biggest first.
+    pub biggest_classes: Vec<(Id, usize)>,
+    /// The number of eclasses with each number of parents.
+    pub parent_counts: BTreeMap<usize, usize>,
+    /// The number of eclasses with at least one leaf.
+    pub classes_with_leaf: usize,
+    /// The number of memo entries.
+    pub memo_size: usize,
+    /// The memo's entries over its capacity.
+    pub memo_load_factor: f64,
+}
+
+impl Display for EGraphStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn counts<K: Display>(counts: impl IntoIterator<Item = (K, usize)>) -> String {
+            let counts = counts.into_iter().map(|(k, n)| format!("{}: {}", k, n));
+            counts.collect::<Vec<_>>().join(", ")
+        }
+        let biggest = self.biggest_classes.iter().copied();
+        let ops = self.nodes_by_op.iter().map(|(op, n)| (op, *n));
+        let sizes = self.class_sizes.iter().map(|(&k, &n)| (k, n));
+        let parents = self.parent_counts.iter().map(|(&k, &n)| (k, n));
+
+        writeln!(f, "E-graph stats")?;
+        writeln!(f, "=============")?;
+        writeln!(f, "  Size: {} nodes, {} classes", self.nodes, self.classes)?;
+        writeln!(
+            f,
+            "  Memo: {} entries, load factor {:.2}",
+            self.memo_size, self.memo_load_factor
+        )?;
+        writeln!(f, "  Classes with a leaf: {}", self.classes_with_leaf)?;
+        writeln!(f, "  Nodes by op: {}", counts(ops))?;
+        writeln!(f, "  Class sizes: {}", counts(sizes))?;
+        writeln!(f, "  Biggest classes: {}", counts(biggest))?;
+        writeln!(f, "  Parent counts: {}", counts(parents))
+    }
+}
+
+// Statistics
+impl<L: Language + Display, N: Analysis<L>> EGraph<L, N> {
+    /// The number of eclasses listed in [`EGraphStats::biggest_classes`].
+    const BIGGEST_CLASSES: usize = 10;
+
+    /// Counts things about the shape of this e-graph.
+    ///
+    /// # Example
+    /// ```
+    /// use egg::*;
+    /// let mut egraph = EGraph::<SymbolLang, ()>::default();
+    /// let x = egraph.add_expr(&"(f x)".parse().unwrap());
+    /// let y = egraph.add_expr(&"(g y)".parse().unwrap());
+    /// egraph.union(x, y);
+    /// egraph.rebuild();
+    ///
+    /// let stats = egraph.stats();
+    /// assert_eq!((stats.classes, stats.nodes), (3, 4));
+    /// assert_eq!(stats.biggest_classes[0], (egraph.find(x), 2));
+    /// assert_eq!(stats.classes_with_leaf, 2);
+    /// assert_eq!(stats.nodes_by_op[0], ("f".to_string(), 1));
+    /// println!("{}", stats);
+    /// ```
+    pub fn stats(&self) -> EGraphStats {
+        let mut by_op = HashMap::<Cow<'static, str>, usize>::default();
+        let mut class_sizes = BTreeMap::new();
+        let mut parent_counts = BTreeMap::new();
+        let mut biggest_classes = vec![];
+        let mut classes_with_leaf = 0;
+        for class in self.classes() {
+            for node in class.iter() {
+                let op = match node.variant_name() {
+                    Some(name) => Cow::Borrowed(name),
+                    None => Cow::Owned(node.to_string()),
+                };
+                *by_op.entry(op).or_insert(0) += 1;
+            }
+            *class_sizes.entry(class.len()).or_insert(0) += 1;
+            *parent_counts.entry(class.parents.len()).or_insert(0) += 1;
+            biggest_classes.push((class.id, class.len()));
+            if class.leaves().next().is_some() {
+                classes_with_leaf += 1;
+            }
+        }
+
+        let mut nodes_by_op: Vec<(String, usize)> = by_op
+            .into_iter()
+            .map(|(op, n)| (op.into_owned(), n))
+            .collect();
+        nodes_by_op.sort_by(|(op1, n1), (op2, n2)| n2.cmp(n1).then_with(|| op1.cmp(op2)));
+        biggest_classes.sort_by_key(|&(id, size)| (std::cmp::Reverse(size), id));
+        biggest_classes.truncate(Self::BIGGEST_CLASSES);
+        let memo_load_factor = match self.memo.capacity() {
+            0 => 0.0,
+            capacity => self.memo.len() as f64 / capacity as f64,
+        };
+
+        EGraphStats {
+            classes: self.number_of_classes(),
+            nodes: self.total_number_of_nodes(),
+            nodes_by_op,
+            class_sizes,
+            biggest_classes,
+            parent_counts,
+            classes_with_leaf,
+            memo_size: self.memo.len(),
+            memo_load_factor,
+        }
+    }
+}
+
+// Enumerating terms
+impl<L: Language, N: Analysis<L>> EGraph<L, N> {
+    /// Iterates over the distinct terms that eclass `id` represents, in order
+    /// of increasing size, up to `max_size` enodes.
+    ///
+    /// Cycles in the e-graph are fine, since `max_size` bounds the terms.
+    /// The iterator takes memory for a table of `max_size` counts for each
+    /// eclass below `id`, but not for the terms it has yielded, see
+    /// [`Terms`].
+    /// The e-graph must be [`clean`](EGraph::clean).
+    ///
+    /// # Example
+    /// ```
+    /// use egg::*;
+    /// let mut egraph = EGraph::<SymbolLang, ()>::default();
+    /// let x = egraph.add(SymbolLang::leaf("x"));
+    /// let fx = egraph.add(SymbolLang::new("f", vec![x]));
+    /// // x = (f x) = (f (f x)) = ...
+    /// egraph.union(x, fx);
+    /// egraph.rebuild();
+    ///
+    /// let terms: Vec<String> = egraph.enumerate_terms(x, 3).map(|t| t.to_string()).collect();
+    /// assert_eq!(terms, ["x", "(f x)", "(f (f x))"]);
+    /// assert_eq!(egraph.count_terms(x, 100), 100);
+    /// ```
+    pub fn enumerate_terms(&self, id: Id, max_size: usize) -> Terms<'_, L, N> {
+        assert!(self.clean, "Can't enumerate the terms of a dirty e-graph");
+        Terms::new(self, id, max_size)
+    }
+
+    /// The number of distinct terms that eclass `id` represents with at most
+    /// `max_size` enodes, or `u64::MAX` if there are more.
+    ///
+    /// This is the number of terms that
+    /// [`enumerate_terms`](EGraph::enumerate_terms) yields, without building
+    /// them.
+    /// The e-graph must be [`clean`](EGraph::clean).
+    pub fn count_terms(&self, id: Id, max_size: usize) -> u64 {
+        assert!(self.clean, "Can't count the terms of a dirty e-graph");
+        TermCounts::new(self, id, max_size).total(self.find(id))
+    }
+}
+
+/// A disagreement between the analysis data of an eclass and its enodes,
+/// found by [`EGraph::check_analysis`].
+#[derive(Debug, Clone)]
+pub struct AnalysisError<L> {
+    /// The eclass whose data is wrong.
+    pub class: Id,
+    /// The enode that disagrees, unless the data is more than all of
+    /// them make.
+    pub node: Option<L>,
+    /// What's wrong.
+    pub message: String,
+}
+
+impl<L: Debug> Display for AnalysisError<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "eclass {}", self.class)?;
+        if let Some(node) = &self.node {
+            write!(f, ", enode {:?}", node)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl<L: Debug> std::error::Error for AnalysisError<L> {}
+
+// Checking the analysis
+impl<L: Language, N: Analysis<L>> EGraph<L, N> {
+    /// Checks that the data of each eclass is what [`Analysis::make`] makes
+    /// for its enodes, folded together with [`Analysis::merge`].
+    ///
+    /// This also checks that each of those merges returns the right
+    /// [`DidMerge`], since a wrong one silently stops
+    /// [`rebuild`](EGraph::rebuild) from updating the parents.
+    /// The merges are done on a clone of the analysis.
+    /// The e-graph must be [`clean`](EGraph::clean).
+    /// See [`Runner::with_analysis_checks`] to check after every rebuild.
+    ///
+    /// # Example
+    /// ```
+    /// use egg::*;
+    /// #[derive(Clone, Default)]
+    /// struct Wrong;
+    /// impl Analysis<SymbolLang> for Wrong {
+    ///     type Data = usize;
+    ///     fn make(_: &EGraph<SymbolLang, Self>, enode: &SymbolLang) -> usize {
+    ///         enode.len()
+    ///     }
+    ///     fn merge(&mut self, to: &mut usize, from: usize) -> DidMerge {
+    ///         *to = (*to).max(from);
+    ///         DidMerge(false, false)
+    ///     }
+    /// }
+    ///
+    /// let mut egraph = EGraph::<SymbolLang, Wrong>::default();
+    /// let x = egraph.add(SymbolLang::leaf("x"));
+    /// let fx = egraph.add(SymbolLang::new("f", vec![x]));
+    /// egraph.union(x, fx);
+    /// egraph.rebuild();
+    /// let err = egraph.check_analysis().unwrap_err();
+    /// assert_eq!(err.class, egraph.find(x));
+    /// ```
+    pub fn check_analysis(&self) -> Result<(), AnalysisError<L>>
+    where
+        N: Clone,
+        N::Data: Clone + PartialEq,
+    {
+        self.check_analysis_by(|data, made| data == made)
+    }
+
+    /// Like [`check_analysis`](EGraph::check_analysis), but lets the data of
+    /// an eclass be more precise than what its enodes make:
+    /// `precise(data, made)` says whether `data` is at least as precise as
+    /// `made`.
+    ///
+    /// This is for analyses like one that [`modify`](Analysis::modify)
+    /// refines, or whose data depends on the order the merges happened in.
+    pub fn check_analysis_by(
+        &self,
+        precise: impl Fn(&N::Data, &N::Data) -> bool,
+    ) -> Result<(), AnalysisError<L>>
+    where
+        N: Clone,
+        N::Data: Clone + PartialEq,
+    {
+        assert!(self.clean, "Can't check the analysis of a dirty e-graph");
+        let mut analysis = self.analysis.clone();
+        let error = |class: &EClass<L, N::Data>, node: Option<&L>, message| AnalysisError {
+            class: class.id,
+            node: node.cloned(),
+            message,
+        };
+
+        let mut classes: Vec<&EClass<L, N::Data>> = self.classes().collect();
+        classes.sort_by_key(|class| class.id);
+        for class in classes {
+            let mut folded: Option<N::Data> = None;
+            for node in class.iter() {
+                let made = N::make(self, node);
+                let data = match &mut folded {
+                    None => {
+                        folded = Some(made);
+                        continue;
+                    }
+                    Some(data) => data,
+                };
+                let before = data.clone();
+                let did = analysis.merge(data, made.clone());
+                if *data != before && !did.0 {
+                    let message = format!(
+                        "merging {:?} into {:?} made {:?}, but returned DidMerge(false, _)",
+                        made, before, data
+                    );
+                    return Err(error(class, Some(node), message));
+                }
+                if *data != made && !did.1 {
+                    let message = format!(
+                        "merging {:?} into {:?} made {:?}, but returned DidMerge(_, false)",
+                        made, before, data
+                    );
+                    return Err(error(class, Some(node), message));
+                }
+            }
+
+            let folded = folded.expect("empty eclass");
+            if !precise(&class.data, &folded) {
+                // blame an enode that knows something the data doesn't
+                let node = class.iter().find(|node| {
+                    let mut data = class.data.clone();
+                    analysis.merge(&mut data, N::make(self, node));
+                    data != class.data
+                });
+                let message = format!(
+                    "the data is {:?}, but the enodes make {:?}",
+                    class.data, folded
+                );
+                return Err(error(class, node, message));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Undoes [`concat_vecs`], given the length `to` had, returning `from`.
+fn split_concat<T>(to: &mut Vec<T>, len: usize) -> Vec<T> {
+    let from_len = to.len() - len;
+    if len < from_len {
+        // they were swapped, so `to` is at the end
+        let tail = to.split_off(from_len);
+        std::mem::replace(to, tail)
+    } else {
+        to.split_off(len)
+    }
+}
+
+struct EGraphDump<'a, L: Language, N: Analysis<L>>(&'a EGraph<L, N>);
+
+impl<'a, L: Language, N: Analysis<L>> Debug for EGraphDump<'a, L, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<Id> = self.0.classes().map(|c| c.id).collect();
+        ids.sort();
+        for id in ids {
+            let mut nodes = self.0[id].nodes.clone();
+            nodes.sort();
+            writeln!(f, "{} ({:?}): {:?}", id, self.0[id].data, nodes)?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn simple_add() {
+        use SymbolLang as S;
+
+        crate::init_logger();
+        let mut egraph = EGraph::<S, ()>::default();
+
+        let x = egraph.add(S::leaf("x"));
+        let x2 = egraph.add(S::leaf("x"));
+        let _plus = egraph.add(S::new("+", vec![x, x2]));
+
+        egraph.union_instantiations(
+            &"x".parse().unwrap(),
+            &"y".parse().unwrap(),
+            &Default::default(),
+            "union x and y".to_string(),
+        );
+        egraph.rebuild();
+    }
+
+    #[derive(Default, Clone)]
+    struct Fold;
+    impl Analysis<SymbolLang> for Fold {
+        type Data = Option<i32>;
+        fn make(egraph: &EGraph<SymbolLang, Self>, enode: &SymbolLang) -> Self::Data {
+            let c = |i: &Id| egraph[*i].data;
+            match (enode.op.as_str(), enode.children.as_slice()) {
+                ("+", [a, b]) => Some(c(a)? + c(b)?),
+                (op, []) => op.parse().ok(),
+                _ => None,
+            }
+        }
+        fn merge(&mut self, to: &mut Self::Data, from: Self::Data) -> DidMerge {
+            crate::merge_max(to, from)
+        }
+        fn modify(egraph: &mut EGraph<SymbolLang, Self>, id: Id) {
+            if let Some(n) = egraph[id].data {
+                let folded = egraph.add(SymbolLang::leaf(n.to_string()));
+                egraph.union(id, folded);
+            }
+        }
+    }
+
+    // everything an e-graph is made of, in a canonical order
+    fn state<N: Analysis<SymbolLang>>(egraph: &EGraph<SymbolLang, N>) -> String {
+        let mut classes: Vec<_> = egraph.classes.values().collect();
+        classes.sort_by_key(|c| c.id);
+        let mut memo: Vec<_> = egraph.memo.iter().collect();
+        memo.sort();
+        let mut by_op: Vec<Vec<Id>> = (egraph.classes_by_op.values())
+            .map(|ids| ids.iter().copied().collect())
+            .filter(|ids: &Vec<Id>| !ids.is_empty())
+            .collect();
+        by_op.iter_mut().for_each(|ids| ids.sort());
+        by_op.sort();
+        format!(
+            "{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+            classes, memo, egraph.unionfind, by_op, egraph.pending
+        )
+    }
+
+    #[test]
+    fn push_pop() {
+        let mut egraph = EGraph::<SymbolLang, Fold>::default();
+        let a = egraph.add_expr(&"(+ (f x) (+ 1 2))".parse().unwrap());
+        let b = egraph.add_expr(&"(+ (f y) 3)".parse().unwrap());
+        let u1 = egraph.add_expr(&"(+ u 1)".parse().unwrap());
+        egraph.rebuild();
+        let before = state(&egraph);
+
+        egraph.push();
+        let x = egraph.add_expr(&"x".parse().unwrap());
+        let y = egraph.add_expr(&"y".parse().unwrap());
+        egraph.add_expr(&"(g (+ 2 1))".parse().unwrap());
+        egraph.union(x, y);
+        let u = egraph.add_expr(&"u".parse().unwrap());
+        let five = egraph.add_expr(&"5".parse().unwrap());
+        egraph.union(u, five);
+        egraph.rebuild();
+        assert_eq!(egraph[u1].data, Some(6));
+        assert_eq!(egraph.find(a), egraph.find(b));
+        let after = state(&egraph);
+
+        // nested, and popped without rebuilding
+        egraph.push();
+        let z = egraph.add_expr(&"(+ z 4)".parse().unwrap());
+        egraph.union(z, a);
+        egraph.pop();
+        assert_eq!(state(&egraph), after);
+        assert_eq!(egraph.snapshots(), 1);
+
+        egraph.pop();
+        assert_eq!(state(&egraph), before);
+        assert_eq!(egraph.snapshots(), 0);
+        assert_ne!(egraph.find(a), egraph.find(b));
+
+        // the e-graph still works after popping
+        egraph.union(x, y);
+        egraph.rebuild();
+        assert_eq!(egraph.find(a), egraph.find(b));
+    }
+
+    #[test]
+    fn retain_reachable() {
+        let mut egraph = EGraph::<SymbolLang, Fold>::default();
+        let garbage = egraph.add_expr(&"(g (+ 1 (h x)))".parse().unwrap());
+        let root = egraph.add_expr(&"(f x (+ 1 2) u)".parse().unwrap());
+        let u = egraph.add_expr(&"u".parse().unwrap());
+        let v = egraph.add_expr(&"v".parse().unwrap());
+        egraph.union(u, v);
+        egraph.rebuild();
+
+        let ids = egraph.retain_reachable(&[root]);
+        assert_eq!(ids.get(garbage), None);
+        assert_eq!(ids[u], ids[v]);
+        // x, 1, 2, (+ 1 2) and 3, u and v, and f
+        assert_eq!(egraph.number_of_classes(), 6);
+        assert_eq!(egraph.total_number_of_nodes(), 8);
+        let max = egraph.classes().map(|c| usize::from(c.id)).max();
+        assert_eq!(max, Some(5));
+
+        // the parent link from (+ 1 (h x)) to 1 is gone
+        let one = egraph.lookup_expr(&"1".parse().unwrap()).unwrap();
+        assert_eq!(egraph[one].parents.len(), 1);
+
+        // the e-graph still works, and finds the same classes again
+        let three = egraph.add_expr(&"(+ 2 1)".parse().unwrap());
+        let g = egraph.add_expr(&"(g (f x 3 v))".parse().unwrap());
+        egraph.rebuild();
+        assert_eq!(
+            egraph.find(three),
+            egraph.lookup_expr(&"3".parse().unwrap()).unwrap()
+        );
+        assert_eq!(egraph[g].nodes[0].children, vec![ids[root]]);
+    }
+
+    #[test]
+    fn subgraph() {
+        let mut egraph = EGraph::<SymbolLang, Fold>::default();
+        let root = egraph.add_expr(&"(f (+ 1 2) (g y))".parse().unwrap());
+        let other = egraph.add_expr(&"(h y)".parse().unwrap());
+        egraph.rebuild();
+        let sum = egraph.lookup_expr(&"(+ 1 2)".parse().unwrap()).unwrap();
+        let gy = egraph.lookup_expr(&"(g y)".parse().unwrap()).unwrap();
+
+        let (sub, ids) = egraph.subgraph(&[root]);
+        assert!(sub.check_memo());
+        // f, 1, 2, 3 = (+ 1 2), g and y, but not (h y)
+        assert_eq!(sub.number_of_classes(), 6);
+        assert_eq!(ids.get(other), None);
+        assert_eq!(sub[ids[sum]].data, Some(3));
+        assert_eq!(sub[ids[sum]].len(), 2);
+        let y = sub.lookup(SymbolLang::leaf("y")).unwrap();
+        assert_eq!(sub[y].parents.len(), 1);
+        let y_parent = SymbolLang::new("g", vec![y]);
+        assert_eq!(sub.lookup(y_parent), Some(ids[gy]));
+
+        let (shallow, ids) = egraph.subgraph_to_depth(&[root], 0);
+        assert!(shallow.check_memo());
+        assert_eq!(shallow.number_of_classes(), 3);
+        assert_eq!(ids.get(egraph.lookup(SymbolLang::leaf("y")).unwrap()), None);
+        let placeholder = SymbolLang::leaf(format!("<{}>", sum));
+        assert_eq!(shallow[ids[sum]].nodes, vec![placeholder]);
+        assert_eq!(shallow[ids[sum]].data, Some(3));
+        assert_eq!(shallow[ids[root]].parents.len(), 0);
+        assert_eq!(shallow[ids[gy]].parents.len(), 1);
+    }
+
+    #[test]
+    fn contexts() {
+        let mut egraph = EGraph::<SymbolLang, ()>::default();
+        let gfx = egraph.add_expr(&"(g (f x))".parse().unwrap());
+        let fy = egraph.add_expr(&"(f y)".parse().unwrap());
+        let x = egraph.lookup(SymbolLang::leaf("x")).unwrap();
+        let y = egraph.lookup(SymbolLang::leaf("y")).unwrap();
+        egraph.rebuild();
+        let before = state(&egraph);
+
+        let ctx = egraph.with_assumption(x, y);
+        let other = egraph.with_assumption(fy, gfx);
+        egraph.rebuild();
+        assert_eq!(state(&egraph), before);
+        assert_eq!(egraph.find_in(ctx, x), egraph.find_in(ctx, y));
+        let fx = egraph.lookup_expr(&"(f x)".parse().unwrap()).unwrap();
+        assert_eq!(egraph.find_in(ctx, fx), egraph.find_in(ctx, fy));
+        assert_ne!(egraph.find_in(ctx, gfx), egraph.find_in(ctx, fy));
+        assert_ne!(egraph.find_in(other, x), egraph.find_in(other, y));
+
+        // a union outside the context shows up in it after a rebuild
+        let gfy = egraph.add_expr(&"(g (f y))".parse().unwrap());
+        let z = egraph.add(SymbolLang::leaf("z"));
+        egraph.union(z, gfy);
+        egraph.rebuild();
+        assert_eq!(egraph.find_in(ctx, z), egraph.find_in(ctx, gfx));
+        assert_ne!(egraph.find(z), egraph.find(gfx));
+
+        // so do new enodes, and new unions in the context
+        let kx = egraph.add_expr(&"(k x)".parse().unwrap());
+        let ky = egraph.add_expr(&"(k y)".parse().unwrap());
+        let kz = egraph.add_expr(&"(k z)".parse().unwrap());
+        egraph.rebuild();
+        assert_eq!(egraph.find_in(ctx, kx), egraph.find_in(ctx, ky));
+        assert_ne!(egraph.find_in(ctx, kx), egraph.find_in(ctx, kz));
+        assert!(egraph.union_in(ctx, z, y));
+        egraph.rebuild();
+        assert_eq!(egraph.find_in(ctx, kx), egraph.find_in(ctx, kz));
+        assert_eq!(egraph.find_in(ctx, x), egraph.find_in(ctx, gfx));
+
+        let rules = [rewrite!("g"; "(g ?a)" => "(h ?a)")];
+        let before = state(&egraph);
+        egraph.push();
+        assert_eq!(egraph.apply_in(other, &[&rules[0]]), 2);
+        egraph.rebuild();
+        let hfx = egraph.lookup_expr(&"(h (f x))".parse().unwrap()).unwrap();
+        assert_ne!(egraph.find(hfx), egraph.find(gfx));
+        assert_eq!(egraph.find_in(other, hfx), egraph.find_in(other, gfx));
+        assert_eq!(egraph.find_in(other, hfx), egraph.find_in(other, fy));
+        egraph.pop();
+        assert_eq!(state(&egraph), before);
+        assert_eq!(egraph.contexts[other.0].assumptions.len(), 1);
+        assert_eq!(egraph.find_in(other, gfx), egraph.find_in(other, fy));
+
+        // a pop undoes the contexts too, dropping those made since the push
+        let context_state = |context: &Context<SymbolLang>| {
+            let mut memo: Vec<_> = context.memo.iter().collect();
+            memo.sort();
+            let mut parents: Vec<_> = (context.parents.iter())
+                .filter(|(_, parents)| !parents.is_empty())
+                .collect();
+            parents.sort();
+            format!("{:?}\n{:?}\n{:?}", memo, parents, context.unionfind)
+        };
+        let before = context_state(&egraph.contexts[ctx.0]);
+        egraph.push();
+        egraph.union_in(ctx, kx, fy);
+        egraph.with_assumption(x, z);
+        egraph.add_expr(&"(k (g (f y)))".parse().unwrap());
+        egraph.rebuild();
+        assert_eq!(egraph.find_in(ctx, kx), egraph.find_in(ctx, fy));
+        egraph.pop();
+        assert_eq!(egraph.contexts.len(), 2);
+        assert_eq!(context_state(&egraph.contexts[ctx.0]), before);
+        assert_ne!(egraph.find_in(ctx, kx), egraph.find_in(ctx, fy));
+    }
+
+    #[test]
+    fn apply_in_modify() {
+        let mut egraph = EGraph::<SymbolLang, Fold>::default();
+        let fx = egraph.add_expr(&"(f x)".parse().unwrap());
+        let x = egraph.lookup(SymbolLang::leaf("x")).unwrap();
+        let ctx = egraph.with_assumption(x, fx);
+        egraph.rebuild();
+
+        // the applier's union only holds in the context, but the one
+        // constant folding makes holds in the e-graph
+        let rules = [rewrite!("f"; "(f ?a)" => "(+ 1 2)")];
+        assert_eq!(egraph.apply_in(ctx, &[&rules[0]]), 1);
+        egraph.rebuild();
+        let sum = egraph.lookup_expr(&"(+ 1 2)".parse().unwrap()).unwrap();
+        let three = egraph.lookup(SymbolLang::leaf("3")).unwrap();
+        assert_eq!(egraph.find(sum), egraph.find(three));
+        assert_ne!(egraph.find(sum), egraph.find(fx));
+        assert_eq!(egraph.find_in(ctx, three), egraph.find_in(ctx, fx));
+    }
+
+    #[test]
+    fn check_analysis() {
+        let mut egraph = EGraph::<SymbolLang, Fold>::default();
+        let sum = egraph.add_expr(&"(+ 1 2)".parse().unwrap());
+        let fx = egraph.add_expr(&"(f x)".parse().unwrap());
+        egraph.union(sum, fx);
+        egraph.rebuild();
+        assert!(egraph.check_analysis().is_ok());
+
+        // data that no enode makes
+        let x = egraph.lookup(SymbolLang::leaf("x")).unwrap();
+        egraph[x].data = Some(5);
+        let err = egraph.check_analysis().unwrap_err();
+        assert_eq!((err.class, err.node), (x, None));
+        assert!(egraph
+            .check_analysis_by(|data, made| made.is_none() || data == made)
+            .is_ok());
+
+        // data missing what an enode makes
+        egraph[x].data = None;
+        egraph[sum].data = Some(2);
+        let err = egraph.check_analysis().unwrap_err();
+        assert_eq!(err.class, egraph.find(sum));
+        assert!(matches!(err.node, Some(n) if n.op.as_str() != "f"));
+    }
+
+    #[test]
+    fn stats() {
+        define_language! {
+            enum Calc {
+                "+" = Add([Id; 2]),
+                "-" = Sub([Id; 2]),
+                Num(i32),
+            }
+        }
+        let mut egraph = EGraph::<Calc, ()>::default();
+        let a = egraph.add_expr(&"(+ 1 (+ 2 3))".parse().unwrap());
+        let b = egraph.add_expr(&"(- 7 1)".parse().unwrap());
+        egraph.union(a, b);
+        egraph.rebuild();
+
+        let stats = egraph.stats();
+        assert_eq!((stats.classes, stats.nodes, stats.memo_size), (6, 7, 7));
+        let ops: Vec<_> = (stats.nodes_by_op.iter())
+            .map(|(op, n)| (op.as_str(), *n))
+            .collect();
+        assert_eq!(ops, [("Num", 4), ("Add", 2), ("Sub", 1)]);
+        let sizes: Vec<_> = stats.class_sizes.clone().into_iter().collect();
+        assert_eq!(sizes, [(1, 5), (2, 1)]);
+        assert_eq!(stats.biggest_classes[0], (egraph.find(a), 2));
+        assert_eq!(stats.biggest_classes.len(), 6);
+        let parents: Vec<_> = stats.parent_counts.clone().into_iter().collect();
+        assert_eq!(parents, [(0, 1), (1, 4), (2, 1)]);
+        assert_eq!(stats.classes_with_leaf, 4);
+        assert!(stats.memo_load_factor > 0.0 && stats.memo_load_factor <= 1.0);
+        assert!(stats
+            .to_string()
+            .contains("Nodes by op: Num: 4, Add: 2, Sub: 1\n"));
+    }
+
+    #[test]
+    fn query_equal() {
+        let mut egraph = EGraph::<SymbolLang, ()>::default();
+        let fx = egraph.add_expr(&"(f x)".parse().unwrap());
+        let gy = egraph.add_expr(&"(g y)".parse().unwrap());
+        egraph.union(fx, gy);
+        egraph.rebuild();
+        let before = state(&egraph);
+
+        let q = |a: &str, b: &str| egraph.query_equal(&a.parse().unwrap(), &b.parse().unwrap());
+        assert_eq!(q("(f x)", "(g y)"), Some(true));
+        assert_eq!(q("x", "y"), Some(false));
+        // neither is there, but they are the same term
+        assert_eq!(q("(h z z)", "(h z z)"), Some(true));
+        // congruent to each other above classes that are there
+        assert_eq!(q("(k (f x) 1)", "(k (g y) 1)"), Some(true));
+        assert_eq!(q("(k (f x) 1)", "(k (g y) 2)"), None);
+        assert_eq!(q("(k (f x))", "(f x)"), None);
+        assert_eq!(state(&egraph), before);
+    }
+
+    #[test]
+    fn enumerate_terms() {
+        let mut egraph = EGraph::<SymbolLang, ()>::default();
+        let sum = egraph.add_expr(&"(+ a b)".parse().unwrap());
+        let swapped = egraph.add_expr(&"(+ b a)".parse().unwrap());
+        let a = egraph.lookup(SymbolLang::leaf("a")).unwrap();
+        let c = egraph.add(SymbolLang::leaf("c"));
+        egraph.union(sum, swapped);
+        egraph.union(a, c);
+        egraph.union(sum, a);
+        egraph.rebuild();
+
+        // a = c = (+ a b) = (+ b a), so the terms never run out
+        let terms: Vec<RecExpr<SymbolLang>> = egraph.enumerate_terms(sum, 5).collect();
+        let n = egraph.count_terms(sum, 5);
+        assert_eq!(n, terms.len() as u64);
+        let mut sizes: Vec<usize> = terms.iter().map(|t| t.as_ref().len()).collect();
+        assert!(sizes.windows(2).all(|w| w[0] <= w[1]));
+        sizes.dedup();
+        assert_eq!(sizes, [1, 3, 5]);
+        let distinct: HashSet<String> = terms.iter().map(|t| t.to_string()).collect();
+        assert_eq!(distinct.len(), terms.len());
+        for term in &terms {
+            assert_eq!(egraph.lookup_expr(term), Some(egraph.find(sum)));
+        }
+        // a and c, then (+ ? b) and (+ b ?) of each smaller term
+        assert_eq!(n, 2 + 4 + 8);
+
+        assert_eq!(egraph.count_terms(sum, 200), u64::MAX);
+        assert_eq!(
+            egraph.count_terms(egraph.lookup(SymbolLang::leaf("b")).unwrap(), 9),
+            1
+        );
+    }
+
+    #[test]
+    fn absorb() {
+        let mut left = EGraph::<SymbolLang, Fold>::default();
+        let fx = left.add_expr(&"(f x)".parse().unwrap());
+        let gy = left.add_expr(&"(g y)".parse().unwrap());
+        left.union(fx, gy);
+        left.rebuild();
+
+        let mut right = EGraph::<SymbolLang, Fold>::default();
+        let x = right.add_expr(&"x".parse().unwrap());
+        let gy = right.add_expr(&"(g y)".parse().unwrap());
+        let sum = right.add_expr(&"(+ 1 2)".parse().unwrap());
+        let x_plus = right.add_expr(&"(+ x 4)".parse().unwrap());
+        right.union(x, sum);
+        right.union(gy, x_plus);
+        right.rebuild();
+
+        let ids = left.absorb(right);
+        left.rebuild();
+        assert_eq!(ids.len(), 10);
+        // (f x) = (g y) from the left, and (g y) = (+ x 4) and x = 3 from
+        // the right, so (f x) = (f 3) = 7
+        assert_eq!(left.find(fx), ids[gy]);
+        assert_eq!(left.find(fx), ids[x_plus]);
+        assert_eq!(left.find(ids[x]), ids[sum]);
+        assert_eq!(left[ids[x]].data, Some(3));
+        assert_eq!(left[fx].data, Some(7));
+        let f3 = left.lookup_expr(&"(f 3)".parse().unwrap());
+        assert_eq!(f3, Some(left.find(fx)));
+    }
+
+    // the class of the first child, to check that ids in data are translated
+    #[derive(Default)]
+    struct FirstChild;
+    impl Analysis<SymbolLang> for FirstChild {
+        type Data = Option<Id>;
+        fn make(egraph: &EGraph<SymbolLang, Self>, enode: &SymbolLang) -> Self::Data {
+            enode.children.first().map(|&c| egraph.find(c))
+        }
+        fn merge(&mut self, to: &mut Self::Data, from: Self::Data) -> DidMerge {
+            crate::merge_min(to, from)
+        }
+        fn renumber(&mut self, data: &mut Self::Data, ids: &IdMap) {
+            *data = data.and_then(|id| ids.get(id));
+        }
+    }
+
+    #[test]
+    fn absorb_renumber() {
+        let mut left = EGraph::<SymbolLang, FirstChild>::default();
+        left.add_expr(&"(g a b c)".parse().unwrap());
+        let mut right = EGraph::<SymbolLang, FirstChild>::default();
+        let fx = right.add_expr(&"(f x)".parse().unwrap());
+        assert_eq!(right[fx].data, Some(Id::from(0)));
+
+        let ids = left.absorb(right);
+        left.rebuild();
+        let x = left.lookup(SymbolLang::leaf("x")).unwrap();
+        assert_eq!(left[ids[fx]].data, Some(x));
+    }
+
+    #[test]
+    fn absorb_explanations() {
+        let mut left = EGraph::<SymbolLang, ()>::default().with_explanations_enabled();
+        let a = "(f a)".parse().unwrap();
+        let c = "(f c)".parse().unwrap();
+        left.add_expr(&a);
+        left.rebuild();
+
+        let mut right = EGraph::<SymbolLang, ()>::default().with_explanations_enabled();
+        let subst = Default::default();
+        let (lhs, rhs) = (&"a".parse().unwrap(), &"b".parse().unwrap());
+        right.union_instantiations(lhs, rhs, &subst, "a-b");
+        let (lhs, rhs) = (&"(f c)".parse().unwrap(), &"(f b)".parse().unwrap());
+        right.union_instantiations(lhs, rhs, &subst, "fc-fb");
+        right.rebuild();
+
+        left.absorb(right);
+        left.rebuild();
+        let mut explanation = left.explain_equivalence(&a, &c);
+        assert_eq!(
+            explanation.get_flat_strings(),
+            vec!["(f a)", "(f (Rewrite=> a-b b))", "(Rewrite<= fc-fb (f c))"]
+        );
+    }
+
+    #[test]
+    fn ac_canonical_rebuild() {
+        define_language! {
+            enum Math {
+                #[ac] "+" = Add(Vec<Id>),
+                Symbol(Symbol),
+            }
+        }
+        assert!("(+)".parse::<RecExpr<Math>>().is_err());
+        assert!("(+ a)".parse::<RecExpr<Math>>().is_err());
+
+        let mut egraph = EGraph::<Math, ()>::default().with_ac_canonicalization();
+        let ab = egraph.add_expr(&"(+ a b)".parse().unwrap());
+        let cb = egraph.add_expr(&"(+ c b)".parse().unwrap());
+        let id = |s: &str| egraph.lookup_expr(&s.parse().unwrap()).unwrap();
+        let (a, b, c) = (id("a"), id("b"), id("c"));
+        assert_ne!(ab, cb);
+        egraph.union(a, c);
+        egraph.rebuild();
+        assert_eq!(egraph.find(ab), egraph.find(cb));
+
+        // enodes added directly are sorted too
+        let ba = egraph.add(Math::Add(vec![b, a]));
+        assert_eq!(egraph.find(ba), egraph.find(ab));
+        assert_eq!(egraph.lookup(Math::Add(vec![b, c])), Some(egraph.find(ab)));
+        let abc = egraph.add_expr(&"(+ a (+ b c))".parse().unwrap());
+        assert_eq!(
+            egraph.lookup_expr(&"(+ (+ c b) a)".parse().unwrap()),
+            Some(abc)
+        );
+
+        // only commutativity stays canonical: (+ h d) isn't flattened
+        // into (+ a b d) when h becomes (+ a b)
+        let hd = egraph.add_expr(&"(+ h d)".parse().unwrap());
+        let h = egraph.lookup(Math::Symbol("h".into())).unwrap();
+        egraph.union(h, ab);
+        egraph.rebuild();
+        assert_eq!(egraph.lookup_expr(&"(+ a b d)".parse().unwrap()), None);
+        assert_eq!(egraph.lookup_expr(&"(+ (+ b a) d)".parse().unwrap()), None);
+        assert_eq!(
+            egraph.lookup_expr(&"(+ d h)".parse().unwrap()),
+            Some(egraph.find(hd))
+        );
+
+        let q = |a: &str, b: &str| egraph.query_equal(&a.parse().unwrap(), &b.parse().unwrap());
+        assert_eq!(q("(+ b a)", "(+ c b)"), Some(true));
+        assert_eq!(q("(+ (+ b c) a)", "(+ a b c)"), Some(true));
+        // missing terms are compared up to AC too
+        assert_eq!(q("(+ d e)", "(+ e d)"), Some(true));
+        assert_eq!(q("(+ d (+ e a))", "(+ a e d)"), Some(true));
     }
 
     #[cfg(all(feature = "serde-1", feature = "serde_json"))]
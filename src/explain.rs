@@ -824,6 +824,13 @@ impl<L: Language> Explain<L> {
         }
     }
 
+    /// The enodes in the order they were added, each with the enode it
+    /// points to in the proof forest (itself for a root), the justification
+    /// of that edge and whether it goes from the enode to the next one.
+    pub(crate) fn proof_edges(&self) -> impl Iterator<Item = (&L, Id, &Justification, bool)> {
+        (self.explainfind.iter()).map(|n| (&n.node, n.next, &n.justification, n.is_rewrite_forward))
+    }
+
     pub(crate) fn set_existance_reason(&mut self, node: Id, existance_node: Id) {
         self.explainfind[usize::from(node)].existance_node = existance_node;
     }
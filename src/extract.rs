@@ -215,9 +215,15 @@ where
 
     /// Find the cheapest (lowest cost) represented `RecExpr` in the
     /// given eclass.
+    ///
+    /// If the e-graph [canonicalizes AC operators](EGraph::with_ac_canonicalization),
+    /// so is the returned term; the cost is still that of the unflattened term.
     pub fn find_best(&self, eclass: Id) -> (CF::Cost, RecExpr<L>) {
         let (cost, root) = self.costs[&self.egraph.find(eclass)].clone();
         let expr = root.build_recexpr(|id| self.find_best_node(id).clone());
+        if self.egraph.is_ac_canonicalization_enabled() {
+            return (cost, expr.flatten_ac());
+        }
         (cost, expr)
     }
 
@@ -285,3 +291,160 @@ where
         cost.map(|c| (c, node.clone()))
     }
 }
+
+/// An iterator over the distinct terms of an eclass, smallest first,
+/// made by [`EGraph::enumerate_terms`].
+///
+/// This holds only a table of the number of terms of each size of each
+/// eclass below the root, and builds each term from its position in the
+/// order, so it takes the same memory however many terms it yields.
+pub struct Terms<'a, L: Language, N: Analysis<L>> {
+    egraph: &'a EGraph<L, N>,
+    counts: TermCounts,
+    root: Id,
+    size: usize,
+    index: u64,
+}
+
+impl<'a, L: Language, N: Analysis<L>> Terms<'a, L, N> {
+    pub(crate) fn new(egraph: &'a EGraph<L, N>, root: Id, max_size: usize) -> Self {
+        let root = egraph.find(root);
+        Terms {
+            counts: TermCounts::new(egraph, root, max_size),
+            egraph,
+            root,
+            size: 1,
+            index: 0,
+        }
+    }
+
+    /// Adds the term of `size` at position `index` of eclass `id` to `expr`.
+    fn build(&self, expr: &mut RecExpr<L>, id: Id, size: usize, mut index: u64) -> Id {
+        for node in self.egraph[id].iter() {
+            let n = self.counts.children(self.egraph, node.children(), size - 1);
+            if index >= n {
+                index -= n;
+                continue;
+            }
+
+            let children = node.children();
+            let mut ids = Vec::with_capacity(children.len());
+            let mut rest = size - 1;
+            for (i, &child) in children.iter().enumerate() {
+                let child = self.egraph.find(child);
+                for child_size in 1..=rest {
+                    let count = self.counts.of(child, child_size);
+                    let others = &children[i + 1..];
+                    let tails = self.counts.children(self.egraph, others, rest - child_size);
+                    let n = count.saturating_mul(tails);
+                    if index < n {
+                        ids.push(self.build(expr, child, child_size, index % count));
+                        index /= count;
+                        rest -= child_size;
+                        break;
+                    }
+                    index -= n;
+                }
+            }
+
+            let mut ids = ids.into_iter();
+            return expr.add(node.clone().map_children(|_| ids.next().unwrap()));
+        }
+        unreachable!("no term {} of size {} in eclass {}", index, size, id)
+    }
+}
+
+impl<'a, L: Language, N: Analysis<L>> Iterator for Terms<'a, L, N> {
+    type Item = RecExpr<L>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.size <= self.counts.max_size {
+            if self.index < self.counts.of(self.root, self.size) {
+                let mut expr = RecExpr::default();
+                self.build(&mut expr, self.root, self.size, self.index);
+                self.index += 1;
+                return Some(expr);
+            }
+            self.size += 1;
+            self.index = 0;
+        }
+        None
+    }
+}
+
+/// The number of terms of each size of some eclasses, saturating at
+/// `u64::MAX`.
+pub(crate) struct TermCounts {
+    /// Indexed by size, from 0
+    counts: HashMap<Id, Vec<u64>>,
+    max_size: usize,
+}
+
+impl TermCounts {
+    /// Counts the terms of up to `max_size` enodes of the eclasses below
+    /// `root`.
+    pub(crate) fn new<L: Language, N: Analysis<L>>(
+        egraph: &EGraph<L, N>,
+        root: Id,
+        max_size: usize,
+    ) -> Self {
+        let mut counts = HashMap::default();
+        let mut todo = vec![egraph.find(root)];
+        while let Some(id) = todo.pop() {
+            if counts.insert(id, vec![0; max_size + 1]).is_none() {
+                let children = egraph[id].iter().flat_map(|n| n.children());
+                todo.extend(children.map(|&c| egraph.find(c)));
+            }
+        }
+        let mut term_counts = TermCounts { counts, max_size };
+
+        // a term's children are smaller than it, even in a cycle
+        let ids: Vec<Id> = term_counts.counts.keys().copied().collect();
+        for size in 1..=max_size {
+            for &id in &ids {
+                let n = egraph[id].iter().fold(0u64, |sum, node| {
+                    let n = term_counts.children(egraph, node.children(), size - 1);
+                    sum.saturating_add(n)
+                });
+                term_counts.counts.get_mut(&id).unwrap()[size] = n;
+            }
+        }
+        term_counts
+    }
+
+    /// The number of terms of eclass `id` with `size` enodes.
+    pub(crate) fn of(&self, id: Id, size: usize) -> u64 {
+        self.counts[&id][size]
+    }
+
+    /// The number of terms of eclass `id` with at most `max_size` enodes.
+    pub(crate) fn total(&self, id: Id) -> u64 {
+        let counts = self.counts[&id].iter();
+        counts.fold(0, |sum, &n| sum.saturating_add(n))
+    }
+
+    /// The number of ways to pick a term for each of `children` with
+    /// `size` enodes between them.
+    fn children<L: Language, N: Analysis<L>>(
+        &self,
+        egraph: &EGraph<L, N>,
+        children: &[Id],
+        size: usize,
+    ) -> u64 {
+        // ways[s] is the number of ways for the children so far to have size s
+        let mut ways = vec![0u64; size + 1];
+        ways[0] = 1;
+        for &child in children {
+            let counts = &self.counts[&egraph.find(child)];
+            let mut next = vec![0u64; size + 1];
+            for (so_far, &w) in ways.iter().enumerate().filter(|&(_, &w)| w > 0) {
+                for child_size in 1..=size - so_far {
+                    let n = w.saturating_mul(counts[child_size]);
+                    next[so_far + child_size] = next[so_far + child_size].saturating_add(n);
+                }
+            }
+            ways = next;
+        }
+        ways[size]
+    }
+}
@@ -1,15 +1,17 @@
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hash};
 use std::ops::{BitOr, Index, IndexMut};
+use std::str::FromStr;
 use std::{cmp::Ordering, convert::TryFrom};
 use std::{
     convert::Infallible,
     fmt::{self, Debug, Display},
 };
-use std::{hash::Hash, str::FromStr};
 
 use crate::*;
 
 use fmt::Formatter;
-use symbolic_expressions::{Sexp, SexpError};
+use symbolic_expressions::Sexp;
 use thiserror::Error;
 
 /// Trait that defines a Language whose terms will be in the [`EGraph`].
@@ -39,6 +41,68 @@ pub trait Language: Debug + Clone + Eq + Ord + Hash {
     /// Returns a mutable slice of the children of this e-node.
     fn children_mut(&mut self) -> &mut [Id];
 
+    /// Returns true if this enode is an operator that is associative
+    /// and commutative.
+    ///
+    /// [`Pattern`]s match AC operators modulo associativity and
+    /// commutativity.
+    /// A nested pattern like `(+ ?a (+ ?b ?c))` matches any e-class that can
+    /// be flattened into a sum of at least three parts, where each of `?a`,
+    /// `?b` and `?c` is bound to an existing e-class summing some of them.
+    /// Matching never adds sums, so two sums added separately are only made
+    /// equal by rules like `(+ ?a ?b) => (+ ?b ?a)`, or by
+    /// [`EGraph::with_ac_canonicalization`].
+    ///
+    /// AC matching is exponential, so an e-class is only flattened into sums
+    /// of at most 6 parts, and into at most 32 different sums;
+    /// matches past that are skipped, with a warning in the log.
+    ///
+    /// AC matches can't be justified by [`Explanation`]s, so
+    /// [`Runner::run`] panics on rules that search for AC operators if
+    /// explanations are enabled.
+    ///
+    /// The [`define_language!`] macro implements this for variants marked
+    /// `#[ac]`; the default is `false`.
+    fn is_ac(&self) -> bool {
+        false
+    }
+
+    /// Returns the name of this enode's variant, like `"Num"` for a
+    /// [`define_language!`] variant `Num(i32)`, or `None` if it has none.
+    ///
+    /// Annotated pattern variables like `?x:Num` only match e-classes that
+    /// contain an enode of that variant.
+    /// The [`define_language!`] macro implements this; the default is `None`.
+    fn variant_name(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Returns the names of all of this language's variants, or `None` if
+    /// they aren't known.
+    ///
+    /// [`Rewrite::new`] rejects patterns annotated with other names.
+    /// The [`define_language!`] macro implements this; the default is `None`.
+    fn variant_names() -> Option<&'static [&'static str]> {
+        None
+    }
+
+    /// Returns this enode's operator with the given children, or `None` if
+    /// the operator can't take that many.
+    ///
+    /// This is how AC operators move between their binary and n-ary forms
+    /// (see [`RecExpr::flatten_ac`]).
+    /// The default only accepts as many children as `self` has;
+    /// the [`define_language!`] macro accepts any length the variant's
+    /// [`LanguageChildren`] can be.
+    fn rebuild_with(&self, children: Vec<Id>) -> Option<Self> {
+        if children.len() != self.len() {
+            return None;
+        }
+        let mut node = self.clone();
+        node.children_mut().copy_from_slice(&children);
+        Some(node)
+    }
+
     /// Runs a given function on each child `Id`.
     fn for_each<F: FnMut(Id)>(&self, f: F) {
         self.children().iter().copied().for_each(f)
@@ -282,6 +346,22 @@ impl FromOpError {
     }
 }
 
+/// Returns true if `a` and `b` are the same operator, regardless of how many
+/// children they have.
+pub(crate) fn same_op<L: Language>(a: &L, b: &L) -> bool {
+    if a.matches(b) {
+        return true;
+    }
+    let rebuilt = a.rebuild_with(b.children().to_vec());
+    rebuilt.map_or(false, |n| n.matches(b))
+}
+
+/// Returns true if `a` and `b` are the same AC operator, regardless of how
+/// many children they have.
+pub(crate) fn same_ac_op<L: Language>(a: &L, b: &L) -> bool {
+    a.is_ac() && b.is_ac() && same_op(a, b)
+}
+
 /// A marker that defines acceptable children types for [`define_language!`].
 ///
 /// See [`define_language!`] for more details.
@@ -428,6 +508,92 @@ impl<L: Language> RecExpr<L> {
         self[new_root].build_recexpr(|id| self[id].clone())
     }
 
+    /// Flattens nested AC operators (see [`Language::is_ac`]) into n-ary
+    /// nodes with sorted children, so AC-equal terms become equal.
+    ///
+    /// Operators that can't take more children
+    /// (see [`Language::rebuild_with`]) just have their children sorted.
+    ///
+    /// # Example
+    /// ```
+    /// # use egg::*;
+    /// define_language! {
+    ///     enum Math {
+    ///         #[ac] "+" = Add(Vec<Id>),
+    ///         Num(i32),
+    ///     }
+    /// }
+    /// let a: RecExpr<Math> = "(+ 3 (+ 2 1))".parse().unwrap();
+    /// let b: RecExpr<Math> = "(+ (+ 1 3) 2)".parse().unwrap();
+    /// assert_eq!(a.flatten_ac().to_string(), "(+ 1 2 3)");
+    /// assert_eq!(a.flatten_ac(), b.flatten_ac());
+    /// assert_eq!(b.flatten_ac().binarize_ac().to_string(), "(+ 1 (+ 2 3))");
+    /// ```
+    pub fn flatten_ac(&self) -> Self {
+        let mut flat = RecExpr::default();
+        let mut memo = HashMap::<L, Id>::default();
+        let mut ids = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let mut node = node.clone().map_children(|id| ids[usize::from(id)]);
+            if node.is_ac() {
+                let mut children = vec![];
+                for &c in node.children() {
+                    match &flat[c] {
+                        n if same_ac_op(n, &node) => children.extend_from_slice(n.children()),
+                        _ => children.push(c),
+                    }
+                }
+                children.sort_by(|a, b| flat.cmp_terms(*a, *b));
+                node = node.rebuild_with(children).unwrap_or_else(|| {
+                    node.children_mut().sort_by(|a, b| flat.cmp_terms(*a, *b));
+                    node
+                });
+            }
+            let id = *memo.entry(node.clone()).or_insert_with(|| flat.add(node));
+            ids.push(id);
+        }
+        match ids.last() {
+            Some(&root) => flat.extract(root),
+            None => flat,
+        }
+    }
+
+    /// Turns the n-ary AC nodes made by [`RecExpr::flatten_ac`] back into
+    /// right-nested binary ones.
+    pub fn binarize_ac(&self) -> Self {
+        let mut binary = RecExpr::default();
+        let mut ids = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let node = node.clone().map_children(|id| ids[usize::from(id)]);
+            let pair = |l, r| node.rebuild_with(vec![l, r]);
+            let mut children = node.children().iter().rev().copied();
+            let id = match children.next() {
+                Some(last) if node.is_ac() && node.len() > 2 && pair(last, last).is_some() => {
+                    children.fold(last, |acc, c| binary.add(pair(c, acc).unwrap()))
+                }
+                _ => binary.add(node.clone()),
+            };
+            ids.push(id);
+        }
+        binary
+    }
+
+    /// Compares the subterms at `a` and `b` structurally
+    fn cmp_terms(&self, a: Id, b: Id) -> Ordering {
+        if a == b {
+            return Ordering::Equal;
+        }
+        let (x, y) = (&self[a], &self[b]);
+        let op = |n: &L| n.clone().map_children(|_| Id::from(0));
+        op(x).cmp(&op(y)).then_with(|| {
+            let children = x.children().iter().zip(y.children());
+            children
+                .map(|(c, d)| self.cmp_terms(*c, *d))
+                .find(|o| *o != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        })
+    }
+
     /// Checks if this expr is a DAG, i.e. doesn't have any back edges
     pub fn is_dag(&self) -> bool {
         for (i, n) in self.nodes.iter().enumerate() {
@@ -517,66 +683,223 @@ impl<L: Language + Display> RecExpr<L> {
     }
 }
 
+/// Where in its input a [`RecExprParseError`] happened.
+///
+/// This displays as the line and column followed by the offending line of
+/// the input, with the problem marked by carets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourcePos {
+    /// The byte offset into the input
+    pub offset: usize,
+    /// The line, counting from 1
+    pub line: usize,
+    /// The column in characters, counting from 1
+    pub column: usize,
+    snippet: String,
+}
+
+impl SourcePos {
+    /// The position of the bytes `start..end` of `input`.
+    pub(crate) fn new(input: &str, start: usize, end: usize) -> Self {
+        let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[start..].find('\n').map_or(input.len(), |i| start + i);
+        let text = &input[line_start..line_end];
+        let before = &input[line_start..start];
+
+        // keep tabs so the carets line up
+        let pad: String = before
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = input[start..end.min(line_end)].chars().count().max(1);
+        let snippet = format!("  {}\n  {}{}", text, pad, "^".repeat(width));
+
+        Self {
+            offset: start,
+            line: input[..start].matches('\n').count() + 1,
+            column: before.chars().count() + 1,
+            snippet,
+        }
+    }
+}
+
+impl Display for SourcePos {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}:\n{}",
+            self.line, self.column, self.snippet
+        )
+    }
+}
+
 /// An error type for failures when attempting to parse an s-expression as a
 /// [`RecExpr<L>`].
+///
+/// Every error records where in the input it happened.
+///
+/// ```
+/// # use egg::*;
+/// let err = "(+ a\n   (f b c d) ())".parse::<RecExpr<SymbolLang>>().unwrap_err();
+/// assert_eq!(err.pos().line, 2);
+/// assert_eq!(err.to_string(), "
+/// found empty s-expression at line 2, column 14:
+///      (f b c d) ())
+///                ^^
+/// ".trim_matches('\n'));
+/// ```
 #[derive(Debug, Error)]
 pub enum RecExprParseError<E> {
     /// An empty s-expression was found. Usually this is caused by an
-    /// empty list "()" somewhere in the input.
-    #[error("found empty s-expression")]
-    EmptySexp,
+    /// empty list "()" somewhere in the input, or by an empty input.
+    #[error("found empty s-expression at {0}")]
+    EmptySexp(SourcePos),
 
     /// A list was found where an operator was expected. This is caused by
     /// s-expressions of the form "((a b c) d e f)."
-    #[error("found a list in the head position: {0}")]
-    HeadList(Sexp),
+    #[error("found a list in the head position at {0}")]
+    HeadList(SourcePos),
+
+    /// Attempting to parse an operator into a value of type `L` failed,
+    /// for example because it was given the wrong number of children.
+    /// The position covers the whole e-node.
+    #[error("{0} at {1}")]
+    BadOp(E, SourcePos),
+
+    /// A list was never closed.
+    /// The position is that of its opening parenthesis.
+    #[error("unclosed parenthesis at {0}")]
+    UnclosedParen(SourcePos),
+
+    /// A closing parenthesis has no matching opening one.
+    /// Closing parentheses at the very end of the input are ignored with a
+    /// warning instead.
+    #[error("unexpected closing parenthesis at {0}")]
+    UnexpectedParen(SourcePos),
+
+    /// There is more input after a complete s-expression.
+    #[error("unexpected input after the s-expression at {0}")]
+    TrailingInput(SourcePos),
+}
 
-    /// Attempting to parse an operator into a value of type `L` failed.
-    #[error(transparent)]
-    BadOp(E),
+impl<E> RecExprParseError<E> {
+    /// Where in the input this error happened.
+    pub fn pos(&self) -> &SourcePos {
+        use RecExprParseError::*;
+        match self {
+            EmptySexp(pos) | HeadList(pos) | BadOp(_, pos) => pos,
+            UnclosedParen(pos) | UnexpectedParen(pos) | TrailingInput(pos) => pos,
+        }
+    }
+}
 
-    /// An error occurred while parsing the s-expression itself, generally
-    /// because the input had an invalid structure (e.g. unpaired parentheses).
-    #[error(transparent)]
-    BadSexp(SexpError),
+/// Parses s-expressions straight into a [`RecExpr`], keeping track of
+/// where each e-node came from for error messages.
+struct SexpParser<'a> {
+    input: &'a str,
+    offset: usize,
 }
 
-impl<L: FromOp> FromStr for RecExpr<L> {
-    type Err = RecExprParseError<L::Error>;
+impl<'a> SexpParser<'a> {
+    fn pos(&self, start: usize, end: usize) -> SourcePos {
+        SourcePos::new(self.input, start, end)
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.offset..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.offset += rest.len() - rest.trim_start().len();
+    }
+
+    // an operator, or a string in double quotes (which may contain anything)
+    fn atom(&mut self) -> &'a str {
+        let rest = self.rest();
+        let len = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut escaped = false;
+            let close = quoted.find(|c| {
+                let found = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                found
+            });
+            close.map_or(rest.len(), |i| i + 2)
+        } else {
+            let end = |c: char| c.is_whitespace() || c == '(' || c == ')';
+            rest.find(end).unwrap_or(rest.len())
+        };
+        self.offset += len;
+        &rest[..len]
+    }
+
+    fn expr<L: FromOp>(
+        &mut self,
+        expr: &mut RecExpr<L>,
+    ) -> Result<Id, RecExprParseError<L::Error>> {
         use RecExprParseError::*;
 
-        fn parse_sexp_into<L: FromOp>(
-            sexp: &Sexp,
-            expr: &mut RecExpr<L>,
-        ) -> Result<Id, RecExprParseError<L::Error>> {
-            match sexp {
-                Sexp::Empty => Err(EmptySexp),
-                Sexp::String(s) => {
-                    let node = L::from_op(s, vec![]).map_err(BadOp)?;
-                    Ok(expr.add(node))
+        self.skip_whitespace();
+        let start = self.offset;
+        let (op, children) = match self.rest().chars().next() {
+            None => return Err(EmptySexp(self.pos(start, start))),
+            Some(')') => return Err(UnexpectedParen(self.pos(start, start + 1))),
+            Some('(') => {
+                self.offset += 1;
+                self.skip_whitespace();
+                match self.rest().chars().next() {
+                    None => return Err(UnclosedParen(self.pos(start, start + 1))),
+                    Some(')') => return Err(EmptySexp(self.pos(start, self.offset + 1))),
+                    Some('(') => return Err(HeadList(self.pos(self.offset, self.offset + 1))),
+                    Some(_) => (),
                 }
-                Sexp::List(list) if list.is_empty() => Err(EmptySexp),
-                Sexp::List(list) => match &list[0] {
-                    Sexp::Empty => unreachable!("Cannot be in head position"),
-                    list @ Sexp::List(..) => Err(HeadList(list.to_owned())),
-                    Sexp::String(op) => {
-                        let arg_ids: Vec<Id> = list[1..]
-                            .iter()
-                            .map(|s| parse_sexp_into(s, expr))
-                            .collect::<Result<_, _>>()?;
-                        let node = L::from_op(op, arg_ids).map_err(BadOp)?;
-                        Ok(expr.add(node))
+                let op = self.atom();
+                let mut children = vec![];
+                loop {
+                    self.skip_whitespace();
+                    match self.rest().chars().next() {
+                        None => return Err(UnclosedParen(self.pos(start, start + 1))),
+                        Some(')') => break,
+                        Some(_) => children.push(self.expr(expr)?),
                     }
-                },
+                }
+                self.offset += 1;
+                (op, children)
             }
-        }
+            Some(_) => (self.atom(), vec![]),
+        };
+        let node = L::from_op(op, children).map_err(|e| BadOp(e, self.pos(start, self.offset)))?;
+        Ok(expr.add(node))
+    }
+}
 
+impl<L: FromOp> FromStr for RecExpr<L> {
+    type Err = RecExprParseError<L::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use RecExprParseError::*;
+
+        let mut parser = SexpParser {
+            input: s,
+            offset: 0,
+        };
         let mut expr = RecExpr::default();
-        let sexp = symbolic_expressions::parser::parse_str(s.trim()).map_err(BadSexp)?;
-        parse_sexp_into(&sexp, &mut expr)?;
-        Ok(expr)
+        parser.expr(&mut expr)?;
+
+        parser.skip_whitespace();
+        let start = parser.offset;
+        let rest = parser.rest();
+        match rest.chars().next() {
+            None => Ok(expr),
+            // extra closing parentheses used to be ignored, so keep accepting them
+            Some(')') if rest.chars().all(|c| c == ')' || c.is_whitespace()) => {
+                let pos = parser.pos(start, start + 1);
+                log::warn!("Ignoring unexpected closing parenthesis at {}", pos);
+                Ok(expr)
+            }
+            Some(')') => Err(UnexpectedParen(parser.pos(start, start + 1))),
+            Some(_) => Err(TrailingInput(parser.pos(start, s.len()))),
+        }
     }
 }
 
@@ -729,6 +1052,36 @@ pub trait Analysis<L: Language>: Sized {
     /// `Analysis::merge` when unions are performed.
     #[allow(unused_variables)]
     fn modify(egraph: &mut EGraph<L, Self>, id: Id) {}
+
+    /// Checks the predicate `name` on the data of an e-class, or returns
+    /// `None` if this analysis has no such predicate.
+    ///
+    /// Annotated pattern variables like `?x:<name>` only match e-classes
+    /// for which this returns `Some(true)`.
+    /// [`Rewrite::new`] rejects predicates missing from
+    /// [`predicates`](Analysis::predicates), and searching for a pattern
+    /// with an unknown one panics.
+    ///
+    /// By default there are no predicates.
+    #[allow(unused_variables)]
+    fn predicate(&self, name: &str, data: &Self::Data) -> Option<bool> {
+        None
+    }
+
+    /// Returns the names [`predicate`](Analysis::predicate) knows.
+    ///
+    /// By default there are no predicates.
+    fn predicates() -> Vec<&'static str> {
+        vec![]
+    }
+
+    /// Updates the [`Id`]s stored in `data` after
+    /// [`EGraph::retain_reachable`] renumbered the e-classes.
+    /// `ids` has the new ids of the old ids of the remaining e-classes.
+    ///
+    /// By default this does nothing, which is right for data without ids.
+    #[allow(unused_variables)]
+    fn renumber(&mut self, data: &mut Self::Data, ids: &IdMap) {}
 }
 
 impl<L: Language> Analysis<L> for () {
@@ -739,6 +1092,384 @@ impl<L: Language> Analysis<L> for () {
     }
 }
 
+/// An [`Analysis`] that can be part of a product of analyses.
+///
+/// A pair `(A, B)` of these is an [`Analysis`] whose data is the pair of
+/// their data, and is itself one of these, so pairs can be nested to
+/// combine more than two.
+/// The product merges both parts, setting each [`DidMerge`] flag if
+/// either part does, and calls the [`modify`] hooks of `A` then `B`.
+///
+/// The methods look like those of [`Analysis`], except that the e-graph's
+/// analysis is the whole product, so they also get `part`, which finds
+/// this analysis's data in the data of the product.
+/// That is also why an [`Analysis`] can't be a part as it is: its `make`
+/// and `modify` need an e-graph of its own type, so it has to be ported,
+/// usually by reading its data through `part`.
+///
+/// # Example
+/// ```
+/// use egg::*;
+/// #[derive(Default)]
+/// struct Size;
+/// impl SubAnalysis<SymbolLang> for Size {
+///     type Data = usize;
+///     fn make<N: Analysis<SymbolLang>>(
+///         egraph: &EGraph<SymbolLang, N>,
+///         enode: &SymbolLang,
+///         part: impl Fn(&N::Data) -> &usize,
+///     ) -> usize {
+///         1 + enode.fold(0, |sum, id| sum + part(&egraph[id].data))
+///     }
+///     fn merge(&mut self, to: &mut usize, from: usize) -> DidMerge {
+///         merge_min(to, from)
+///     }
+/// }
+///
+/// #[derive(Default)]
+/// struct Depth;
+/// impl SubAnalysis<SymbolLang> for Depth {
+///     type Data = usize;
+///     fn make<N: Analysis<SymbolLang>>(
+///         egraph: &EGraph<SymbolLang, N>,
+///         enode: &SymbolLang,
+///         part: impl Fn(&N::Data) -> &usize,
+///     ) -> usize {
+///         1 + enode.fold(0, |max, id| max.max(*part(&egraph[id].data)))
+///     }
+///     fn merge(&mut self, to: &mut usize, from: usize) -> DidMerge {
+///         merge_min(to, from)
+///     }
+/// }
+///
+/// let mut egraph = EGraph::<SymbolLang, (Size, Depth)>::default();
+/// let id = egraph.add_expr(&"(f (g x) y)".parse().unwrap());
+/// assert_eq!(egraph[id].data, (4, 3));
+/// ```
+///
+/// [`modify`]: Analysis::modify
+pub trait SubAnalysis<L: Language>: Sized {
+    /// The per-[`EClass`] data for this analysis.
+    type Data: Debug;
+
+    /// Makes the data for a new enode, like [`Analysis::make`].
+    fn make<N: Analysis<L>>(
+        egraph: &EGraph<L, N>,
+        enode: &L,
+        part: impl Fn(&N::Data) -> &Self::Data,
+    ) -> Self::Data;
+
+    /// Merges two `Data`s, like [`Analysis::merge`].
+    fn merge(&mut self, a: &mut Self::Data, b: Self::Data) -> DidMerge;
+
+    /// Modifies the e-graph after a merge, like [`Analysis::modify`].
+    ///
+    /// By default this does nothing.
+    #[allow(unused_variables)]
+    fn modify<N: Analysis<L>>(
+        egraph: &mut EGraph<L, N>,
+        id: Id,
+        part: impl Fn(&N::Data) -> &Self::Data,
+    ) {
+    }
+
+    /// Checks a predicate, like [`Analysis::predicate`].
+    ///
+    /// By default there are no predicates.
+    #[allow(unused_variables)]
+    fn predicate(&self, name: &str, data: &Self::Data) -> Option<bool> {
+        None
+    }
+
+    /// Returns the names `predicate` knows, like [`Analysis::predicates`].
+    ///
+    /// By default there are no predicates.
+    fn predicates() -> Vec<&'static str> {
+        vec![]
+    }
+
+    /// Updates the [`Id`]s stored in `data`, like [`Analysis::renumber`].
+    ///
+    /// By default this does nothing.
+    #[allow(unused_variables)]
+    fn renumber(&mut self, data: &mut Self::Data, ids: &IdMap) {}
+}
+
+impl<L: Language> SubAnalysis<L> for () {
+    type Data = ();
+    fn make<N: Analysis<L>>(_: &EGraph<L, N>, _: &L, _: impl Fn(&N::Data) -> &()) {}
+    fn merge(&mut self, _: &mut Self::Data, _: Self::Data) -> DidMerge {
+        DidMerge(false, false)
+    }
+}
+
+impl<L, A, B> SubAnalysis<L> for (A, B)
+where
+    L: Language,
+    A: SubAnalysis<L>,
+    B: SubAnalysis<L>,
+    // for the parts of the data borrowed by the `part` closures
+    A::Data: 'static,
+    B::Data: 'static,
+{
+    type Data = (A::Data, B::Data);
+
+    fn make<N: Analysis<L>>(
+        egraph: &EGraph<L, N>,
+        enode: &L,
+        part: impl Fn(&N::Data) -> &Self::Data,
+    ) -> Self::Data {
+        let a = A::make(egraph, enode, |data| &part(data).0);
+        let b = B::make(egraph, enode, |data| &part(data).1);
+        (a, b)
+    }
+
+    fn merge(&mut self, a: &mut Self::Data, b: Self::Data) -> DidMerge {
+        self.0.merge(&mut a.0, b.0) | self.1.merge(&mut a.1, b.1)
+    }
+
+    fn modify<N: Analysis<L>>(
+        egraph: &mut EGraph<L, N>,
+        id: Id,
+        part: impl Fn(&N::Data) -> &Self::Data,
+    ) {
+        A::modify(egraph, id, |data| &part(data).0);
+        B::modify(egraph, id, |data| &part(data).1);
+    }
+
+    fn predicate(&self, name: &str, data: &Self::Data) -> Option<bool> {
+        (self.0.predicate(name, &data.0)).or_else(|| self.1.predicate(name, &data.1))
+    }
+
+    fn predicates() -> Vec<&'static str> {
+        let mut names = A::predicates();
+        names.extend(B::predicates());
+        names
+    }
+
+    fn renumber(&mut self, data: &mut Self::Data, ids: &IdMap) {
+        self.0.renumber(&mut data.0, ids);
+        self.1.renumber(&mut data.1, ids);
+    }
+}
+
+impl<L, A, B> Analysis<L> for (A, B)
+where
+    L: Language,
+    A: SubAnalysis<L>,
+    B: SubAnalysis<L>,
+    // for the parts of the data borrowed by the `part` closures
+    A::Data: 'static,
+    B::Data: 'static,
+{
+    type Data = (A::Data, B::Data);
+
+    fn make(egraph: &EGraph<L, Self>, enode: &L) -> Self::Data {
+        <Self as SubAnalysis<L>>::make(egraph, enode, |data| data)
+    }
+
+    fn merge(&mut self, a: &mut Self::Data, b: Self::Data) -> DidMerge {
+        <Self as SubAnalysis<L>>::merge(self, a, b)
+    }
+
+    fn modify(egraph: &mut EGraph<L, Self>, id: Id) {
+        <Self as SubAnalysis<L>>::modify(egraph, id, |data| data)
+    }
+
+    fn predicate(&self, name: &str, data: &Self::Data) -> Option<bool> {
+        <Self as SubAnalysis<L>>::predicate(self, name, data)
+    }
+
+    fn predicates() -> Vec<&'static str> {
+        <Self as SubAnalysis<L>>::predicates()
+    }
+
+    fn renumber(&mut self, data: &mut Self::Data, ids: &IdMap) {
+        <Self as SubAnalysis<L>>::renumber(self, data, ids)
+    }
+}
+
+/// A join-semilattice, whose join can be used as [`Analysis::merge`].
+pub trait Lattice {
+    /// Joins `other` into `self`, returning a [`DidMerge`] like
+    /// [`Analysis::merge`] does.
+    fn join(&mut self, other: Self) -> DidMerge;
+}
+
+impl Lattice for () {
+    fn join(&mut self, _: Self) -> DidMerge {
+        DidMerge(false, false)
+    }
+}
+
+/// `false` is below `true`.
+impl Lattice for bool {
+    fn join(&mut self, other: Self) -> DidMerge {
+        let did = DidMerge(!*self && other, *self && !other);
+        *self |= other;
+        did
+    }
+}
+
+/// `None` is below everything else.
+impl<T: Lattice> Lattice for Option<T> {
+    fn join(&mut self, other: Self) -> DidMerge {
+        merge_option(self, other, T::join)
+    }
+}
+
+/// Joins each side separately.
+impl<A: Lattice, B: Lattice> Lattice for (A, B) {
+    fn join(&mut self, other: Self) -> DidMerge {
+        self.0.join(other.0) | self.1.join(other.1)
+    }
+}
+
+/// An analysis whose data is a [`Lattice`] made from an enode and the data
+/// of its children, so it merges by [`join`](Lattice::join).
+///
+/// Wrapping one in [`Join`] makes it an [`Analysis`] on its own, and a
+/// [`SubAnalysis`] that can be part of a product.
+///
+/// # Example
+/// ```
+/// use egg::*;
+/// #[derive(Default)]
+/// struct HasX;
+/// impl LatticeAnalysis<SymbolLang> for HasX {
+///     type Data = bool;
+///     fn make<'a>(enode: &SymbolLang, child: impl Fn(Id) -> &'a bool) -> bool {
+///         enode.op.as_str() == "x" || enode.any(|c| *child(c))
+///     }
+/// }
+///
+/// let mut egraph = EGraph::<SymbolLang, Join<HasX>>::default();
+/// let fx = egraph.add_expr(&"(f x)".parse().unwrap());
+/// let y = egraph.add_expr(&"y".parse().unwrap());
+/// assert!(!egraph[y].data);
+/// egraph.union(fx, y);
+/// egraph.rebuild();
+/// assert!(egraph[y].data);
+/// ```
+pub trait LatticeAnalysis<L: Language> {
+    /// The per-[`EClass`] data for this analysis.
+    type Data: Lattice + Debug;
+
+    /// Makes the data for a new enode from the data of its children,
+    /// like [`Analysis::make`].
+    fn make<'a>(enode: &L, child: impl Fn(Id) -> &'a Self::Data) -> Self::Data
+    where
+        Self::Data: 'a;
+
+    /// Checks a predicate, like [`Analysis::predicate`].
+    ///
+    /// By default there are no predicates.
+    #[allow(unused_variables)]
+    fn predicate(&self, name: &str, data: &Self::Data) -> Option<bool> {
+        None
+    }
+
+    /// Returns the names `predicate` knows, like [`Analysis::predicates`].
+    ///
+    /// By default there are no predicates.
+    fn predicates() -> Vec<&'static str> {
+        vec![]
+    }
+}
+
+/// Makes a [`LatticeAnalysis`] into an [`Analysis`] and a [`SubAnalysis`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Join<A>(pub A);
+
+impl<L: Language, A: LatticeAnalysis<L>> SubAnalysis<L> for Join<A> {
+    type Data = A::Data;
+
+    fn make<N: Analysis<L>>(
+        egraph: &EGraph<L, N>,
+        enode: &L,
+        part: impl Fn(&N::Data) -> &Self::Data,
+    ) -> Self::Data {
+        A::make(enode, |id| part(&egraph[id].data))
+    }
+
+    fn merge(&mut self, a: &mut Self::Data, b: Self::Data) -> DidMerge {
+        a.join(b)
+    }
+
+    fn predicate(&self, name: &str, data: &Self::Data) -> Option<bool> {
+        self.0.predicate(name, data)
+    }
+
+    fn predicates() -> Vec<&'static str> {
+        A::predicates()
+    }
+}
+
+impl<L: Language, A: LatticeAnalysis<L>> Analysis<L> for Join<A> {
+    type Data = A::Data;
+
+    fn make(egraph: &EGraph<L, Self>, enode: &L) -> Self::Data {
+        A::make(enode, |id| &egraph[id].data)
+    }
+
+    fn merge(&mut self, a: &mut Self::Data, b: Self::Data) -> DidMerge {
+        a.join(b)
+    }
+
+    fn predicate(&self, name: &str, data: &Self::Data) -> Option<bool> {
+        self.0.predicate(name, data)
+    }
+
+    fn predicates() -> Vec<&'static str> {
+        A::predicates()
+    }
+}
+
+/// A utility for implementing [`Analysis::merge`]
+/// when the `Data` type is an [`Option`].
+/// `None` is replaced by anything, and two values are merged with `merge`.
+pub fn merge_option<T>(
+    to: &mut Option<T>,
+    from: Option<T>,
+    merge: impl FnOnce(&mut T, T) -> DidMerge,
+) -> DidMerge {
+    match (to.as_mut(), from) {
+        (None, None) => DidMerge(false, false),
+        (None, from @ Some(_)) => {
+            *to = from;
+            DidMerge(true, false)
+        }
+        (Some(_), None) => DidMerge(false, true),
+        (Some(a), Some(b)) => merge(a, b),
+    }
+}
+
+/// A utility for implementing [`Analysis::merge`]
+/// when the `Data` type is a set.
+/// This will take the union of the two sets.
+pub fn merge_union<T: Eq + Hash, S: BuildHasher>(
+    to: &mut HashSet<T, S>,
+    from: HashSet<T, S>,
+) -> DidMerge {
+    let (to_len, from_len) = (to.len(), from.len());
+    to.extend(from);
+    // `from` is a subset of the union, so it's the same if it's as big
+    DidMerge(to.len() != to_len, to.len() != from_len)
+}
+
+/// A utility for implementing [`Analysis::merge`]
+/// when the `Data` type is a set.
+/// This will take the intersection of the two sets.
+pub fn merge_intersection<T: Eq + Hash, S: BuildHasher>(
+    to: &mut HashSet<T, S>,
+    from: HashSet<T, S>,
+) -> DidMerge {
+    let to_len = to.len();
+    to.retain(|x| from.contains(x));
+    // the intersection is a subset of `from`, so it's the same if it's as big
+    DidMerge(to.len() != to_len, to.len() != from.len())
+}
+
 /// A utility for implementing [`Analysis::merge`]
 /// when the `Data` type has a total ordering.
 /// This will take the maximum of the two values.
@@ -803,6 +1534,10 @@ impl Language for SymbolLang {
     fn children_mut(&mut self) -> &mut [Id] {
         &mut self.children
     }
+
+    fn rebuild_with(&self, children: Vec<Id>) -> Option<Self> {
+        Some(Self::new(self.op, children))
+    }
 }
 
 impl Display for SymbolLang {
@@ -821,3 +1556,257 @@ impl FromOp for SymbolLang {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    define_language! {
+        enum Calc {
+            "+" = Add([Id; 2]),
+            Num(i32),
+        }
+    }
+
+    fn error<L: FromOp>(s: &str) -> (usize, usize, String)
+    where
+        L::Error: std::fmt::Display,
+    {
+        let err = s.parse::<RecExpr<L>>().unwrap_err();
+        let pos = err.pos();
+        (pos.line, pos.column, err.to_string())
+    }
+
+    #[test]
+    fn parse_error_positions() {
+        let (line, column, msg) = error::<Calc>("(+ 1\n\t(+ 2 3 4))");
+        assert_eq!((line, column), (2, 2));
+        assert!(msg.ends_with("line 2, column 2:\n  \t(+ 2 3 4))\n  \t^^^^^^^^^"));
+
+        // the carets stop at the end of the line
+        let (line, column, msg) = error::<Calc>("(+ 1 2\n 3)");
+        assert_eq!((line, column), (1, 1));
+        assert!(msg.ends_with("  (+ 1 2\n  ^^^^^^"));
+
+        let cases = [
+            ("", 1, "found empty s-expression"),
+            ("(f (g a)", 1, "unclosed parenthesis"),
+            ("(f (g a))) b", 10, "unexpected closing parenthesis"),
+            (")", 1, "unexpected closing parenthesis"),
+            ("(f a) (g b)", 7, "unexpected input after the s-expression"),
+            ("(f ((g) a))", 5, "found a list in the head position"),
+            ("(f ( ) a)", 4, "found empty s-expression"),
+        ];
+        for (s, column, start) in cases {
+            let (line, col, msg) = error::<SymbolLang>(s);
+            assert_eq!((line, col), (1, column), "{}", s);
+            assert!(msg.starts_with(start), "{}", msg);
+        }
+
+        // extra closing parentheses at the end are only warned about
+        let e: RecExpr<SymbolLang> = "(f (g a))) )\n".parse().unwrap();
+        assert_eq!(e, "(f (g a))".parse().unwrap());
+    }
+
+    #[test]
+    fn parse_quoted() {
+        let e: RecExpr<SymbolLang> = r#"(f "a (b" "c \" d")"#.parse().unwrap();
+        let ops: Vec<String> = e.as_ref().iter().map(|n| n.op.to_string()).collect();
+        assert_eq!(ops, vec![r#""a (b""#, r#""c \" d""#, "f"]);
+    }
+
+    struct Fold;
+    impl SubAnalysis<SymbolLang> for Fold {
+        type Data = Option<i32>;
+        fn make<N: Analysis<SymbolLang>>(
+            egraph: &EGraph<SymbolLang, N>,
+            enode: &SymbolLang,
+            part: impl Fn(&N::Data) -> &Self::Data,
+        ) -> Self::Data {
+            let c = |i: &Id| *part(&egraph[*i].data);
+            match (enode.op.as_str(), enode.children.as_slice()) {
+                ("+", [a, b]) => Some(c(a)? + c(b)?),
+                (op, []) => op.parse().ok(),
+                _ => None,
+            }
+        }
+        fn merge(&mut self, to: &mut Self::Data, from: Self::Data) -> DidMerge {
+            merge_option(to, from, merge_max)
+        }
+        fn modify<N: Analysis<SymbolLang>>(
+            egraph: &mut EGraph<SymbolLang, N>,
+            id: Id,
+            part: impl Fn(&N::Data) -> &Self::Data,
+        ) {
+            if let Some(n) = *part(&egraph[id].data) {
+                let folded = egraph.add(SymbolLang::leaf(n.to_string()));
+                egraph.union(id, folded);
+            }
+        }
+        fn predicate(&self, name: &str, data: &Self::Data) -> Option<bool> {
+            Some(data.is_some()).filter(|_| name == "const")
+        }
+        fn predicates() -> Vec<&'static str> {
+            vec!["const"]
+        }
+    }
+
+    struct Ops;
+    impl SubAnalysis<SymbolLang> for Ops {
+        type Data = std::collections::HashSet<Symbol>;
+        fn make<N: Analysis<SymbolLang>>(
+            _: &EGraph<SymbolLang, N>,
+            enode: &SymbolLang,
+            _: impl Fn(&N::Data) -> &Self::Data,
+        ) -> Self::Data {
+            std::iter::once(enode.op).collect()
+        }
+        fn merge(&mut self, to: &mut Self::Data, from: Self::Data) -> DidMerge {
+            merge_union(to, from)
+        }
+        fn predicate(&self, name: &str, data: &Self::Data) -> Option<bool> {
+            Some(data.len() > 1).filter(|_| name == "merged")
+        }
+        fn predicates() -> Vec<&'static str> {
+            vec!["merged"]
+        }
+    }
+
+    #[test]
+    fn product_analysis() {
+        let mut egraph = EGraph::new((Fold, (Ops, ())));
+        let sum = egraph.add_expr(&"(+ 1 2)".parse().unwrap());
+        let x = egraph.add(SymbolLang::leaf("x"));
+        egraph.rebuild();
+        // the modify of the nested part was called through the product
+        let three = egraph.lookup(SymbolLang::leaf("3")).unwrap();
+        assert_eq!(egraph.find(three), egraph.find(sum));
+        let (n, (ops, ())) = &egraph[sum].data;
+        assert_eq!(*n, Some(3));
+        let mut ops: Vec<_> = ops.iter().map(|op| op.as_str()).collect();
+        ops.sort_unstable();
+        assert_eq!(ops, ["+", "3"]);
+
+        let analysis = &egraph.analysis;
+        let data = &egraph[sum].data;
+        assert_eq!(Analysis::predicate(analysis, "const", data), Some(true));
+        assert_eq!(Analysis::predicate(analysis, "merged", data), Some(true));
+        assert_eq!(Analysis::predicate(analysis, "other", data), None);
+        assert_eq!(
+            Analysis::predicate(analysis, "const", &egraph[x].data),
+            Some(false)
+        );
+
+        egraph.union(x, sum);
+        egraph.rebuild();
+        let (n, (ops, ())) = &egraph[x].data;
+        assert_eq!((*n, ops.len()), (Some(3), 3));
+    }
+
+    #[test]
+    fn product_did_merge() {
+        let mut analysis = (Fold, (Ops, ()));
+        let ops = |ops: &[&str]| ops.iter().map(|&op| Symbol::from(op)).collect();
+        let mut merge = |to: Option<i32>, to_ops, from: Option<i32>, from_ops| {
+            let mut to = (to, (ops(to_ops), ()));
+            Analysis::merge(&mut analysis, &mut to, (from, (ops(from_ops), ())))
+        };
+        // each flag is set if either part sets it
+        let did = |did: DidMerge| (did.0, did.1);
+        assert_eq!(did(merge(None, &["+"], Some(3), &["+"])), (true, false));
+        assert_eq!(did(merge(Some(3), &["+"], None, &["+"])), (false, true));
+        assert_eq!(did(merge(Some(3), &["+"], Some(3), &["+"])), (false, false));
+        assert_eq!(did(merge(None, &["+"], Some(3), &[])), (true, true));
+    }
+
+    // adds the leaf `C` in modify, to show in which order the parts run
+    struct Mark<const C: char>;
+    impl<const C: char> SubAnalysis<SymbolLang> for Mark<C> {
+        type Data = ();
+        fn make<N: Analysis<SymbolLang>>(
+            _: &EGraph<SymbolLang, N>,
+            _: &SymbolLang,
+            _: impl Fn(&N::Data) -> &(),
+        ) {
+        }
+        fn merge(&mut self, _: &mut (), _: ()) -> DidMerge {
+            DidMerge(false, false)
+        }
+        fn modify<N: Analysis<SymbolLang>>(
+            egraph: &mut EGraph<SymbolLang, N>,
+            _: Id,
+            _: impl Fn(&N::Data) -> &(),
+        ) {
+            egraph.add(SymbolLang::leaf(C.to_string()));
+        }
+    }
+
+    #[test]
+    fn product_modify_order() {
+        // the first part's modify runs first
+        let mut egraph = EGraph::new((Mark::<'a'>, Mark::<'b'>));
+        egraph.add(SymbolLang::leaf("x"));
+        let a = egraph.lookup(SymbolLang::leaf("a")).unwrap();
+        let b = egraph.lookup(SymbolLang::leaf("b")).unwrap();
+        assert!(a < b);
+    }
+
+    struct HasX;
+    impl LatticeAnalysis<SymbolLang> for HasX {
+        type Data = bool;
+        fn make<'a>(enode: &SymbolLang, child: impl Fn(Id) -> &'a bool) -> bool {
+            enode.op.as_str() == "x" || enode.any(|c| *child(c))
+        }
+        fn predicate(&self, name: &str, data: &bool) -> Option<bool> {
+            Some(*data).filter(|_| name == "has_x")
+        }
+        fn predicates() -> Vec<&'static str> {
+            vec!["has_x"]
+        }
+    }
+
+    #[test]
+    fn lattice_analysis() {
+        let mut egraph = EGraph::new((Join(HasX), (Fold, ())));
+        let fx = egraph.add_expr(&"(f x)".parse().unwrap());
+        let sum = egraph.add_expr(&"(+ 1 2)".parse().unwrap());
+        egraph.rebuild();
+        assert!(egraph[fx].data.0);
+        assert_eq!(egraph[sum].data, (false, (Some(3), ())));
+
+        egraph.union(fx, sum);
+        egraph.rebuild();
+        assert_eq!(egraph[sum].data, (true, (Some(3), ())));
+        let analysis = &egraph.analysis;
+        let data = &egraph[sum].data;
+        assert_eq!(Analysis::predicate(analysis, "has_x", data), Some(true));
+        assert_eq!(Analysis::predicate(analysis, "const", data), Some(true));
+        let names = <(Join<HasX>, (Fold, ())) as Analysis<_>>::predicates();
+        assert_eq!(names, ["has_x", "const"]);
+    }
+
+    #[test]
+    fn lattices() {
+        let (mut a, b) = ((Some(true), false), (None, true));
+        let did = a.join(b);
+        assert_eq!((did.0, did.1), (true, true));
+        assert_eq!(a, (Some(true), true));
+        let did = a.join((Some(false), true));
+        assert_eq!((did.0, did.1), (false, true));
+
+        let mut none = None;
+        let did = merge_option(&mut none, Some(1), merge_max);
+        assert_eq!((did.0, did.1, none), (true, false, Some(1)));
+
+        let set = |xs: &[i32]| xs.iter().copied().collect::<std::collections::HashSet<_>>();
+        let mut s = set(&[1, 2]);
+        let did = merge_intersection(&mut s, set(&[1, 2, 3]));
+        assert_eq!((did.0, did.1), (false, true));
+        let did = merge_intersection(&mut s, set(&[2]));
+        assert_eq!((did.0, did.1, s.clone()), (true, false, set(&[2])));
+        let did = merge_union(&mut s, set(&[2]));
+        assert_eq!((did.0, did.1), (false, false));
+        let did = merge_union(&mut s, set(&[3]));
+        assert_eq!((did.0, did.1, s), (true, true, set(&[2, 3])));
+    }
+}
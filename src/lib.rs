@@ -39,12 +39,14 @@ mod egraph;
 mod explain;
 mod extract;
 mod language;
+mod lint;
 #[cfg(feature = "lp")]
 mod lp_extract;
 mod machine;
 mod multipattern;
 mod pattern;
 mod rewrite;
+mod rule_file;
 mod run;
 mod run_beam;
 mod run_bfs_default;
@@ -52,8 +54,13 @@ mod subst;
 mod unionfind;
 mod util;
 pub mod udp_analysis;
+pub mod udp_corpus;
+pub mod udp_eval;
 pub mod udp_lang;
+pub mod udp_print;
 pub mod udp_rewrites;
+pub mod udp_spnf;
+pub mod udp_sql;
 
 /// A key to identify [`EClass`]es within an
 /// [`EGraph`].
@@ -91,13 +98,18 @@ pub(crate) use {explain::Explain, explain::Justification, unionfind::UnionFind};
 pub use {
     dot::Dot,
     eclass::EClass,
-    egraph::EGraph,
+    egraph::{AnalysisError, ContextId, EGraph, EGraphStats, IdMap},
     explain::{Explanation, FlatExplanation, FlatTerm, TreeExplanation, TreeTerm},
     extract::*,
     language::*,
+    lint::{lint_rules, LintKind, RuleLint},
     multipattern::*,
-    pattern::{ENodeOrVar, Pattern, PatternAst, SearchMatches},
-    rewrite::{Applier, Condition, ConditionEqual, ConditionalApplier, Rewrite, Searcher},
+    pattern::{Annotation, ENodeOrVar, Pattern, PatternAst, SearchMatches},
+    rewrite::{
+        Applier, Condition, ConditionEqual, ConditionMatches, ConditionNotMatches,
+        ConditionalApplier, Rewrite, Searcher,
+    },
+    rule_file::{RuleArg, RuleFileError, RuleRegistry},
     run::*,
     subst::{Subst, Var},
     util::*,
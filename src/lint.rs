@@ -0,0 +1,327 @@
+use std::fmt::{self, Display};
+
+use crate::*;
+
+/// A problem with a rule, found by [`lint_rules`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleLint {
+    /// The name of the rule
+    pub rule: Symbol,
+    /// What is wrong with it
+    pub kind: LintKind,
+}
+
+/// The kinds of problems [`lint_rules`] looks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintKind {
+    /// The rule rewrites its left-hand side to itself.
+    Identity,
+    /// The left-hand side is a (maybe annotated) variable, so the rule
+    /// matches every e-class.
+    BareVariable,
+    /// The right-hand side or a condition uses a variable that the
+    /// left-hand side doesn't bind.
+    UnboundVar(Var),
+    /// Every application of the rule is also one of the more general,
+    /// unconditional rule `by`.
+    Subsumed {
+        /// The more general rule
+        by: Symbol,
+    },
+    /// The rule is the same as the earlier rule `of`, up to renaming
+    /// variables, like the two directions of `(+ ?a ?b) <=> (+ ?b ?a)`.
+    Duplicate {
+        /// The earlier rule
+        of: Symbol,
+    },
+}
+
+impl Display for RuleLint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rule {} ", self.rule)?;
+        match &self.kind {
+            LintKind::Identity => write!(f, "rewrites its left-hand side to itself"),
+            LintKind::BareVariable => write!(f, "matches every e-class"),
+            LintKind::UnboundVar(v) => {
+                write!(f, "uses {}, which its left-hand side doesn't bind", v)
+            }
+            LintKind::Subsumed { by } => write!(f, "is subsumed by the more general rule {}", by),
+            LintKind::Duplicate { of } => write!(f, "duplicates rule {}", of),
+        }
+    }
+}
+
+fn root<L>(ast: &PatternAst<L>) -> Id {
+    Id::from(ast.as_ref().len() - 1)
+}
+
+fn same_term<L: Language>(a: &PatternAst<L>, i: Id, b: &PatternAst<L>, j: Id) -> bool {
+    match (&a[i], &b[j]) {
+        (ENodeOrVar::Var(x), ENodeOrVar::Var(y)) => x == y,
+        (ENodeOrVar::ENode(m), ENodeOrVar::ENode(n)) => {
+            m.matches(n)
+                && (m.children().iter())
+                    .zip(n.children())
+                    .all(|(&c, &d)| same_term(a, c, b, d))
+        }
+        _ => false,
+    }
+}
+
+// binds the variables of general so that it is the same as specific
+fn instance_of<L: Language>(
+    general: &PatternAst<L>,
+    g: Id,
+    specific: &PatternAst<L>,
+    s: Id,
+    subst: &mut HashMap<Var, Id>,
+) -> bool {
+    match &general[g] {
+        ENodeOrVar::Var(v) => match subst.get(v) {
+            Some(&bound) => same_term(specific, bound, specific, s),
+            None => {
+                subst.insert(*v, s);
+                true
+            }
+        },
+        ENodeOrVar::ENode(m) => match &specific[s] {
+            ENodeOrVar::ENode(n) if m.matches(n) => (m.children().iter())
+                .zip(n.children())
+                .all(|(&c, &d)| instance_of(general, c, specific, d, subst)),
+            _ => false,
+        },
+    }
+}
+
+// whether general instantiated with subst (into lhs) is the same as specific
+fn same_instance<L: Language>(
+    general: &PatternAst<L>,
+    g: Id,
+    subst: &HashMap<Var, Id>,
+    lhs: &PatternAst<L>,
+    specific: &PatternAst<L>,
+    s: Id,
+) -> bool {
+    match &general[g] {
+        ENodeOrVar::Var(v) => match subst.get(v) {
+            Some(&bound) => same_term(lhs, bound, specific, s),
+            None => false,
+        },
+        ENodeOrVar::ENode(m) => match &specific[s] {
+            ENodeOrVar::ENode(n) if m.matches(n) => (m.children().iter())
+                .zip(n.children())
+                .all(|(&c, &d)| same_instance(general, c, subst, lhs, specific, d)),
+            _ => false,
+        },
+    }
+}
+
+// what the linter can see of a rule
+struct Rule<'a, L> {
+    name: Symbol,
+    lhs: &'a PatternAst<L>,
+    rhs: &'a PatternAst<L>,
+    annotations: &'a [(Var, Annotation)],
+    conditional: bool,
+}
+
+impl<'a, L: Language> Rule<'a, L> {
+    // whether every application of other is also one of self
+    fn subsumes(&self, other: &Rule<'a, L>) -> bool {
+        let has_seqs = |ast: &PatternAst<L>| {
+            (ast.as_ref().iter()).any(|n| matches!(n, ENodeOrVar::Var(v) if v.is_sequence()))
+        };
+        if self.conditional || has_seqs(self.lhs) || has_seqs(other.lhs) {
+            return false;
+        }
+        let (lhs, rhs) = (other.lhs, other.rhs);
+        let mut subst = HashMap::default();
+        if !instance_of(self.lhs, root(self.lhs), lhs, root(lhs), &mut subst) {
+            return false;
+        }
+        // an annotated variable only matches variables with the same annotation
+        let annotated = |(v, ann): &(Var, Annotation)| match &lhs[subst[v]] {
+            ENodeOrVar::Var(w) => other.annotations.contains(&(*w, *ann)),
+            ENodeOrVar::ENode(_) => false,
+        };
+        self.annotations.iter().all(annotated)
+            && same_instance(self.rhs, root(self.rhs), &subst, lhs, rhs, root(rhs))
+    }
+}
+
+/** Looks for problems in a rule set.
+
+This reports
+- rules that rewrite a term to itself,
+- rules whose left-hand side is a bare variable, which match every e-class,
+- rules whose right-hand side or conditions use variables that their
+  left-hand side doesn't bind,
+- rules that are subsumed by a more general unconditional rule,
+- and rules that duplicate an earlier rule, up to renaming variables.
+
+Only rules whose sides are [`Pattern`]s, maybe with conditions, are
+checked for the first two and last two of these.
+The [`Runner`] logs these as warnings when it starts.
+
+```
+use egg::{*, SymbolLang as S};
+let rules: Vec<Rewrite<S, ()>> = vec![
+    rewrite!("comm"; "(+ ?a ?b)" => "(+ ?b ?a)"),
+    rewrite!("comm-0"; "(+ ?a 0)" => "(+ 0 ?a)"),
+    rewrite!("same"; "(+ ?a ?b)" => "(+ ?a ?b)"),
+    rewrite!("add-0"; "?a" => "(+ ?a 0)"),
+];
+let lints: Vec<String> = lint_rules(&rules).iter().map(|l| l.to_string()).collect();
+assert_eq!(lints, vec![
+    "rule comm-0 is subsumed by the more general rule comm",
+    "rule same rewrites its left-hand side to itself",
+    "rule add-0 matches every e-class",
+]);
+```
+**/
+pub fn lint_rules<'a, L, N, R>(rules: R) -> Vec<RuleLint>
+where
+    L: Language + 'a,
+    N: Analysis<L> + 'a,
+    R: IntoIterator<Item = &'a Rewrite<L, N>>,
+{
+    let rules: Vec<&Rewrite<L, N>> = rules.into_iter().collect();
+    let patterns: Vec<Option<Rule<L>>> = rules
+        .iter()
+        .map(|rw| {
+            Some(Rule {
+                name: rw.name,
+                lhs: rw.searcher.get_pattern_ast()?,
+                rhs: rw.applier.get_pattern_ast()?,
+                annotations: rw.searcher.get_annotations(),
+                conditional: rw.applier.is_conditional(),
+            })
+        })
+        .collect();
+
+    let mut lints = vec![];
+    let mut lint = |rule: Symbol, kind| lints.push(RuleLint { rule, kind });
+    for (i, rw) in rules.iter().enumerate() {
+        let bound = rw.searcher.vars();
+        let mut used = rw.applier.vars();
+        if let Some(rule) = &patterns[i] {
+            used.extend(rule.rhs.as_ref().iter().filter_map(|n| match n {
+                ENodeOrVar::Var(v) => Some(*v),
+                ENodeOrVar::ENode(_) => None,
+            }));
+        }
+        let mut unbound = vec![];
+        for v in used {
+            if !bound.contains(&v) && !unbound.contains(&v) {
+                unbound.push(v);
+                lint(rw.name, LintKind::UnboundVar(v));
+            }
+        }
+
+        let rule = match &patterns[i] {
+            Some(rule) => rule,
+            None => continue,
+        };
+        if same_term(rule.lhs, root(rule.lhs), rule.rhs, root(rule.rhs)) {
+            lint(rule.name, LintKind::Identity);
+        }
+        if let ENodeOrVar::Var(_) = rule.lhs[root(rule.lhs)] {
+            lint(rule.name, LintKind::BareVariable);
+        }
+
+        let others = patterns.iter().enumerate().filter(|(j, _)| *j != i);
+        let others = others.filter_map(|(j, other)| Some((j, other.as_ref()?)));
+        let mut subsumed_by = None;
+        for (j, other) in others {
+            if other.subsumes(rule) {
+                if j < i && rule.subsumes(other) {
+                    subsumed_by = Some(LintKind::Duplicate { of: other.name });
+                    break;
+                } else if !rule.subsumes(other) && subsumed_by.is_none() {
+                    subsumed_by = Some(LintKind::Subsumed { by: other.name });
+                }
+            }
+        }
+        if let Some(kind) = subsumed_by {
+            lint(rule.name, kind);
+        }
+    }
+    lints
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{SymbolLang as S, *};
+
+    fn lints<N: Analysis<S>>(rules: &[Rewrite<S, N>]) -> Vec<(String, LintKind)> {
+        let lints = lint_rules(rules).into_iter();
+        lints.map(|l| (l.rule.to_string(), l.kind)).collect()
+    }
+
+    #[test]
+    fn lint_identity_and_bare() {
+        let rules: Vec<Rewrite<S, ()>> = vec![
+            rewrite!("id"; "(f ?a ?b)" => "(f ?a ?b)"),
+            rewrite!("bare"; "?a" => "(g ?a)"),
+            rewrite!("fine"; "(f ?a ?b)" => "(f ?b ?a)"),
+        ];
+        assert_eq!(
+            lints(&rules),
+            vec![
+                ("id".into(), LintKind::Identity),
+                ("bare".into(), LintKind::BareVariable),
+            ]
+        );
+    }
+
+    #[test]
+    fn lint_unbound() {
+        let mut rule: Rewrite<S, ()> = rewrite!("r"; "(f ?a)" => "?a");
+        let rhs: Pattern<S> = "(g ?a ?b)".parse().unwrap();
+        rule.applier = std::sync::Arc::new(rhs);
+        let b = "?b".parse().unwrap();
+        assert_eq!(lints(&[rule]), vec![("r".into(), LintKind::UnboundVar(b))]);
+    }
+
+    // an analysis with a predicate <p>
+    struct P;
+    impl Analysis<S> for P {
+        type Data = ();
+        fn make(_: &EGraph<S, Self>, _: &S) {}
+        fn merge(&mut self, _: &mut (), _: ()) -> DidMerge {
+            DidMerge(false, false)
+        }
+        fn predicates() -> Vec<&'static str> {
+            vec!["p"]
+        }
+    }
+
+    #[test]
+    fn lint_subsumed() {
+        let by = |name: &str| LintKind::Subsumed { by: name.into() };
+        let of = |name: &str| LintKind::Duplicate { of: name.into() };
+        let zero: Pattern<S> = "0".parse().unwrap();
+        let mut rules: Vec<Rewrite<S, P>> = vec![
+            rewrite!("specific"; "(f (g ?x) ?x)" => "(h ?x (g ?x))"),
+            rewrite!("general"; "(f ?a ?b)" => "(h ?b ?a)"),
+            rewrite!("nonlinear"; "(f ?a ?a)" => "(h ?a ?a)"),
+            rewrite!("other-rhs"; "(f ?a 0)" => "(h ?a 0)"),
+            rewrite!("conditional"; "(f ?a ?b)" => "(k ?a)"
+                if ConditionEqual::new(zero.clone(), zero)),
+            rewrite!("under-conditional"; "(f 1 ?b)" => "(k 1)"),
+            rewrite!("annotated"; "(k ?a:<p>)" => "?a"),
+            rewrite!("not-annotated"; "(k 1)" => "1"),
+            rewrite!("annotated-2"; "(k ?b:<p>)" => "?b"),
+        ];
+        rules.extend(rewrite!("comm"; "(+ ?a ?b)" <=> "(+ ?b ?a)"));
+        assert_eq!(
+            lints(&rules),
+            vec![
+                ("specific".into(), by("general")),
+                ("nonlinear".into(), by("general")),
+                ("annotated-2".into(), of("annotated")),
+                ("comm-rev".into(), of("comm")),
+            ]
+        );
+    }
+}
@@ -1,10 +1,43 @@
+use std::fmt;
+use std::sync::Arc;
+
 use crate::*;
 
-#[derive(Default)]
-struct Machine {
+// ways to split an e-class into the arguments of an AC operator
+type AcSplits = Arc<[Vec<Id>]>;
+
+pub(crate) struct Machine<L> {
     reg: Vec<Id>,
     // a buffer to re-use for lookups
     lookup: Vec<Id>,
+    // memoized AC flattenings and sums, keyed by operator
+    flattenings: HashMap<(L, Id), Vec<Vec<Id>>>,
+    sums: HashMap<(L, Vec<Id>), Option<Id>>,
+    ac_matches: HashMap<(L, Id, usize), AcSplits>,
+    // the children bound to sequence variables, like registers
+    seqs: Vec<Vec<Id>>,
+    // whether an AC flattening has been cut off, so it's only logged once
+    ac_cut_off: bool,
+}
+
+impl<L> Default for Machine<L> {
+    fn default() -> Self {
+        Self {
+            reg: Default::default(),
+            lookup: Default::default(),
+            flattenings: Default::default(),
+            sums: Default::default(),
+            ac_matches: Default::default(),
+            seqs: Default::default(),
+            ac_cut_off: false,
+        }
+    }
+}
+
+impl<L> fmt::Debug for Machine<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Machine").finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -14,16 +47,32 @@ struct Reg(u32);
 pub struct Program<L> {
     instructions: Vec<Instruction<L>>,
     subst: Subst,
+    seqs: Vec<(Var, usize)>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum Instruction<L> {
     Bind { node: L, i: Reg, out: Reg },
+    // match an AC operator against i, putting n summands in out..
+    BindAc { node: L, i: Reg, out: Reg, n: usize },
+    // match node against i, with sequence variables among its children
+    BindSeq { node: L, i: Reg, out: Reg, seq: Seq },
     Compare { i: Reg, j: Reg },
+    CompareSeq { i: usize, j: usize },
+    // check the annotation of a pattern variable bound to i
+    Check { i: Reg, ann: Annotation },
     Lookup { term: Vec<ENodeOrReg<L>>, i: Reg },
     Scan { out: Reg },
 }
 
+// the other children go in out.., the sequences in seqs first..
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Seq {
+    first: usize,
+    // which children are sequence variables
+    args: Vec<bool>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum ENodeOrReg<L> {
     ENode(L),
@@ -72,20 +121,19 @@ where
     }
 }
 
-impl Machine {
+impl<L: Language> Machine<L> {
     #[inline(always)]
     fn reg(&self, reg: Reg) -> Id {
         self.reg[reg.0 as usize]
     }
 
-    fn run<L, N>(
+    fn run<N>(
         &mut self,
         egraph: &EGraph<L, N>,
         instructions: &[Instruction<L>],
         subst: &Subst,
         yield_fn: &mut impl FnMut(&Self, &Subst),
     ) where
-        L: Language,
         N: Analysis<L>,
     {
         let mut instructions = instructions.iter();
@@ -99,6 +147,60 @@ impl Machine {
                         self.run(egraph, remaining_instructions, subst, yield_fn)
                     });
                 }
+                Instruction::BindAc { i, out, node, n } => {
+                    let remaining_instructions = instructions.as_slice();
+                    let matches = self.ac_matches(egraph, node, self.reg(*i), *n);
+                    for parts in matches.iter() {
+                        self.reg.truncate(out.0 as usize);
+                        self.reg.extend(parts);
+                        self.run(egraph, remaining_instructions, subst, yield_fn)
+                    }
+                    return;
+                }
+                Instruction::BindSeq { i, out, node, seq } => {
+                    let remaining_instructions = instructions.as_slice();
+                    let args = &seq.args;
+                    let fixed = args.iter().filter(|is_seq| !**is_seq).count();
+                    for n in &egraph[self.reg(*i)].nodes {
+                        if n.len() < fixed || !same_op(n, node) {
+                            continue;
+                        }
+                        for lengths in seq_lengths(n.len() - fixed, args.len() - fixed) {
+                            self.reg.truncate(out.0 as usize);
+                            self.seqs.truncate(seq.first);
+                            let mut children = n.children().iter().copied();
+                            let mut lengths = lengths.into_iter();
+                            for &is_seq in args {
+                                if is_seq {
+                                    let len = lengths.next().unwrap();
+                                    self.seqs.push(children.by_ref().take(len).collect());
+                                } else {
+                                    self.reg.extend(children.next());
+                                }
+                            }
+                            self.run(egraph, remaining_instructions, subst, yield_fn)
+                        }
+                    }
+                    return;
+                }
+                Instruction::Check { i, ann } => {
+                    let class = &egraph[self.reg(*i)];
+                    let ok = match ann {
+                        Annotation::Variant(name) => {
+                            let name = Some(name.as_str());
+                            class.nodes.iter().any(|n| n.variant_name() == name)
+                        }
+                        Annotation::Predicate(name) => {
+                            match egraph.analysis.predicate(name.as_str(), &class.data) {
+                                Some(ok) => ok,
+                                None => panic!("Unknown predicate <{}> in pattern", name),
+                            }
+                        }
+                    };
+                    if !ok {
+                        return;
+                    }
+                }
                 Instruction::Scan { out } => {
                     let remaining_instructions = instructions.as_slice();
                     for class in egraph.classes() {
@@ -113,6 +215,13 @@ impl Machine {
                         return;
                     }
                 }
+                Instruction::CompareSeq { i, j } => {
+                    let (a, b) = (&self.seqs[*i], &self.seqs[*j]);
+                    let same = |(x, y): (&Id, &Id)| egraph.find(*x) == egraph.find(*y);
+                    if a.len() != b.len() || !a.iter().zip(b).all(same) {
+                        return;
+                    }
+                }
                 Instruction::Lookup { term, i } => {
                     self.lookup.clear();
                     for node in term {
@@ -142,13 +251,230 @@ impl Machine {
     }
 }
 
+// every way to write `total` as an ordered sum of `parts` lengths
+fn seq_lengths(total: usize, parts: usize) -> Vec<Vec<usize>> {
+    if parts == 0 {
+        return if total == 0 { vec![vec![]] } else { vec![] };
+    }
+    let mut result = vec![];
+    for first in 0..=total {
+        for mut rest in seq_lengths(total - first, parts - 1) {
+            rest.insert(0, first);
+            result.push(rest);
+        }
+    }
+    result
+}
+
+// AC matching is exponential in the number of summands, so cap the search;
+// these are documented on `Language::is_ac`
+const AC_MAX_SUMMANDS: usize = 6;
+const AC_MAX_FLATTENINGS: usize = 32;
+
+impl<L: Language> Machine<L> {
+    fn cut_off_ac(&mut self, id: Id) {
+        if !self.ac_cut_off {
+            self.ac_cut_off = true;
+            log::warn!(
+                "Skipping AC matches in e-class {}: sums of more than {} parts, \
+                 or more than {} sums, are not searched",
+                id,
+                AC_MAX_SUMMANDS,
+                AC_MAX_FLATTENINGS
+            );
+        }
+    }
+
+    /// The ways to write `id` as a sorted multiset of `op`-summands,
+    /// starting with just `[id]`. Classes being flattened are not unfolded
+    /// again, and nodes like `(+ a 0)` in the class of `a` are skipped:
+    /// they would make `a` a summand of itself, over and over.
+    fn flatten<N: Analysis<L>>(
+        &mut self,
+        egraph: &EGraph<L, N>,
+        op: &L,
+        id: Id,
+        in_progress: &mut Vec<Id>,
+    ) -> Vec<Vec<Id>> {
+        let id = egraph.find(id);
+        if let Some(found) = self.flattenings.get(&(op.clone(), id)) {
+            return found.clone();
+        }
+        let mut result = vec![vec![id]];
+        if in_progress.contains(&id) {
+            return result;
+        }
+        in_progress.push(id);
+        let mut seen: HashSet<Vec<Id>> = result.iter().cloned().collect();
+        'nodes: for node in &egraph[id].nodes {
+            if !same_ac_op(node, op) || node.any(|c| egraph.find(c) == id) {
+                continue;
+            }
+            // n-ary nodes are flattened child by child
+            let mut sums = vec![vec![]];
+            for &child in node.children() {
+                let parts = self.flatten(egraph, op, child, in_progress);
+                let mut next = vec![];
+                for s in &sums {
+                    for p in &parts {
+                        if s.len() + p.len() <= AC_MAX_SUMMANDS {
+                            next.push(s.iter().chain(p).copied().collect::<Vec<Id>>());
+                        } else {
+                            self.cut_off_ac(id);
+                        }
+                    }
+                }
+                if next.len() > AC_MAX_FLATTENINGS {
+                    next.truncate(AC_MAX_FLATTENINGS);
+                    self.cut_off_ac(id);
+                }
+                sums = next;
+            }
+            for mut sum in sums {
+                sum.sort();
+                if seen.insert(sum.clone()) {
+                    result.push(sum);
+                }
+                if result.len() > AC_MAX_FLATTENINGS {
+                    self.cut_off_ac(id);
+                    break 'nodes;
+                }
+            }
+        }
+        in_progress.pop();
+        self.flattenings.insert((op.clone(), id), result.clone());
+        result
+    }
+
+    /// An existing e-class equal to the AC sum of `summands`, if any
+    fn ac_sum<N: Analysis<L>>(
+        &mut self,
+        egraph: &EGraph<L, N>,
+        op: &L,
+        summands: &[Id],
+    ) -> Option<Id> {
+        if let [id] = summands {
+            return Some(*id);
+        }
+        let key = (op.clone(), summands.to_vec());
+        if let Some(found) = self.sums.get(&key) {
+            return *found;
+        }
+        let mut found = None;
+        // an n-ary node, as added by an AC-canonicalizing e-graph
+        if summands.len() > 2 {
+            let node = op.rebuild_with(summands.to_vec());
+            found = node.and_then(|n| egraph.lookup(n));
+        }
+        // the first summand always goes left; commuting is done by the lookup
+        for mask in (1..1usize << summands.len()).step_by(2) {
+            if found.is_some() {
+                break;
+            }
+            let (mut left, mut right) = (vec![], vec![]);
+            for (i, id) in summands.iter().enumerate() {
+                match mask & (1 << i) != 0 {
+                    true => left.push(*id),
+                    false => right.push(*id),
+                }
+            }
+            if right.is_empty() {
+                continue;
+            }
+            let l = self.ac_sum(egraph, op, &left);
+            let r = self.ac_sum(egraph, op, &right);
+            if let (Some(l), Some(r)) = (l, r) {
+                for (x, y) in [(l, r), (r, l)] {
+                    if let Some(node) = op.rebuild_with(vec![x, y]) {
+                        found = found.or_else(|| egraph.lookup(node));
+                    }
+                }
+            }
+        }
+        self.sums.insert(key, found);
+        found
+    }
+
+    /// Every way to split `id` into `arity` existing e-classes whose AC sum it is
+    fn ac_matches<N: Analysis<L>>(
+        &mut self,
+        egraph: &EGraph<L, N>,
+        op: &L,
+        id: Id,
+        arity: usize,
+    ) -> AcSplits {
+        let id = egraph.find(id);
+        let key = (op.clone(), id, arity);
+        if let Some(found) = self.ac_matches.get(&key) {
+            return found.clone();
+        }
+        let mut flattenings = self.flatten(egraph, op, id, &mut vec![]);
+        // the first flattening is `id` itself, which is not a sum
+        flattenings.remove(0);
+        // nodes that refer to `id` still match, but only as they are
+        for node in &egraph[id].nodes {
+            if same_ac_op(node, op) && node.any(|c| egraph.find(c) == id) {
+                let mut sum: Vec<Id> = node.children().iter().map(|c| egraph.find(*c)).collect();
+                sum.sort();
+                if !flattenings.contains(&sum) {
+                    flattenings.push(sum);
+                }
+            }
+        }
+
+        let mut seen = HashSet::default();
+        let mut result = vec![];
+        for summands in flattenings {
+            let n = summands.len();
+            if n < arity {
+                continue;
+            }
+            // assign each summand to one part, like an odometer
+            let mut part_of = vec![0; n];
+            loop {
+                let mut parts = vec![vec![]; arity];
+                for (s, &p) in summands.iter().zip(&part_of) {
+                    parts[p].push(*s);
+                }
+                if parts.iter().all(|p| !p.is_empty()) && seen.insert(parts.clone()) {
+                    let ids: Option<Vec<Id>> =
+                        parts.iter().map(|p| self.ac_sum(egraph, op, p)).collect();
+                    if let Some(ids) = ids {
+                        result.push(ids);
+                    }
+                }
+
+                let mut i = 0;
+                while i < n && part_of[i] + 1 == arity {
+                    part_of[i] = 0;
+                    i += 1;
+                }
+                if i == n {
+                    break;
+                }
+                part_of[i] += 1;
+            }
+        }
+        let result: AcSplits = result.into();
+        self.ac_matches.insert(key, result.clone());
+        result
+    }
+}
+
 struct Compiler<L> {
     v2r: IndexMap<Var, Reg>,
+    // sequence variables and the seqs they are bound to
+    v2s: IndexMap<Var, usize>,
+    annotations: Vec<(Var, Annotation)>,
     free_vars: Vec<HashSet<Var>>,
     subtree_size: Vec<usize>,
+    // whether a subterm has an AC operator or a sequence variable,
+    // so can't be looked up syntactically
+    no_lookup: Vec<bool>,
     todo_nodes: HashMap<(Id, Reg), L>,
     instructions: Vec<Instruction<L>>,
     next_reg: Reg,
+    next_seq: usize,
 }
 
 impl<L: Language> Compiler<L> {
@@ -156,20 +482,30 @@ impl<L: Language> Compiler<L> {
         Self {
             free_vars: Default::default(),
             subtree_size: Default::default(),
+            no_lookup: Default::default(),
             v2r: Default::default(),
+            v2s: Default::default(),
+            annotations: Default::default(),
             todo_nodes: Default::default(),
             instructions: Default::default(),
             next_reg: Reg(0),
+            next_seq: 0,
         }
     }
 
     fn add_todo(&mut self, pattern: &PatternAst<L>, id: Id, reg: Reg) {
         match &pattern[id] {
+            ENodeOrVar::Var(v) if v.is_sequence() => {
+                panic!("sequence variable {} must be a child of an enode", v)
+            }
             ENodeOrVar::Var(v) => {
                 if let Some(&j) = self.v2r.get(v) {
                     self.instructions.push(Instruction::Compare { i: reg, j })
                 } else {
                     self.v2r.insert(*v, reg);
+                    for &(_, ann) in self.annotations.iter().filter(|(w, _)| w == v) {
+                        self.instructions.push(Instruction::Check { i: reg, ann })
+                    }
                 }
             }
             ENodeOrVar::ENode(pat) => {
@@ -182,24 +518,31 @@ impl<L: Language> Compiler<L> {
         let len = pattern.as_ref().len();
         self.free_vars = Vec::with_capacity(len);
         self.subtree_size = Vec::with_capacity(len);
+        self.no_lookup = Vec::with_capacity(len);
 
         for node in pattern.as_ref() {
             let mut free = HashSet::default();
             let mut size = 0;
+            let no_lookup;
             match node {
                 ENodeOrVar::ENode(n) => {
                     size = 1;
+                    let mut ac = n.is_ac();
                     for &child in n.children() {
                         free.extend(&self.free_vars[usize::from(child)]);
                         size += self.subtree_size[usize::from(child)];
+                        ac |= self.no_lookup[usize::from(child)];
                     }
+                    no_lookup = ac;
                 }
                 ENodeOrVar::Var(v) => {
                     free.insert(*v);
+                    no_lookup = v.is_sequence();
                 }
             }
             self.free_vars.push(free);
             self.subtree_size.push(size);
+            self.no_lookup.push(no_lookup);
         }
     }
 
@@ -231,7 +574,7 @@ impl<L: Language> Compiler<L> {
     fn is_ground_now(&self, id: Id) -> bool {
         self.free_vars[usize::from(id)]
             .iter()
-            .all(|v| self.v2r.contains_key(v))
+            .all(|v| self.v2r.contains_key(v) || self.v2s.contains_key(v))
     }
 
     fn compile(&mut self, patternbinder: Option<Var>, pattern: &PatternAst<L>) {
@@ -268,7 +611,8 @@ impl<L: Language> Compiler<L> {
         }
 
         while let Some(((id, reg), node)) = self.next() {
-            if self.is_ground_now(id) && !node.is_leaf() {
+            let ground = self.is_ground_now(id) && !self.no_lookup[usize::from(id)];
+            if ground && !node.is_leaf() {
                 let extracted = pattern.extract(id);
                 self.instructions.push(Instruction::Lookup {
                     i: reg,
@@ -281,6 +625,56 @@ impl<L: Language> Compiler<L> {
                         })
                         .collect(),
                 });
+            } else if node.any(|c| is_seq_var(pattern, c)) {
+                let is_seq = |c: &Id| is_seq_var(pattern, *c);
+                let args: Vec<bool> = node.children().iter().map(is_seq).collect();
+                let (seqs, fixed): (Vec<Id>, Vec<Id>) =
+                    node.children().iter().partition(|c| is_seq(c));
+                let out = next_out;
+                next_out.0 += fixed.len() as u32;
+                let first = self.next_seq;
+                self.next_seq += seqs.len();
+
+                let op = node.clone().map_children(|_| Id::from(0));
+                self.instructions.push(Instruction::BindSeq {
+                    i: reg,
+                    node: op,
+                    out,
+                    seq: Seq { first, args },
+                });
+
+                for (i, &child) in fixed.iter().enumerate() {
+                    self.add_todo(pattern, child, Reg(out.0 + i as u32));
+                }
+                for (i, &child) in seqs.iter().enumerate() {
+                    let v = match &pattern[child] {
+                        ENodeOrVar::Var(v) => *v,
+                        ENodeOrVar::ENode(_) => unreachable!(),
+                    };
+                    let i = first + i;
+                    if let Some(&j) = self.v2s.get(&v) {
+                        self.instructions.push(Instruction::CompareSeq { i, j })
+                    } else {
+                        self.v2s.insert(v, i);
+                    }
+                }
+            } else if node.is_ac() && node.len() >= 2 {
+                let mut args = vec![];
+                ac_args(pattern, id, &node, &mut args);
+                let out = next_out;
+                next_out.0 += args.len() as u32;
+
+                let op = node.clone().map_children(|_| Id::from(0));
+                self.instructions.push(Instruction::BindAc {
+                    i: reg,
+                    node: op,
+                    out,
+                    n: args.len(),
+                });
+
+                for (i, &arg) in args.iter().enumerate() {
+                    self.add_todo(pattern, arg, Reg(out.0 + i as u32));
+                }
             } else {
                 let out = next_out;
                 next_out.0 += node.len() as u32;
@@ -309,21 +703,46 @@ impl<L: Language> Compiler<L> {
         Program {
             instructions: self.instructions,
             subst,
+            seqs: self.v2s.into_iter().collect(),
+        }
+    }
+}
+
+fn is_seq_var<L: Language>(pattern: &PatternAst<L>, id: Id) -> bool {
+    matches!(&pattern[id], ENodeOrVar::Var(v) if v.is_sequence())
+}
+
+// the arguments of a nest of `op`s in a pattern, like `?a ?b ?c` in `(+ ?a (+ ?b ?c))`
+fn ac_args<L: Language>(pattern: &PatternAst<L>, id: Id, op: &L, args: &mut Vec<Id>) {
+    for &child in pattern[id].children() {
+        match &pattern[child] {
+            ENodeOrVar::ENode(n) if same_ac_op(n, op) && !n.any(|c| is_seq_var(pattern, c)) => {
+                ac_args(pattern, child, op, args)
+            }
+            _ => args.push(child),
         }
     }
 }
 
 impl<L: Language> Program<L> {
-    pub(crate) fn compile_from_pat(pattern: &PatternAst<L>) -> Self {
+    pub(crate) fn compile_from_pat(
+        pattern: &PatternAst<L>,
+        annotations: &[(Var, Annotation)],
+    ) -> Self {
         let mut compiler = Compiler::new();
+        compiler.annotations = annotations.to_vec();
         compiler.compile(None, pattern);
         let program = compiler.extract();
         log::debug!("Compiled {:?} to {:?}", pattern.as_ref(), program);
         program
     }
 
-    pub(crate) fn compile_from_multi_pat(patterns: &[(Var, PatternAst<L>)]) -> Self {
+    pub(crate) fn compile_from_multi_pat(
+        patterns: &[(Var, PatternAst<L>)],
+        annotations: &[(Var, Annotation)],
+    ) -> Self {
         let mut compiler = Compiler::new();
+        compiler.annotations = annotations.to_vec();
         for (var, pattern) in patterns {
             compiler.compile(Some(*var), pattern);
         }
@@ -331,13 +750,67 @@ impl<L: Language> Program<L> {
     }
 
     pub fn run<A>(&self, egraph: &EGraph<L, A>, eclass: Id) -> Vec<Subst>
+    where
+        A: Analysis<L>,
+    {
+        self.run_with(&mut Machine::default(), egraph, eclass)
+    }
+
+    /// Runs the program on each of `eclasses`, sharing the memoized AC
+    /// flattenings between them.
+    pub(crate) fn run_each<A>(
+        &self,
+        egraph: &EGraph<L, A>,
+        eclasses: impl IntoIterator<Item = Id>,
+    ) -> Vec<(Id, Vec<Subst>)>
     where
         A: Analysis<L>,
     {
         let mut machine = Machine::default();
+        eclasses
+            .into_iter()
+            .map(|eclass| (eclass, self.run_with(&mut machine, egraph, eclass)))
+            .collect()
+    }
 
+    fn run_with<A>(&self, machine: &mut Machine<L>, egraph: &EGraph<L, A>, eclass: Id) -> Vec<Subst>
+    where
+        A: Analysis<L>,
+    {
         assert!(egraph.clean, "Tried to search a dirty e-graph!");
-        assert_eq!(machine.reg.len(), 0);
+        self.run_unchecked(machine, egraph, eclass)
+    }
+
+    /// Runs the program even if the e-graph is dirty, as it is while
+    /// conditions are checked; nodes that are stale since the last rebuild
+    /// may then be missed.
+    /// The `machine` can be reused across runs, since the e-graph may
+    /// change between them its memoized AC matches are forgotten first.
+    pub(crate) fn run_dirty<A>(
+        &self,
+        machine: &mut Machine<L>,
+        egraph: &EGraph<L, A>,
+        eclass: Id,
+    ) -> Vec<Subst>
+    where
+        A: Analysis<L>,
+    {
+        machine.flattenings.clear();
+        machine.sums.clear();
+        machine.ac_matches.clear();
+        self.run_unchecked(machine, egraph, eclass)
+    }
+
+    fn run_unchecked<A>(
+        &self,
+        machine: &mut Machine<L>,
+        egraph: &EGraph<L, A>,
+        eclass: Id,
+    ) -> Vec<Subst>
+    where
+        A: Analysis<L>,
+    {
+        machine.reg.clear();
         machine.reg.push(eclass);
 
         let mut matches = Vec::new();
@@ -352,10 +825,25 @@ impl<L: Language> Program<L> {
                     // HACK we are reusing Ids here, this is bad
                     .map(|(v, reg_id)| (*v, machine.reg(Reg(usize::from(*reg_id) as u32))))
                     .collect();
-                matches.push(Subst { vec: subst_vec });
+                let seqs = self.seqs.iter();
+                let seqs = seqs.map(|(v, s)| (*v, machine.seqs[*s].clone())).collect();
+                matches.push(Subst {
+                    vec: subst_vec,
+                    seqs,
+                });
             },
         );
 
+        // different ways of splitting an AC operator can give the same match
+        if self
+            .instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::BindAc { .. }))
+        {
+            let mut seen = HashSet::default();
+            matches.retain(|subst| seen.insert(subst.clone()));
+        }
+
         log::trace!("Ran program, found {:?}", matches);
         matches
     }
@@ -31,9 +31,15 @@ define_language! {
 
         // string variants with an array of child `Id`s (any static size)
         // any type that implements LanguageChildren may be used here
-        "+" = Add([Id; 2]),
         "-" = Sub([Id; 2]),
-        "*" = Mul([Id; 2]),
+
+        // variants marked `#[ac]` are associative and commutative,
+        // so patterns match them modulo AC (see `Language::is_ac`);
+        // with a `Vec<Id>` of children they can also be flattened into
+        // n-ary nodes (see `RecExpr::flatten_ac`); they must have at
+        // least two children
+        #[ac] "+" = Add([Id; 2]),
+        #[ac] "*" = Mul(Vec<Id>),
 
         // can also do a variable number of children in a boxed slice
         // this will only match if the lengths are the same
@@ -63,7 +69,7 @@ define_language! {
 #[macro_export]
 macro_rules! define_language {
     ($(#[$meta:meta])* $vis:vis enum $name:ident $variants:tt) => {
-        $crate::__define_language!($(#[$meta])* $vis enum $name $variants -> {} {} {} {} {} {});
+        $crate::__define_language!($(#[$meta])* $vis enum $name $variants -> {} {} {} {} {} {} {} {} {});
     };
 }
 
@@ -72,7 +78,7 @@ macro_rules! define_language {
 macro_rules! __define_language {
     ($(#[$meta:meta])* $vis:vis enum $name:ident {} ->
      $decl:tt {$($matches:tt)*} $children:tt $children_mut:tt
-     $display:tt {$($from_op:tt)*}
+     $display:tt {$($from_op:tt)*} {$($ac:tt)*} {$($rebuild:tt)*} {$([$names:ident] $variants:pat,)*}
     ) => {
         $(#[$meta])*
         #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
@@ -87,6 +93,24 @@ macro_rules! __define_language {
 
             fn children(&self) -> &[Id] { match self $children }
             fn children_mut(&mut self) -> &mut [Id] { match self $children_mut }
+
+            #[allow(unreachable_patterns)]
+            fn is_ac(&self) -> bool {
+                match self { $($ac)* _ => false }
+            }
+
+            fn rebuild_with(&self, children: ::std::vec::Vec<$crate::Id>) -> ::std::option::Option<Self> {
+                // like `Display`, pass `children` in for hygiene reasons
+                match (self, children) { $($rebuild)* _ => None }
+            }
+
+            fn variant_name(&self) -> ::std::option::Option<&'static str> {
+                Some(match self { $($variants => stringify!($names),)* })
+            }
+
+            fn variant_names() -> ::std::option::Option<&'static [&'static str]> {
+                Some(&[$(stringify!($names)),*])
+            }
         }
 
         impl ::std::fmt::Display for $name {
@@ -115,7 +139,7 @@ macro_rules! __define_language {
          $($variants:tt)*
      } ->
      { $($decl:tt)* } { $($matches:tt)* } { $($children:tt)* } { $($children_mut:tt)* }
-     { $($display:tt)* } { $($from_op:tt)* }
+     { $($display:tt)* } { $($from_op:tt)* } { $($ac:tt)* } { $($rebuild:tt)* } { $($names:tt)* }
     ) => {
         $crate::__define_language!(
             $(#[$meta])* $vis enum $name
@@ -126,6 +150,29 @@ macro_rules! __define_language {
             { $($children_mut)*  $name::$variant => &mut [], }
             { $($display)*       ($name::$variant, f) => f.write_str($string), }
             { $($from_op)*       ($string, children) if children.is_empty() => Ok($name::$variant), }
+            { $($ac)* }
+            { $($rebuild)*       ($name::$variant, children) if children.is_empty() => Some($name::$variant), }
+            { $($names)*         [$variant] $name::$variant, }
+        );
+    };
+
+    ($(#[$meta:meta])* $vis:vis enum $name:ident
+     {
+         #[ac] $string:literal = $variant:ident ($ids:ty),
+         $($variants:tt)*
+     } ->
+     { $($decl:tt)* } { $($matches:tt)* } { $($children:tt)* } { $($children_mut:tt)* }
+     { $($display:tt)* } { $($from_op:tt)* } { $($ac:tt)* } { $($rebuild:tt)* } { $($names:tt)* }
+    ) => {
+        $crate::__define_language!(
+            $(#[$meta])* $vis enum $name
+            { $string = $variant($ids), $($variants)* } ->
+            { $($decl)* } { $($matches)* } { $($children)* } { $($children_mut)* }
+            { $($display)* }
+            { $($from_op)*       (op, children) if op == $string && children.len() < 2 => Err($crate::FromOpError::new(op, children)), }
+            { $($ac)*            $name::$variant(..) => true, }
+            { $($rebuild)* }
+            { $($names)* }
         );
     };
 
@@ -135,7 +182,7 @@ macro_rules! __define_language {
          $($variants:tt)*
      } ->
      { $($decl:tt)* } { $($matches:tt)* } { $($children:tt)* } { $($children_mut:tt)* }
-     { $($display:tt)* } { $($from_op:tt)* }
+     { $($display:tt)* } { $($from_op:tt)* } { $($ac:tt)* } { $($rebuild:tt)* } { $($names:tt)* }
     ) => {
         $crate::__define_language!(
             $(#[$meta])* $vis enum $name
@@ -150,6 +197,12 @@ macro_rules! __define_language {
                   Ok($name::$variant(children))
               },
             }
+            { $($ac)* }
+            { $($rebuild)*       ($name::$variant(_), children) if <$ids as $crate::LanguageChildren>::can_be_length(children.len()) => {
+                  Some($name::$variant(<$ids as $crate::LanguageChildren>::from_vec(children)))
+              },
+            }
+            { $($names)*         [$variant] $name::$variant(..), }
         );
     };
 
@@ -159,7 +212,7 @@ macro_rules! __define_language {
          $($variants:tt)*
      } ->
      { $($decl:tt)* } { $($matches:tt)* } { $($children:tt)* } { $($children_mut:tt)* }
-     { $($display:tt)* } { $($from_op:tt)* }
+     { $($display:tt)* } { $($from_op:tt)* } { $($ac:tt)* } { $($rebuild:tt)* } { $($names:tt)* }
     ) => {
         $crate::__define_language!(
             $(#[$meta])* $vis enum $name
@@ -170,6 +223,9 @@ macro_rules! __define_language {
             { $($children_mut)*  $name::$variant(_data) => &mut [], }
             { $($display)*       ($name::$variant(data), f) => ::std::fmt::Display::fmt(data, f), }
             { $($from_op)*       (op, children) if op.parse::<$data>().is_ok() && children.is_empty() => Ok($name::$variant(op.parse().unwrap())), }
+            { $($ac)* }
+            { $($rebuild)*       ($name::$variant(data), children) if children.is_empty() => Some($name::$variant(data.clone())), }
+            { $($names)*         [$variant] $name::$variant(..), }
         );
     };
 
@@ -179,7 +235,7 @@ macro_rules! __define_language {
          $($variants:tt)*
      } ->
      { $($decl:tt)* } { $($matches:tt)* } { $($children:tt)* } { $($children_mut:tt)* }
-     { $($display:tt)* } { $($from_op:tt)* }
+     { $($display:tt)* } { $($from_op:tt)* } { $($ac:tt)* } { $($rebuild:tt)* } { $($names:tt)* }
     ) => {
         $crate::__define_language!(
             $(#[$meta])* $vis enum $name
@@ -195,6 +251,12 @@ macro_rules! __define_language {
                   Ok($name::$variant(data, children))
               },
             }
+            { $($ac)* }
+            { $($rebuild)*       ($name::$variant(data, _), children) if <$ids as $crate::LanguageChildren>::can_be_length(children.len()) => {
+                  Some($name::$variant(data.clone(), <$ids as $crate::LanguageChildren>::from_vec(children)))
+              },
+            }
+            { $($names)*         [$variant] $name::$variant(..), }
         );
     };
 }
@@ -222,6 +284,9 @@ where the given expression should implement [`Condition`].
 For each of these, the macro will wrap the given applier in a
 [`ConditionalApplier`] with the given condition, with the first condition being
 the outermost, and the last condition being the innermost.
+The conditions `if match "(pat)" in ?x` and `if !match "(pat)" in ?x`
+check whether the pattern matches in the e-class bound to `?x`
+(see [`ConditionMatches`] and [`ConditionNotMatches`]).
 
 # Example
 ```
@@ -251,9 +316,12 @@ let mut rules: Vec<Rewrite<SimpleLanguage, ()>> = vec![
     rewrite!("something_conditional";
              "(/ ?a ?b)" => "(* ?a (/ 1 ?b))"
              if is_not_zero("?b")),
+
+    // only fires when ?b isn't bound to the same e-class as ?a
+    rewrite!("sub-self"; "(- ?a ?b)" => "(+ ?a (* -1 ?b))" if !match "?a" in ?b),
 ];
 
-// rewrite! supports bidirectional rules too
+// rewrite! supports bidirectional rules too, see Rewrite::new_bidirectional
 // it returns a Vec of length 2, so you need to concat
 rules.extend(vec![
     rewrite!("add-0"; "(+ ?a 0)" <=> "?a"),
@@ -283,24 +351,24 @@ macro_rules! rewrite {
     (
         $name:expr;
         $lhs:tt => $rhs:tt
-        $(if $cond:expr)*
+        $($cond:tt)*
     )  => {{
         let searcher = $crate::__rewrite!(@parse Pattern $lhs);
         let core_applier = $crate::__rewrite!(@parse Pattern $rhs);
-        let applier = $crate::__rewrite!(@applier core_applier; $($cond,)*);
+        let applier = $crate::__rewrite!(@applier core_applier; $($cond)*);
         $crate::Rewrite::new($name.to_string(), searcher, applier).unwrap()
     }};
     (
         $name:expr;
         $lhs:tt <=> $rhs:tt
-        $(if $cond:expr)*
+        $($cond:tt)*
     )  => {{
         let name = $name;
         let name2 = String::from(name.clone()) + "-rev";
-        vec![
-            $crate::rewrite!(name;  $lhs => $rhs $(if $cond)*),
-            $crate::rewrite!(name2; $rhs => $lhs $(if $cond)*)
-        ]
+        $crate::Rewrite::paired(
+            $crate::rewrite!(name;  $lhs => $rhs $($cond)*),
+            $crate::rewrite!(name2; $rhs => $lhs $($cond)*)
+        ).unwrap()
     }};
 }
 
@@ -332,12 +400,32 @@ macro_rules! __rewrite {
     };
     (@parse $t:ident $rhs:expr) => { $rhs };
     (@applier $applier:expr;) => { $applier };
-    (@applier $applier:expr; $cond:expr, $($conds:expr,)*) => {
+    (@applier $applier:expr; if $($rest:tt)*) => {
+        $crate::__rewrite!(@applier $applier; [] $($rest)*)
+    };
+    // collect the tokens of one condition up to the next `if`
+    (@applier $applier:expr; [$($cond:tt)*] if $($rest:tt)*) => {
         $crate::ConditionalApplier {
-            condition: $cond,
-            applier: $crate::__rewrite!(@applier $applier; $($conds,)*)
+            condition: $crate::__rewrite!(@condition $($cond)*),
+            applier: $crate::__rewrite!(@applier $applier; [] $($rest)*)
         }
     };
+    (@applier $applier:expr; [$($cond:tt)*] $t:tt $($rest:tt)*) => {
+        $crate::__rewrite!(@applier $applier; [$($cond)* $t] $($rest)*)
+    };
+    (@applier $applier:expr; [$($cond:tt)*]) => {
+        $crate::ConditionalApplier {
+            condition: $crate::__rewrite!(@condition $($cond)*),
+            applier: $applier
+        }
+    };
+    (@condition ! match $pat:literal in ? $var:ident) => {
+        $crate::ConditionNotMatches::parse($pat, concat!("?", stringify!($var)))
+    };
+    (@condition match $pat:literal in ? $var:ident) => {
+        $crate::ConditionMatches::parse($pat, concat!("?", stringify!($var)))
+    };
+    (@condition $cond:expr) => { $cond };
 }
 
 #[cfg(test)]
@@ -374,6 +462,32 @@ mod tests {
             rewrite!("rule"; "f" => { "pat".parse::<Pattern<_>>().unwrap() }),
         ];
         rws.extend(rewrite!("two-way"; "foo" <=> "bar"));
+        assert_eq!(rws[2].name.as_str(), "two-way");
+        assert_eq!(rws[3].name.as_str(), "two-way-rev");
+        assert!(rws[2..].iter().all(|r| r.axiom == Some("two-way".into())));
+        assert_eq!(rws[0].axiom, None);
+    }
+
+    #[test]
+    fn variant_names() {
+        let names = Simple::variant_names().unwrap();
+        assert_eq!(
+            names,
+            ["Add", "Sub", "Mul", "Neg", "List", "Pi", "Int", "Var"]
+        );
+        assert_eq!(Simple::Pi.variant_name(), Some("Pi"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Rewrite bad has unknown annotation ?a:Itn")]
+    fn rewrite_annotation_panic() {
+        let _: Rewrite<Simple, ()> = rewrite!("bad"; "(+ ?a:Itn ?b)" => "?b");
+    }
+
+    #[test]
+    #[should_panic(expected = "refers to unbound var ?b")]
+    fn rewrite_bidirectional_panic() {
+        let _: Vec<Rewrite<Simple, ()>> = rewrite!("bad"; "(+ ?a ?b)" <=> "?a");
     }
 
     #[test]
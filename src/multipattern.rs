@@ -52,7 +52,12 @@ impl<L: Language> MultiPattern<L> {
     /// assert_eq!(multipattern.n_matches(&egraph), 2);
     /// ```
     pub fn new(asts: Vec<(Var, PatternAst<L>)>) -> Self {
-        let program = machine::Program::compile_from_multi_pat(&asts);
+        let mut annotations = vec![];
+        let asts: Vec<_> = asts
+            .iter()
+            .map(|(v, ast)| (*v, pattern::strip_annotations(ast, &mut annotations)))
+            .collect();
+        let program = machine::Program::compile_from_multi_pat(&asts, &annotations);
         Self { asts, program }
     }
 }
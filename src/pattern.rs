@@ -60,14 +60,113 @@ use crate::*;
 /// assert_eq!(matched_eclasses, vec![a11, a22]);
 /// ```
 ///
+/// Sequence variables like `?xs...` (see [`Var::is_sequence`]) match any
+/// run of an enode's children, so a pattern can look into operators with
+/// any number of children, like those of [`SymbolLang`].
+/// As an [`Applier`], a [`Pattern`] splices the runs back in.
+/// A pattern can't be just a sequence variable, and a [`Runner`] with
+/// explanations enabled rejects rules that use them.
+///
+/// Variables may be annotated to only match some e-classes, as in `?x:Num`
+/// or `?x:<pred>`; see [`Annotation`].
+/// The annotations are checked while matching.
+///
+/// ```
+/// # use egg::*;
+/// define_language! {
+///     enum Math {
+///         Num(i32),
+///         "+" = Add([Id; 2]),
+///         Symbol(Symbol),
+///     }
+/// }
+///
+/// let mut egraph = EGraph::<Math, ()>::default();
+/// egraph.add_expr(&"(+ 1 x)".parse().unwrap());
+/// egraph.add_expr(&"(+ y 2)".parse().unwrap());
+/// egraph.rebuild();
+///
+/// let num_left: Pattern<Math> = "(+ ?a:Num ?b)".parse().unwrap();
+/// assert_eq!(num_left.n_matches(&egraph), 1);
+/// assert_eq!(num_left.to_string(), "(+ ?a:Num ?b)");
+/// ```
+///
+/// ```
+/// # use egg::*;
+/// let mut egraph = EGraph::<SymbolLang, ()>::default();
+/// egraph.add_expr(&"(f a b c)".parse().unwrap());
+/// egraph.rebuild();
+///
+/// // any application of f with b as an argument
+/// let has_b: Pattern<SymbolLang> = "(f ?xs... b ?ys...)".parse().unwrap();
+/// let matches = has_b.search(&egraph);
+/// let ys = "?ys...".parse().unwrap();
+/// assert_eq!(matches[0].substs[0].get_seq(ys).unwrap().len(), 1);
+/// ```
+///
 /// [`FromStr`]: std::str::FromStr
 #[derive(Debug, PartialEq, Clone)]
 pub struct Pattern<L> {
     /// The actual pattern as a [`RecExpr`]
     pub ast: PatternAst<L>,
+    annotations: Vec<(Var, Annotation)>,
     program: machine::Program<L>,
 }
 
+/// A check on the e-class bound to an annotated pattern variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Annotation {
+    /// `?x:Num` only matches e-classes with an enode whose
+    /// [`variant_name`](Language::variant_name) is `Num`.
+    Variant(Symbol),
+    /// `?x:<name>` only matches e-classes whose data satisfies the
+    /// [`predicate`](Analysis::predicate) `name` of the analysis.
+    Predicate(Symbol),
+}
+
+impl Annotation {
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        let bad = |s: &str| s.is_empty() || s.contains(&['<', '>'][..]);
+        match s.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+            Some(name) if !bad(name) => Some(Annotation::Predicate(name.into())),
+            None if !bad(s) => Some(Annotation::Variant(s.into())),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Annotation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Annotation::Variant(name) => write!(f, "{}", name),
+            Annotation::Predicate(name) => write!(f, "<{}>", name),
+        }
+    }
+}
+
+/// Replaces annotated variables like `?x:Num` in `ast` with plain ones,
+/// collecting the annotations.
+pub(crate) fn strip_annotations<L: Language>(
+    ast: &PatternAst<L>,
+    annotations: &mut Vec<(Var, Annotation)>,
+) -> PatternAst<L> {
+    let nodes = ast.as_ref().iter().map(|n| match n {
+        ENodeOrVar::Var(v) => match v.annotations() {
+            Some((var, anns)) => {
+                for ann in anns {
+                    if !annotations.contains(&(var, ann)) {
+                        annotations.push((var, ann));
+                    }
+                }
+                ENodeOrVar::Var(var)
+            }
+            None => n.clone(),
+        },
+        ENodeOrVar::ENode(_) => n.clone(),
+    });
+    RecExpr::from(nodes.collect::<Vec<_>>())
+}
+
 /// A [`RecExpr`] that represents a
 /// [`Pattern`].
 pub type PatternAst<L> = RecExpr<ENodeOrVar<L>>;
@@ -103,9 +202,45 @@ impl<L: Language> PatternAst<L> {
 impl<L: Language> Pattern<L> {
     /// Creates a new pattern from the given pattern ast.
     pub fn new(ast: PatternAst<L>) -> Self {
-        let ast = ast.compact();
-        let program = machine::Program::compile_from_pat(&ast);
-        Pattern { ast, program }
+        let mut annotations = vec![];
+        let ast = strip_annotations(&ast, &mut annotations).compact();
+        let program = machine::Program::compile_from_pat(&ast, &annotations);
+        Pattern {
+            ast,
+            annotations,
+            program,
+        }
+    }
+
+    /// Returns the [`Annotation`]s on this pattern's variables.
+    pub fn annotations(&self) -> &[(Var, Annotation)] {
+        &self.annotations
+    }
+
+    /// The ast with the annotations put back on the variables
+    fn annotated_ast(&self) -> Cow<'_, PatternAst<L>> {
+        if self.annotations.is_empty() {
+            return Cow::Borrowed(&self.ast);
+        }
+        let nodes = self.ast.as_ref().iter().map(|n| match n {
+            ENodeOrVar::Var(v) => {
+                let anns = self.annotations.iter().filter(|(w, _)| w == v);
+                let anns: Vec<Annotation> = anns.map(|(_, a)| *a).collect();
+                ENodeOrVar::Var(v.annotated(&anns))
+            }
+            ENodeOrVar::ENode(_) => n.clone(),
+        });
+        Cow::Owned(RecExpr::from(nodes.collect::<Vec<_>>()))
+    }
+
+    /// Searches one e-class with `machine`, even if the e-graph is dirty.
+    pub(crate) fn search_eclass_dirty<A: Analysis<L>>(
+        &self,
+        machine: &mut machine::Machine<L>,
+        egraph: &EGraph<L, A>,
+        eclass: Id,
+    ) -> Vec<Subst> {
+        self.program.run_dirty(machine, egraph, eclass)
     }
 
     /// Returns a list of the [`Var`]s in this pattern.
@@ -120,12 +255,30 @@ impl<L: Language> Pattern<L> {
         }
         vars
     }
+
+    fn search_eclasses<A: Analysis<L>>(
+        &self,
+        egraph: &EGraph<L, A>,
+        eclasses: impl IntoIterator<Item = Id>,
+    ) -> Vec<SearchMatches<L>> {
+        let ast = &self.ast;
+        self.program
+            .run_each(egraph, eclasses)
+            .into_iter()
+            .filter(|(_, substs)| !substs.is_empty())
+            .map(|(eclass, substs)| SearchMatches {
+                eclass,
+                substs,
+                ast: Some(Cow::Borrowed(ast)),
+            })
+            .collect()
+    }
 }
 
 impl<L: Language + Display> Pattern<L> {
     /// Pretty print this pattern as a sexp with the given width
     pub fn pretty(&self, width: usize) -> String {
-        self.ast.pretty(width)
+        self.annotated_ast().pretty(width)
     }
 }
 
@@ -176,6 +329,9 @@ pub enum ENodeOrVarParseError<E> {
     #[error("tried to parse pattern variable {0:?} as an operator")]
     UnexpectedVar(String),
 
+    #[error("sequence variable {0:?} must be a child of an enode")]
+    BareSequence(String),
+
     #[error(transparent)]
     BadOp(E),
 }
@@ -202,7 +358,16 @@ impl<L: FromOp> std::str::FromStr for Pattern<L> {
     type Err = RecExprParseError<ENodeOrVarParseError<L::Error>>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        PatternAst::from_str(s).map(Self::from)
+        let ast = PatternAst::from_str(s)?;
+        if let Some(ENodeOrVar::Var(v)) = ast.as_ref().last() {
+            if v.is_sequence() {
+                let start = s.len() - s.trim_start().len();
+                let pos = SourcePos::new(s, start, s.trim_end().len());
+                let err = ENodeOrVarParseError::BareSequence(v.to_string());
+                return Err(RecExprParseError::BadOp(err, pos));
+            }
+        }
+        Ok(Self::from(ast))
     }
 }
 
@@ -236,7 +401,7 @@ impl<L: Language> TryFrom<Pattern<L>> for RecExpr<L> {
 
 impl<L: Language + Display> Display for Pattern<L> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        Display::fmt(&self.ast, f)
+        Display::fmt(&self.annotated_ast(), f)
     }
 }
 
@@ -262,6 +427,10 @@ impl<L: Language, A: Analysis<L>> Searcher<L, A> for Pattern<L> {
         Some(&self.ast)
     }
 
+    fn get_annotations(&self) -> &[(Var, Annotation)] {
+        &self.annotations
+    }
+
     fn search(&self, egraph: &EGraph<L, A>) -> Vec<SearchMatches<L>> {
         match self.ast.as_ref().last().unwrap() {
             ENodeOrVar::ENode(e) => {
@@ -269,16 +438,10 @@ impl<L: Language, A: Analysis<L>> Searcher<L, A> for Pattern<L> {
                 let key = std::mem::discriminant(e);
                 match egraph.classes_by_op.get(&key) {
                     None => vec![],
-                    Some(ids) => ids
-                        .iter()
-                        .filter_map(|&id| self.search_eclass(egraph, id))
-                        .collect(),
+                    Some(ids) => self.search_eclasses(egraph, ids.iter().copied()),
                 }
             }
-            ENodeOrVar::Var(_) => egraph
-                .classes()
-                .filter_map(|e| self.search_eclass(egraph, e.id))
-                .collect(),
+            ENodeOrVar::Var(_) => self.search_eclasses(egraph, egraph.classes().map(|e| e.id)),
         }
     }
 
@@ -385,9 +548,11 @@ pub(crate) fn apply_pat<L: Language, A: Analysis<L>>(
 
     for (i, pat_node) in pat.iter().enumerate() {
         let id = match pat_node {
+            // spliced in by the parent
+            ENodeOrVar::Var(w) if w.is_sequence() => Id::from(0),
             ENodeOrVar::Var(w) => subst[*w],
             ENodeOrVar::ENode(e) => {
-                let n = e.clone().map_children(|child| ids[usize::from(child)]);
+                let n = instantiate_node(e, pat, ids, subst);
                 trace!("adding: {:?}", n);
                 egraph.add(n)
             }
@@ -398,6 +563,38 @@ pub(crate) fn apply_pat<L: Language, A: Analysis<L>>(
     *ids.last().unwrap()
 }
 
+/// Instantiates the pattern enode `e` given the `ids` of the pattern's nodes,
+/// splicing in the sequences bound to any sequence variables among its children.
+pub(crate) fn instantiate_node<L: Language>(
+    e: &L,
+    pat: &[ENodeOrVar<L>],
+    ids: &[Id],
+    subst: &Subst,
+) -> L {
+    let seq_var = |child: Id| match &pat[usize::from(child)] {
+        ENodeOrVar::Var(v) if v.is_sequence() => Some(*v),
+        _ => None,
+    };
+    if !e.any(|child| seq_var(child).is_some()) {
+        return e.clone().map_children(|child| ids[usize::from(child)]);
+    }
+    let mut children = vec![];
+    for &child in e.children() {
+        match seq_var(child) {
+            Some(v) => match subst.get_seq(v) {
+                Some(seq) => children.extend_from_slice(seq),
+                None => panic!("Sequence var '{}' not found in {:?}", v, subst),
+            },
+            None => children.push(ids[usize::from(child)]),
+        }
+    }
+    let len = children.len();
+    match e.rebuild_with(children) {
+        Some(n) => n,
+        None => panic!("Can't splice {} children into {:?}", len, e),
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -467,4 +664,157 @@ mod tests {
         assert_eq!(n_matches("(f ?x (g ?x))))"), 1);
         assert_eq!(n_matches("(h ?x 0 0)"), 1);
     }
+
+    #[test]
+    fn ac_patterns() {
+        crate::init_logger();
+        define_language! {
+            enum Ac {
+                #[ac] "+" = Add([Id; 2]),
+                "f" = F(Id),
+                Symbol(Symbol),
+            }
+        }
+        let mut egraph = crate::EGraph::<Ac, ()>::default();
+        let root = egraph.add_expr(&"(+ (+ a (f b)) c)".parse().unwrap());
+        egraph.add_expr(&"(+ (f b) c)".parse().unwrap());
+        egraph.rebuild();
+
+        let n_matches = |s: &str| s.parse::<Pattern<Ac>>().unwrap().n_matches(&egraph);
+        // commutativity
+        assert_eq!(n_matches("(+ c (f ?x))"), 1);
+        // associativity: ?y is (f b) in (+ a (f b)), and the existing
+        // (+ (f b) c) in the root
+        assert_eq!(n_matches("(+ a ?y)"), 2);
+        // any order of the three summands of the root
+        assert_eq!(n_matches("(+ ?x (+ ?y ?z))"), 6);
+        // a ground pattern is compared modulo AC too
+        let ground: Pattern<Ac> = "(+ c (+ (f b) a))".parse().unwrap();
+        assert_eq!(ground.search_eclass(&egraph, root).unwrap().substs.len(), 1);
+        assert_eq!(n_matches("(+ a (+ a ?x))"), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Rule comm matches an AC operator, but explanations are enabled")]
+    fn ac_patterns_explanations() {
+        define_language! {
+            enum Ac {
+                #[ac] "+" = Add([Id; 2]),
+                Symbol(Symbol),
+            }
+        }
+        let rule = rewrite!("comm"; "(+ ?x ?y)" => "(+ ?y ?x)");
+        Runner::<Ac, ()>::default()
+            .with_explanations_enabled()
+            .with_expr(&"(+ a b)".parse().unwrap())
+            .run(&[rule]);
+    }
+
+    #[test]
+    fn sequence_vars() {
+        crate::init_logger();
+        let mut egraph = EGraph::default();
+        let fabc = egraph.add_expr(&"(f a b c)".parse().unwrap());
+        egraph.add_expr(&"(f b)".parse().unwrap());
+        egraph.add_expr(&"(h a b a b)".parse().unwrap());
+        egraph.add_expr(&"(h a b b a)".parse().unwrap());
+        egraph.rebuild();
+
+        let n_matches = |s: &str| s.parse::<Pattern<S>>().unwrap().n_matches(&egraph);
+        assert_eq!(n_matches("(f ?xs...)"), 2);
+        // any application of f with ?x as some argument
+        assert_eq!(n_matches("(f ?xs... ?x ?ys...)"), 4);
+        assert_eq!(n_matches("(f ?xs... b ?ys...)"), 2);
+        assert_eq!(n_matches("(f ?x ?y ?zs...)"), 1);
+        // a repeated sequence variable must match the same children
+        assert_eq!(n_matches("(h ?xs... ?xs...)"), 1);
+
+        let pat: Pattern<S> = "(f ?xs... b ?ys...)".parse().unwrap();
+        let matches = pat.search_eclass(&egraph, fabc).unwrap();
+        let a = egraph.lookup(S::leaf("a")).unwrap();
+        let xs = "?xs...".parse().unwrap();
+        assert_eq!(matches.substs[0].get_seq(xs), Some(&[a][..]));
+
+        assert!("?xs...".parse::<Pattern<S>>().is_err());
+        let unbound = Rewrite::<S, ()>::new(
+            "unbound",
+            pat.clone(),
+            "(g ?zs...)".parse::<Pattern<S>>().unwrap(),
+        );
+        assert_eq!(
+            unbound.unwrap_err(),
+            "Rewrite unbound refers to unbound var ?zs..."
+        );
+
+        let swap = rewrite!("swap"; "(f ?xs... b ?ys...)" => "(g ?ys... ?xs... ?ys...)");
+        Runner::default()
+            .with_egraph(egraph)
+            .with_iter_limit(1)
+            .run(&[swap])
+            .egraph
+            .check_goals(fabc, &["(g c a c)".parse().unwrap()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Rule g uses sequence variable ?xs..., but explanations are enabled")]
+    fn sequence_vars_explanations() {
+        let rule = rewrite!("g"; "(f ?xs...)" => "(g ?xs...)");
+        Runner::<S, ()>::default()
+            .with_explanations_enabled()
+            .with_expr(&"(f a b)".parse().unwrap())
+            .run(&[rule]);
+    }
+
+    #[derive(Default)]
+    struct Leaves;
+    impl Analysis<S> for Leaves {
+        type Data = bool;
+        fn make(_egraph: &crate::EGraph<S, Self>, enode: &S) -> bool {
+            enode.is_leaf()
+        }
+        fn merge(&mut self, to: &mut bool, from: bool) -> DidMerge {
+            merge_max(to, from)
+        }
+        fn predicate(&self, name: &str, data: &bool) -> Option<bool> {
+            match name {
+                "leaf" => Some(*data),
+                _ => None,
+            }
+        }
+        fn predicates() -> Vec<&'static str> {
+            vec!["leaf"]
+        }
+    }
+
+    #[test]
+    fn annotated_vars() {
+        crate::init_logger();
+        let mut egraph = crate::EGraph::<S, Leaves>::default();
+        egraph.add_expr(&"(f a (g b))".parse().unwrap());
+        egraph.rebuild();
+
+        let n_matches = |s: &str| s.parse::<Pattern<S>>().unwrap().n_matches(&egraph);
+        assert_eq!(n_matches("(f ?x ?y)"), 1);
+        assert_eq!(n_matches("(f ?x ?y:<leaf>)"), 0);
+        assert_eq!(n_matches("(f ?x:<leaf> ?y)"), 1);
+        assert_eq!(n_matches("?x:<leaf>"), 2);
+        assert_eq!(n_matches("?x"), 4);
+
+        let pat: Pattern<S> = "(f ?x:<leaf> ?x)".parse().unwrap();
+        assert_eq!(pat.to_string(), "(f ?x:<leaf> ?x:<leaf>)");
+        assert_eq!(
+            pat.annotations(),
+            pat.to_string().parse::<Pattern<S>>().unwrap().annotations()
+        );
+        assert_eq!(pat.vars(), vec!["?x".parse().unwrap()]);
+        assert!("?x:".parse::<Var>().is_err());
+
+        let rule = |lhs: &str| {
+            let lhs: Pattern<S> = lhs.parse().unwrap();
+            Rewrite::<S, Leaves>::new("r", lhs, "?x".parse::<Pattern<S>>().unwrap())
+        };
+        assert!(rule("(f ?x:<leaf> ?y)").is_ok());
+        let err = rule("(f ?x:<leef> ?y)").unwrap_err();
+        assert_eq!(err, "Rewrite r has unknown annotation ?x:<leef>");
+    }
 }
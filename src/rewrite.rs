@@ -1,6 +1,7 @@
+use machine::Machine;
 use pattern::apply_pat;
 use std::fmt::{self, Debug, Display};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::*;
 
@@ -22,6 +23,9 @@ pub struct Rewrite<L, N> {
     pub searcher: Arc<dyn Searcher<L, N> + Sync + Send>,
     /// The applier (right-hand side) of the rewrite.
     pub applier: Arc<dyn Applier<L, N> + Sync + Send>,
+    /// For either direction of a bidirectional rewrite, the name of the
+    /// forward one, so the two can be treated as one axiom.
+    pub axiom: Option<Symbol>,
 }
 
 impl<L, N> Debug for Rewrite<L, N>
@@ -70,13 +74,93 @@ impl<L: Language, N: Analysis<L>> Rewrite<L, N> {
             }
         }
 
+        for (v, ann) in searcher.get_annotations() {
+            let known = match ann {
+                Annotation::Variant(variant) => match L::variant_names() {
+                    Some(names) => names.contains(&variant.as_str()),
+                    None => true,
+                },
+                Annotation::Predicate(predicate) => N::predicates().contains(&predicate.as_str()),
+            };
+            if !known {
+                return Err(format!(
+                    "Rewrite {} has unknown annotation {}",
+                    name,
+                    v.annotated(&[*ann])
+                ));
+            }
+        }
+
         Ok(Self {
             name,
             searcher,
             applier,
+            axiom: None,
         })
     }
 
+    /// Create both directions of the bidirectional rewrite `lhs <=> rhs`,
+    /// named `name` and `name-rev`, like the [`rewrite!`] macro does.
+    ///
+    /// Both sides must bind the same variables, or one direction would
+    /// refer to variables the other side doesn't bind.
+    ///
+    /// ```
+    /// use egg::{*, SymbolLang as S};
+    /// let lhs: Pattern<S> = "(+ ?a 0)".parse().unwrap();
+    /// let rules = Rewrite::<S, ()>::new_bidirectional("add-0", lhs.clone(), "?a".parse().unwrap()).unwrap();
+    /// assert_eq!(rules[1].name.as_str(), "add-0-rev");
+    /// assert_eq!(rules[1].axiom, Some("add-0".into()));
+    ///
+    /// let bad = Rewrite::<S, ()>::new_bidirectional("bad", lhs, "(* ?a ?b)".parse().unwrap());
+    /// assert_eq!(bad.unwrap_err(), "Bidirectional rewrite bad binds ?b only on the right");
+    /// ```
+    pub fn new_bidirectional<P>(
+        name: impl Into<Symbol>,
+        lhs: P,
+        rhs: P,
+    ) -> Result<Vec<Self>, String>
+    where
+        P: Searcher<L, N> + Applier<L, N> + Clone + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let lhs_vars = Searcher::vars(&lhs);
+        let rhs_vars = Searcher::vars(&rhs);
+        for (vars, other, side) in [
+            (&lhs_vars, &rhs_vars, "left"),
+            (&rhs_vars, &lhs_vars, "right"),
+        ] {
+            if let Some(v) = vars.iter().find(|v| !other.contains(v)) {
+                return Err(format!(
+                    "Bidirectional rewrite {} binds {} only on the {}",
+                    name, v, side
+                ));
+            }
+        }
+        let forward = Self::new(name, lhs.clone(), rhs.clone())?;
+        let reverse = Self::new(format!("{}-rev", name), rhs, lhs)?;
+        Self::paired(forward, reverse)
+    }
+
+    /// Marks two rewrites as the directions of one bidirectional rewrite,
+    /// setting both of their [`axiom`](Rewrite::axiom)s to the name of
+    /// `forward`.
+    ///
+    /// This fails if the searcher of `reverse` binds variables that the
+    /// searcher of `forward` doesn't.
+    pub fn paired(mut forward: Self, mut reverse: Self) -> Result<Vec<Self>, String> {
+        let vars = forward.searcher.vars();
+        if let Some(v) = reverse.searcher.vars().iter().find(|v| !vars.contains(v)) {
+            return Err(format!(
+                "Reverse rewrite {} binds new var {}",
+                reverse.name, v
+            ));
+        }
+        forward.axiom = Some(forward.name);
+        reverse.axiom = Some(forward.name);
+        Ok(vec![forward, reverse])
+    }
+
     /// Call [`search`] on the [`Searcher`].
     ///
     /// [`search`]: Searcher::search()
@@ -152,6 +236,11 @@ where
         None
     }
 
+    /// For patterns, return the [`Annotation`]s on their variables
+    fn get_annotations(&self) -> &[(Var, Annotation)] {
+        &[]
+    }
+
     /// Returns a list of the variables bound by this Searcher
     fn vars(&self) -> Vec<Var>;
 }
@@ -330,6 +419,12 @@ where
     fn vars(&self) -> Vec<Var> {
         vec![]
     }
+
+    /// Returns whether this Applier checks a [`Condition`] before
+    /// applying, like a [`ConditionalApplier`] does.
+    fn is_conditional(&self) -> bool {
+        false
+    }
 }
 
 /// An [`Applier`] that checks a [`Condition`] before applying.
@@ -378,6 +473,14 @@ where
         }
     }
 
+    fn get_pattern_ast(&self) -> Option<&PatternAst<L>> {
+        self.applier.get_pattern_ast()
+    }
+
+    fn is_conditional(&self) -> bool {
+        true
+    }
+
     fn vars(&self) -> Vec<Var> {
         let mut vars = self.applier.vars();
         vars.extend(self.condition.vars());
@@ -478,6 +581,156 @@ where
     }
 }
 
+/// A [`Condition`] that checks if a pattern matches in a bound e-class.
+///
+/// The pattern is searched for in the e-class bound to `var`.
+/// Conditions are checked while the e-graph may be dirty, so this sees
+/// the e-graph roughly as of the last rebuild.
+/// Variables of the pattern that are bound by the match
+/// must match the same e-classes; the others may match anything.
+/// The [`rewrite!`] macro supports this as `if match "(pat)" in ?x`.
+///
+#[derive(Debug)]
+pub struct ConditionMatches<L> {
+    pattern: Pattern<L>,
+    var: Var,
+    // reused by every check of the rule
+    machine: Mutex<Machine<L>>,
+}
+
+/// A [`Condition`] that checks if a pattern does _not_ match in a bound
+/// e-class.
+///
+/// This is the negation of [`ConditionMatches`], written
+/// `if !match "(pat)" in ?x` in the [`rewrite!`] macro.
+/// For example, `if !match "?a" in ?b` checks that `?a` and `?b` are
+/// bound to different e-classes.
+///
+/// Only a pattern that is a single variable, like that one, is checked
+/// exactly, since e-classes are compared with [`EGraph::find`].
+/// Any other pattern is searched for in a possibly dirty e-graph, so the
+/// check is approximate: it passes if the enodes the pattern needs are
+/// only found by the next [`rebuild`](EGraph::rebuild).
+///
+#[derive(Debug)]
+pub struct ConditionNotMatches<L> {
+    pattern: Pattern<L>,
+    var: Var,
+    // reused by every check of the rule
+    machine: Mutex<Machine<L>>,
+}
+
+impl<L: Language> ConditionMatches<L> {
+    /// Create a new [`ConditionMatches`] searching for `pattern` in `var`.
+    pub fn new(pattern: Pattern<L>, var: Var) -> Self {
+        let machine = Mutex::default();
+        ConditionMatches {
+            pattern,
+            var,
+            machine,
+        }
+    }
+}
+
+impl<L: Language> ConditionNotMatches<L> {
+    /// Create a new [`ConditionNotMatches`] searching for `pattern` in `var`.
+    pub fn new(pattern: Pattern<L>, var: Var) -> Self {
+        let machine = Mutex::default();
+        ConditionNotMatches {
+            pattern,
+            var,
+            machine,
+        }
+    }
+}
+
+impl<L: FromOp> ConditionMatches<L> {
+    /// Create a ConditionMatches by parsing a pattern and a variable.
+    ///
+    /// This panics if the parsing fails.
+    pub fn parse(pattern: &str, var: &str) -> Self {
+        Self::new(pattern.parse().unwrap(), var.parse().unwrap())
+    }
+}
+
+impl<L: FromOp> ConditionNotMatches<L> {
+    /// Create a ConditionNotMatches by parsing a pattern and a variable.
+    ///
+    /// This panics if the parsing fails.
+    pub fn parse(pattern: &str, var: &str) -> Self {
+        Self::new(pattern.parse().unwrap(), var.parse().unwrap())
+    }
+}
+
+// whether pattern matches in the class of var, agreeing with subst
+fn matches_in<L, N>(
+    pattern: &Pattern<L>,
+    var: Var,
+    machine: &Mutex<Machine<L>>,
+    egraph: &EGraph<L, N>,
+    subst: &Subst,
+) -> bool
+where
+    L: Language,
+    N: Analysis<L>,
+{
+    let same = |a: Id, b: Id| egraph.find(a) == egraph.find(b);
+    let agrees = |s: &Subst| {
+        let vars = s.vec.iter().all(|&(v, id)| match subst.get(v) {
+            Some(&x) => same(x, id),
+            None => true,
+        });
+        let seqs = s.seqs.iter().all(|(v, ids)| match subst.get_seq(*v) {
+            Some(xs) => xs.len() == ids.len() && xs.iter().zip(ids).all(|(&x, &id)| same(x, id)),
+            None => true,
+        });
+        vars && seqs
+    };
+    let mut machine = machine.lock().unwrap();
+    let substs = pattern.search_eclass_dirty(&mut machine, egraph, subst[var]);
+    substs.iter().any(agrees)
+}
+
+impl<L, N> Condition<L, N> for ConditionMatches<L>
+where
+    L: Language,
+    N: Analysis<L>,
+{
+    fn check(&self, egraph: &mut EGraph<L, N>, _eclass: Id, subst: &Subst) -> bool {
+        matches_in(&self.pattern, self.var, &self.machine, egraph, subst)
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        vec![self.var]
+    }
+}
+
+impl<L, N> Condition<L, N> for ConditionNotMatches<L>
+where
+    L: Language,
+    N: Analysis<L>,
+{
+    fn check(&self, egraph: &mut EGraph<L, N>, _eclass: Id, subst: &Subst) -> bool {
+        !matches_in(&self.pattern, self.var, &self.machine, egraph, subst)
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        vec![self.var]
+    }
+}
+
+impl<L: Language + Display> Display for ConditionMatches<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "match {:?} in {}", self.pattern.to_string(), self.var)
+    }
+}
+
+impl<L: Language + Display> Display for ConditionNotMatches<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "!match {:?} in {}", self.pattern.to_string(), self.var)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -588,4 +841,53 @@ mod tests {
         fold_add.run(&mut egraph);
         assert_eq!(egraph.equivs(&start, &goal), vec![egraph.find(root)]);
     }
+
+    #[test]
+    fn match_conditions() {
+        crate::init_logger();
+        let mut egraph = EGraph::default();
+        egraph.add_expr(&"(f x (g x) (g y))".parse().unwrap());
+        egraph.rebuild();
+
+        let cond: ConditionMatches<S> = ConditionMatches::parse("(g ?a)", "?b");
+        assert_eq!(cond.to_string(), r#"match "(g ?a)" in ?b"#);
+        let cond: ConditionNotMatches<S> = ConditionNotMatches::parse("?a", "?b");
+        assert_eq!(cond.to_string(), r#"!match "?a" in ?b"#);
+
+        let rules: Vec<Rewrite<S, ()>> = vec![
+            rewrite!("same"; "(f ?a ?b ?c)" => "same" if match "(g ?a)" in ?b),
+            rewrite!("other"; "(f ?a ?b ?c)" => "other" if !match "(g ?a)" in ?c),
+            rewrite!("never"; "(f ?a ?b ?c)" => "never" if !match "(g ?d)" in ?b),
+            rewrite!("both"; "(f ?a ?b ?c)" => "both"
+                if match "(g ?d)" in ?c
+                if !match "?b" in ?c),
+        ];
+
+        let runner = Runner::default().with_egraph(egraph).run(&rules);
+        let f = runner
+            .egraph
+            .lookup_expr(&"(f x (g x) (g y))".parse().unwrap());
+        for (goal, found) in [
+            ("same", true),
+            ("other", true),
+            ("never", false),
+            ("both", true),
+        ] {
+            let id = runner.egraph.lookup_expr(&goal.parse().unwrap());
+            assert_eq!(id.is_some() && id == f, found, "{}", goal);
+        }
+
+        // the condition's machine is reused, but each check sees the
+        // e-graph as it is then
+        let mut egraph = runner.egraph;
+        let z = egraph.add(S::leaf("z"));
+        let mut subst = Subst::default();
+        subst.insert("?b".parse().unwrap(), z);
+        let cond: ConditionMatches<S> = ConditionMatches::parse("(g ?a)", "?b");
+        assert!(!cond.check(&mut egraph, z, &subst));
+        let gy = egraph.lookup_expr(&"(g y)".parse().unwrap()).unwrap();
+        egraph.union(z, gy);
+        egraph.rebuild();
+        assert!(cond.check(&mut egraph, z, &subst));
+    }
 }
@@ -0,0 +1,541 @@
+use std::fmt::Display;
+
+use thiserror::Error;
+
+use crate::*;
+
+type DynCondition<L, N> = Box<dyn Condition<L, N> + Send + Sync>;
+type DynApplier<L, N> = Box<dyn Applier<L, N> + Send + Sync>;
+type MakeCondition<L, N> = Box<dyn Fn(&[RuleArg<L>]) -> Result<DynCondition<L, N>, String>>;
+type MakeApplier<L, N> = Box<dyn Fn(&[RuleArg<L>]) -> Result<DynApplier<L, N>, String>>;
+type TaggedRules<L, N> = Vec<(Rewrite<L, N>, Vec<String>)>;
+
+/** Reads [`Rewrite`]s from a text rule file.
+
+A rule file has one rule per line, written `name: lhs => rhs`, and
+any number of `if` conditions after it.
+Lines indented further than a rule continue it, and `#` starts a comment.
+`name: lhs <=> rhs` adds the rule in both directions, naming the
+reverse one `name-rev` like the [`rewrite!`] macro does.
+
+The conditions are
+- `if match pat in ?x` and `if !match pat in ?x`
+  ([`ConditionMatches`] and [`ConditionNotMatches`]),
+- `if pat1 == pat2` ([`ConditionEqual`]),
+- `if name(args...)` for a condition registered with
+  [`with_condition`](RuleRegistry::with_condition()).
+
+The right-hand side may also be `name(args...)` for an applier registered
+with [`with_applier`](RuleRegistry::with_applier()).
+Arguments are separated by commas, and each is a variable or a pattern.
+
+A line `[tag1 tag2]` tags the rules after it, up to the next such line,
+so [`parse_tagged_rules`](RuleRegistry::parse_tagged_rules()) can
+select a rule set from a larger file.
+
+# Example
+```
+use egg::{*, SymbolLang as S};
+
+let registry = RuleRegistry::<S, ()>::default().with_condition("is_not", |args| {
+    let (x, name) = match args {
+        [RuleArg::Var(x), RuleArg::Pattern(p)] => (*x, p.to_string()),
+        _ => return Err("expected a variable and a symbol".to_owned()),
+    };
+    let node = S::leaf(name);
+    Ok(move |egraph: &mut EGraph<S, ()>, _, subst: &Subst| {
+        !egraph[subst[x]].nodes.contains(&node)
+    })
+});
+
+let text = "
+[arith]
+commute-add: (+ ?a ?b) => (+ ?b ?a)
+add-0: (+ ?a 0) <=> ?a
+div-self: (/ ?a ?a) => 1
+    if is_not(?a, 0)
+
+[bool]
+and-true: (and ?a true) => ?a   # a comment
+";
+assert_eq!(registry.parse_rules(text).unwrap().len(), 5);
+assert_eq!(registry.parse_tagged_rules(text, "arith").unwrap().len(), 4);
+
+let err = registry.parse_rules("bad: (+ ?a 0) => ?a if is_zero(?a)").unwrap_err();
+assert_eq!(err.to_string(), "line 1: rule bad: unknown condition is_zero");
+```
+**/
+pub struct RuleRegistry<L, N> {
+    conditions: HashMap<String, MakeCondition<L, N>>,
+    appliers: HashMap<String, MakeApplier<L, N>>,
+}
+
+impl<L, N> Default for RuleRegistry<L, N> {
+    fn default() -> Self {
+        Self {
+            conditions: Default::default(),
+            appliers: Default::default(),
+        }
+    }
+}
+
+/// An argument to a registered condition or applier in a rule file.
+#[derive(Debug, Clone)]
+pub enum RuleArg<L> {
+    /// A pattern variable like `?x`.
+    Var(Var),
+    /// Any other pattern.
+    Pattern(Pattern<L>),
+}
+
+impl<L: Language + Display> RuleArg<L> {
+    /// Returns the variable, or an error if this is a pattern.
+    pub fn var(&self) -> Result<Var, String> {
+        match self {
+            RuleArg::Var(v) => Ok(*v),
+            RuleArg::Pattern(p) => Err(format!("expected a variable, found {}", p)),
+        }
+    }
+
+    /// Returns this argument as a pattern; a variable is a pattern too.
+    pub fn pattern(&self) -> Pattern<L> {
+        match self {
+            RuleArg::Var(v) => Pattern::new(vec![ENodeOrVar::Var(*v)].into()),
+            RuleArg::Pattern(p) => p.clone(),
+        }
+    }
+}
+
+/// An error in a rule file.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("line {line}: {message}")]
+pub struct RuleFileError {
+    /// 1-based line of the error
+    pub line: usize,
+    /// What went wrong
+    pub message: String,
+}
+
+// lets boxed conditions and appliers be used where a type is expected
+struct Boxed<T: ?Sized>(Box<T>);
+
+impl<L, N> Condition<L, N> for Boxed<dyn Condition<L, N> + Send + Sync>
+where
+    L: Language,
+    N: Analysis<L>,
+{
+    fn check(&self, egraph: &mut EGraph<L, N>, eclass: Id, subst: &Subst) -> bool {
+        self.0.check(egraph, eclass, subst)
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        self.0.vars()
+    }
+}
+
+impl<L, N> Applier<L, N> for Boxed<dyn Applier<L, N> + Send + Sync>
+where
+    L: Language,
+    N: Analysis<L>,
+{
+    fn apply_matches(
+        &self,
+        egraph: &mut EGraph<L, N>,
+        matches: &[SearchMatches<L>],
+        rule_name: Symbol,
+    ) -> Vec<Id> {
+        self.0.apply_matches(egraph, matches, rule_name)
+    }
+
+    fn get_pattern_ast(&self) -> Option<&PatternAst<L>> {
+        self.0.get_pattern_ast()
+    }
+
+    fn apply_one(
+        &self,
+        egraph: &mut EGraph<L, N>,
+        eclass: Id,
+        subst: &Subst,
+        searcher_ast: Option<&PatternAst<L>>,
+        rule_name: Symbol,
+    ) -> Vec<Id> {
+        self.0
+            .apply_one(egraph, eclass, subst, searcher_ast, rule_name)
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        self.0.vars()
+    }
+
+    fn is_conditional(&self) -> bool {
+        self.0.is_conditional()
+    }
+}
+
+// splits text at whitespace, keeping parenthesized groups whole
+fn items(text: &str) -> Result<Vec<&str>, String> {
+    let mut items = vec![];
+    let mut depth = 0usize;
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() && depth == 0 {
+            items.extend(start.take().map(|s| &text[s..i]));
+            continue;
+        }
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.checked_sub(1).ok_or("unbalanced `)`")?,
+            _ => (),
+        }
+        start.get_or_insert(i);
+    }
+    if depth > 0 {
+        return Err("unbalanced `(`".into());
+    }
+    items.extend(start.map(|s| &text[s..]));
+    Ok(items)
+}
+
+// splits `name(a, b)` into its name and arguments
+fn call(item: &str) -> Option<(&str, Vec<&str>)> {
+    let open = item.find('(')?;
+    let inner = item[open + 1..].strip_suffix(')')?;
+    if open == 0 {
+        return None;
+    }
+    let mut args = vec![];
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(inner[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    if !inner.trim().is_empty() {
+        args.push(inner[start..].trim());
+    }
+    Some((&item[..open], args))
+}
+
+fn pattern<L: FromOp>(text: &str) -> Result<Pattern<L>, String>
+where
+    L::Error: Display,
+{
+    text.parse()
+        .map_err(|e| format!("bad pattern {}: {}", text, e))
+}
+
+fn var(text: &str) -> Result<Var, String> {
+    text.parse()
+        .map_err(|e| format!("bad variable {}: {}", text, e))
+}
+
+fn arg<L: FromOp>(text: &str) -> Result<RuleArg<L>, String>
+where
+    L::Error: Display,
+{
+    if text.starts_with('?') && !text.contains(char::is_whitespace) {
+        var(text).map(RuleArg::Var)
+    } else {
+        pattern(text).map(RuleArg::Pattern)
+    }
+}
+
+impl<L, N> RuleRegistry<L, N>
+where
+    L: Language + FromOp + Send + Sync + 'static,
+    L::Error: Display,
+    N: Analysis<L> + 'static,
+{
+    /// Registers a condition that rule files can use as `if name(args...)`.
+    ///
+    /// `make` builds the condition from the arguments, or returns an
+    /// error message if they don't fit.
+    pub fn with_condition<C>(
+        mut self,
+        name: &str,
+        make: impl Fn(&[RuleArg<L>]) -> Result<C, String> + 'static,
+    ) -> Self
+    where
+        C: Condition<L, N> + Send + Sync + 'static,
+    {
+        let make = move |args: &[RuleArg<L>]| make(args).map(|c| Box::new(c) as DynCondition<L, N>);
+        self.conditions.insert(name.to_owned(), Box::new(make));
+        self
+    }
+
+    /// Registers an applier that rule files can use as the right-hand
+    /// side `name(args...)`.
+    ///
+    /// `make` builds the applier from the arguments, or returns an
+    /// error message if they don't fit.
+    pub fn with_applier<A>(
+        mut self,
+        name: &str,
+        make: impl Fn(&[RuleArg<L>]) -> Result<A, String> + 'static,
+    ) -> Self
+    where
+        A: Applier<L, N> + Send + Sync + 'static,
+    {
+        let make = move |args: &[RuleArg<L>]| make(args).map(|a| Box::new(a) as DynApplier<L, N>);
+        self.appliers.insert(name.to_owned(), Box::new(make));
+        self
+    }
+
+    /// Parses all of the rules in a rule file.
+    pub fn parse_rules(&self, text: &str) -> Result<Vec<Rewrite<L, N>>, RuleFileError> {
+        let rules = self.parse(text)?;
+        Ok(rules.into_iter().map(|(rule, _)| rule).collect())
+    }
+
+    /// Parses the rules of a rule file that are tagged with `tag`.
+    pub fn parse_tagged_rules(
+        &self,
+        text: &str,
+        tag: &str,
+    ) -> Result<Vec<Rewrite<L, N>>, RuleFileError> {
+        let rules = self.parse(text)?;
+        let tagged = rules
+            .into_iter()
+            .filter(|(_, tags)| tags.iter().any(|t| t == tag));
+        Ok(tagged.map(|(rule, _)| rule).collect())
+    }
+
+    // the rules of a rule file, with their tags
+    fn parse(&self, text: &str) -> Result<TaggedRules<L, N>, RuleFileError> {
+        // each rule with its line, name and text so far
+        let mut raw: Vec<(usize, &str, String, Vec<String>)> = vec![];
+        let mut tags: Vec<String> = vec![];
+        for (i, text) in text.lines().enumerate() {
+            let line = i + 1;
+            let error = |message: String| RuleFileError { line, message };
+            let text = text.split('#').next().unwrap();
+            if text.trim().is_empty() {
+                continue;
+            }
+            if text.starts_with(char::is_whitespace) {
+                match raw.last_mut() {
+                    Some((_, _, body, _)) => {
+                        body.push(' ');
+                        body.push_str(text.trim());
+                    }
+                    None => return Err(error("indented line outside of a rule".into())),
+                }
+            } else if let Some(header) = text.trim().strip_prefix('[') {
+                let header = header
+                    .strip_suffix(']')
+                    .ok_or_else(|| error("expected a closing `]` after the tags".into()))?;
+                tags = header.split_whitespace().map(str::to_owned).collect();
+            } else {
+                let (name, body) = text
+                    .split_once(':')
+                    .ok_or_else(|| error("expected `name: lhs => rhs`".into()))?;
+                let name = name.trim();
+                if name.is_empty() || name.contains(char::is_whitespace) {
+                    return Err(error(format!("bad rule name {:?}", name)));
+                }
+                if raw.iter().any(|r| r.1 == name) {
+                    return Err(error(format!("duplicate rule {}", name)));
+                }
+                raw.push((line, name, body.to_owned(), tags.clone()));
+            }
+        }
+
+        let mut rules = vec![];
+        for (line, name, body, tags) in raw {
+            let error = |message: String| RuleFileError {
+                line,
+                message: format!("rule {}: {}", name, message),
+            };
+            for rule in self.parse_rule(name, &body).map_err(error)? {
+                rules.push((rule, tags.clone()));
+            }
+        }
+        Ok(rules)
+    }
+
+    fn parse_rule(&self, name: &str, body: &str) -> Result<Vec<Rewrite<L, N>>, String> {
+        let items = items(body)?;
+        let (lhs, arrow, rhs, conditions) = match items.as_slice() {
+            [lhs, arrow, rhs, conditions @ ..] => (*lhs, *arrow, *rhs, conditions),
+            _ => return Err("expected `lhs => rhs`".into()),
+        };
+        match arrow {
+            "=>" => Ok(vec![self.rewrite(name, lhs, rhs, conditions)?]),
+            "<=>" => {
+                let rev = format!("{}-rev", name);
+                Rewrite::paired(
+                    self.rewrite(name, lhs, rhs, conditions)?,
+                    self.rewrite(&rev, rhs, lhs, conditions)?,
+                )
+            }
+            _ => Err(format!("expected `=>` or `<=>`, found {}", arrow)),
+        }
+    }
+
+    fn rewrite(
+        &self,
+        name: &str,
+        lhs: &str,
+        rhs: &str,
+        conditions: &[&str],
+    ) -> Result<Rewrite<L, N>, String> {
+        let searcher: Pattern<L> = pattern(lhs)?;
+        let mut applier: DynApplier<L, N> = match call(rhs) {
+            Some((name, args)) => {
+                let make = self
+                    .appliers
+                    .get(name)
+                    .ok_or_else(|| format!("unknown applier {}", name))?;
+                let args = args.into_iter().map(arg).collect::<Result<Vec<_>, _>>()?;
+                make(&args)?
+            }
+            None => Box::new(pattern::<L>(rhs)?),
+        };
+
+        let mut parsed = vec![];
+        let mut rest = conditions;
+        while !rest.is_empty() {
+            let (condition, next) = self.condition(rest)?;
+            parsed.push(condition);
+            rest = next;
+        }
+        // the first condition is the outermost, as in rewrite!
+        for condition in parsed.into_iter().rev() {
+            applier = Box::new(ConditionalApplier {
+                condition: Boxed(condition),
+                applier: Boxed(applier),
+            });
+        }
+        Rewrite::new(name, searcher, Boxed(applier))
+    }
+
+    // parses the condition at the start of items, returning the rest
+    fn condition<'a>(
+        &self,
+        items: &'a [&'a str],
+    ) -> Result<(DynCondition<L, N>, &'a [&'a str]), String> {
+        match items {
+            ["if", "match", pat, "in", v, rest @ ..] => Ok((
+                Box::new(ConditionMatches::new(pattern(pat)?, var(v)?)),
+                rest,
+            )),
+            ["if", "!match", pat, "in", v, rest @ ..] => Ok((
+                Box::new(ConditionNotMatches::new(pattern(pat)?, var(v)?)),
+                rest,
+            )),
+            ["if", p1, "==", p2, rest @ ..] => {
+                let cond = ConditionEqual::new(pattern(p1)?, pattern(p2)?);
+                Ok((Box::new(cond), rest))
+            }
+            ["if", item, rest @ ..] => {
+                let (name, args) = call(item).ok_or_else(|| format!("bad condition {}", item))?;
+                let make = self
+                    .conditions
+                    .get(name)
+                    .ok_or_else(|| format!("unknown condition {}", name))?;
+                let args = args.into_iter().map(arg).collect::<Result<Vec<_>, _>>()?;
+                Ok((make(&args)?, rest))
+            }
+            [item, ..] => Err(format!("expected `if`, found {}", item)),
+            [] => Err("expected a condition after `if`".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{SymbolLang as S, *};
+
+    fn registry() -> RuleRegistry<S, ()> {
+        RuleRegistry::default()
+            .with_condition("is_leaf", |args| {
+                let x = match args {
+                    [arg] => arg.var()?,
+                    _ => return Err("expected one argument".into()),
+                };
+                Ok(move |egraph: &mut EGraph<S, ()>, _, subst: &Subst| {
+                    egraph[subst[x]].nodes.iter().any(|n| n.is_leaf())
+                })
+            })
+            .with_applier("pattern", |args| match args {
+                [arg] => Ok(arg.pattern()),
+                _ => Err("expected one argument".into()),
+            })
+    }
+
+    #[test]
+    fn rule_file() {
+        let text = "
+            # not a rule
+        ";
+        assert!(registry().parse_rules(text).unwrap().is_empty());
+
+        let text = "
+[a]
+comm: (f ?x ?y) => (f ?y ?x)   # a comment
+both: (g ?x) <=> (h ?x)
+    if is_leaf(?x)
+[b c]
+leaf: (k ?x (k ?y)) => pattern((k ?y ?x))
+    if !match ?x in ?y
+    if (k ?x) == (k ?y)
+";
+        let registry = registry();
+        let rules = registry.parse_rules(text).unwrap();
+        let names: Vec<String> = rules.iter().map(|r| r.name.to_string()).collect();
+        assert_eq!(names, ["comm", "both", "both-rev", "leaf"]);
+        assert_eq!(rules[2].axiom, Some("both".into()));
+        assert_eq!(registry.parse_tagged_rules(text, "a").unwrap().len(), 3);
+        assert_eq!(registry.parse_tagged_rules(text, "c").unwrap().len(), 1);
+
+        let runner = Runner::default()
+            .with_expr(&"(g (f a b))".parse().unwrap())
+            .with_expr(&"(g a)".parse().unwrap())
+            .run(&rules);
+        let egraph = &runner.egraph;
+        let lookup = |s: &str| egraph.lookup_expr(&s.parse().unwrap());
+        assert_eq!(lookup("(g (f a b))"), lookup("(g (f b a))"));
+        assert_eq!(lookup("(h a)"), lookup("(g a)"));
+        assert_eq!(lookup("(h (f a b))"), None);
+    }
+
+    #[test]
+    fn rule_file_errors() {
+        let error = |text: &str| registry().parse_rules(text).unwrap_err().to_string();
+        assert_eq!(
+            error("\n\n  (f ?x) => ?x"),
+            "line 3: indented line outside of a rule"
+        );
+        assert_eq!(
+            error("r: (f ?x) -> ?x"),
+            "line 1: rule r: expected `=>` or `<=>`, found ->"
+        );
+        assert_eq!(error("r: (f ?x => ?x"), "line 1: rule r: unbalanced `(`");
+        assert_eq!(
+            error("r: (f ?x) => ?y"),
+            "line 1: rule r: Rewrite r refers to unbound var ?y"
+        );
+        assert_eq!(
+            error("r: (f ?x) => ?x\nr: ?x => (f ?x)"),
+            "line 2: duplicate rule r"
+        );
+        assert_eq!(
+            error("r: (f ?x) => ?x if is_leaf(?x, ?x)"),
+            "line 1: rule r: expected one argument"
+        );
+        assert_eq!(
+            error("r: (f ?x) => ?x if is_leaf((f ?x))"),
+            "line 1: rule r: expected a variable, found (f ?x)"
+        );
+        assert_eq!(
+            error("r: (f ?x) => ?x\n  if\n  is_leaf(?x) is_leaf(?x)"),
+            "line 1: rule r: expected `if`, found is_leaf(?x)"
+        );
+    }
+}
@@ -158,6 +158,10 @@ pub struct Runner<L: Language, N: Analysis<L>, IterData = ()> {
     node_limit: usize,
     time_limit: Duration,
 
+    gc_every: Option<usize>,
+    record_stats: Option<Box<dyn Fn(&EGraph<L, N>) -> EGraphStats>>,
+    #[allow(clippy::type_complexity)]
+    analysis_check: Option<Box<dyn Fn(&EGraph<L, N>) -> Result<(), AnalysisError<L>>>>,
     start_time: Option<Instant>,
     scheduler: Box<dyn RewriteScheduler<L, N>>,
 }
@@ -189,6 +193,9 @@ where
             iter_limit,
             node_limit,
             time_limit,
+            gc_every,
+            record_stats,
+            analysis_check,
             start_time,
             scheduler: _,
         } = self;
@@ -202,6 +209,9 @@ where
             .field("iter_limit", iter_limit)
             .field("node_limit", node_limit)
             .field("time_limit", time_limit)
+            .field("gc_every", gc_every)
+            .field("record_stats", &record_stats.as_ref().map(|_| format_args!("<dyn Fn ..>")))
+            .field("analysis_check", &analysis_check.as_ref().map(|_| format_args!("<dyn Fn ..>")))
             .field("start_time", start_time)
             .field("scheduler", &format_args!("<dyn RewriteScheduler ..>"))
             .finish()
@@ -303,6 +313,9 @@ pub struct Iteration<IterData> {
     pub n_rebuilds: usize,
     /// If the runner stopped on this iterations, this is the reason
     pub stop_reason: Option<StopReason>,
+    /// The [`EGraph::stats`] at the end of this iteration, if the runner
+    /// was made [`with_stats`](Runner::with_stats).
+    pub stats: Option<EGraphStats>,
 }
 
 type RunnerResult<T> = std::result::Result<T, StopReason>;
@@ -326,6 +339,9 @@ where
             stop_reason: None,
             hooks: vec![],
 
+            gc_every: None,
+            record_stats: None,
+            analysis_check: None,
             start_time: None,
 
             // TODO:  Plug in other RewriteSchedulers here as necessary!
@@ -348,6 +364,52 @@ where
         Self { time_limit, ..self }
     }
 
+    /// Deletes the e-classes that no [`root`](Runner::roots) can reach
+    /// after every `n` iterations, see [`EGraph::retain_reachable`].
+    /// This renumbers the e-classes, and
+    /// [`roots`](Runner::roots) along with them.
+    /// [`run`](Runner::run) panics if explanations are enabled too.
+    /// Default: never
+    pub fn with_gc_every(self, n: usize) -> Self {
+        assert!(n > 0, "Can't collect garbage every 0 iterations");
+        Self { gc_every: Some(n), ..self }
+    }
+
+    /// Records the [`EGraph::stats`] at the end of each iteration in
+    /// [`Iteration::stats`].
+    /// Default: off
+    pub fn with_stats(self) -> Self
+    where
+        L: std::fmt::Display,
+    {
+        let stats = |egraph: &EGraph<L, N>| egraph.stats();
+        Self { record_stats: Some(Box::new(stats)), ..self }
+    }
+
+    /// Checks the analysis data with [`EGraph::check_analysis`] after
+    /// every rebuild, panicking if it's wrong.
+    /// This is slow, so it's meant for debugging an [`Analysis`].
+    /// Default: off
+    pub fn with_analysis_checks(self) -> Self
+    where
+        N: Clone,
+        N::Data: Clone + PartialEq,
+    {
+        self.with_analysis_checks_by(|data, made| data == made)
+    }
+
+    /// Like [`with_analysis_checks`](Runner::with_analysis_checks), but
+    /// checks with [`EGraph::check_analysis_by`].
+    pub fn with_analysis_checks_by<F>(self, precise: F) -> Self
+    where
+        N: Clone,
+        N::Data: Clone + PartialEq,
+        F: Fn(&N::Data, &N::Data) -> bool + 'static,
+    {
+        let check = move |egraph: &EGraph<L, N>| egraph.check_analysis_by(&precise);
+        Self { analysis_check: Some(Box::new(check)), ..self }
+    }
+
     /// Add a hook to instrument or modify the behavior of a [`Runner`].
     /// Each hook will run at the beginning of each iteration, i.e. before
     /// all the rewrites.
@@ -379,6 +441,38 @@ where
         self
     }
 
+    /// Applies the `rules` in the context `ctx` at the beginning of each
+    /// iteration, with a hook that calls [`EGraph::apply_in`] and rebuilds.
+    ///
+    /// # Example
+    /// ```
+    /// # use egg::*;
+    /// let mut egraph = EGraph::<SymbolLang, ()>::default();
+    /// let fx = egraph.add_expr(&"(f x)".parse().unwrap());
+    /// let x = egraph.lookup(SymbolLang::leaf("x")).unwrap();
+    /// let ctx = egraph.with_assumption(x, fx);
+    ///
+    /// let runner = Runner::default()
+    ///     .with_egraph(egraph)
+    ///     .with_rules_in(ctx, vec![rewrite!("f-g"; "(f ?a)" => "(g ?a)")])
+    ///     .run(&[]);
+    /// let gx = runner.egraph.lookup_expr(&"(g x)".parse().unwrap()).unwrap();
+    /// assert_eq!(runner.egraph.find_in(ctx, gx), runner.egraph.find_in(ctx, x));
+    /// assert_ne!(runner.egraph.find(gx), runner.egraph.find(x));
+    /// ```
+    pub fn with_rules_in(self, ctx: ContextId, rules: Vec<Rewrite<L, N>>) -> Self
+    where
+        L: 'static,
+        N: 'static,
+    {
+        self.with_hook(move |runner| {
+            let rules: Vec<&Rewrite<L, N>> = rules.iter().collect();
+            runner.egraph.apply_in(ctx, &rules);
+            runner.egraph.rebuild();
+            Ok(())
+        })
+    }
+
     /// Change out the [`RewriteScheduler`] used by this [`Runner`].
     /// The default one is [`BackoffScheduler`].
     ///
@@ -416,7 +510,16 @@ where
         let mut itr_ct = 0;
         let rules: Vec<&Rewrite<L, N>> = rules.into_iter().collect();
         check_rules(&rules);
+        if self.egraph.are_explanations_enabled() {
+            assert!(
+                self.gc_every.is_none(),
+                "Can't collect garbage with explanations enabled"
+            );
+            check_explainable(&rules);
+        }
+        let mut since_gc = 0;
         self.egraph.rebuild();
+        self.check_analysis();
         loop {
             let iter = self.run_one_dfs(&rules);  // This line dictates if BFS or DFS.  Use run_one for BFS and run_one_dfs for DFS.
             self.iterations.push(iter);
@@ -428,6 +531,13 @@ where
                 self.stop_reason = Some(stop_reason);
                 break;
             }
+            if let Some(n) = self.gc_every {
+                since_gc += 1;
+                if since_gc == n {
+                    since_gc = 0;
+                    self.collect_garbage();
+                }
+            }
         }
 
         assert!(!self.iterations.is_empty());
@@ -572,6 +682,7 @@ where
         // restore invariant by rebuilding
         let rebuild_time = Instant::now();
         let n_rebuilds = self.egraph.rebuild();
+        self.check_analysis();
         if self.egraph.are_explanations_enabled() {
             debug_assert!(self.egraph.check_each_explain(rules));
         }
@@ -605,6 +716,7 @@ where
             data: IterData::make(self),
             total_time: start_time.elapsed().as_secs_f64(),
             stop_reason: result.err(),
+            stats: self.record_stats.as_ref().map(|stats| stats(&self.egraph)),
         }
     }
 
@@ -674,6 +786,7 @@ where
                 let rebuild_start_time = Instant::now();
                 n_rebuilds += self.egraph.rebuild();
                 rebuild_time += rebuild_start_time.elapsed().as_secs_f64();
+                self.check_analysis();
 
                 self.check_limits()
             })
@@ -715,6 +828,26 @@ where
             data: IterData::make(self),
             total_time: overall_start_time.elapsed().as_secs_f64(),
             stop_reason: result.err(),
+            stats: self.record_stats.as_ref().map(|stats| stats(&self.egraph)),
+        }
+    }
+
+    fn collect_garbage(&mut self) {
+        if !self.egraph.clean {
+            self.egraph.rebuild();
+        }
+        let ids = self.egraph.retain_reachable(&self.roots);
+        for root in &mut self.roots {
+            *root = ids[*root];
+        }
+        self.check_analysis();
+    }
+
+    fn check_analysis(&self) {
+        if let Some(check) = &self.analysis_check {
+            if let Err(err) = check(&self.egraph) {
+                panic!("Analysis check failed: {}", err);
+            }
         }
     }
 
@@ -741,7 +874,30 @@ where
     }
 }
 
-fn check_rules<L, N>(rules: &[&Rewrite<L, N>]) {
+/// Panics if a rule uses sequence variables or matches AC operators,
+/// which explanations don't support.
+fn check_explainable<L: Language, N: Analysis<L>>(rules: &[&Rewrite<L, N>]) {
+    for rw in rules {
+        let mut vars = rw.searcher.vars();
+        vars.extend(rw.applier.vars());
+        if let Some(v) = vars.iter().find(|v| v.is_sequence()) {
+            panic!(
+                "Rule {} uses sequence variable {}, but explanations are enabled",
+                rw.name, v
+            );
+        }
+        let ast = rw.searcher.get_pattern_ast();
+        let nodes = ast.map_or(&[][..], |ast| ast.as_ref());
+        if nodes.iter().any(|n| matches!(n, ENodeOrVar::ENode(n) if n.is_ac())) {
+            panic!(
+                "Rule {} matches an AC operator, but explanations are enabled",
+                rw.name
+            );
+        }
+    }
+}
+
+fn check_rules<L: Language, N: Analysis<L>>(rules: &[&Rewrite<L, N>]) {
     let mut name_counts = IndexMap::default();
     for rw in rules {
         *name_counts.entry(rw.name).or_default() += 1
@@ -757,6 +913,10 @@ fn check_rules<L, N>(rules: &[&Rewrite<L, N>]) {
             log::warn!("Rule '{}' appears {} times", name, count);
         }
     }
+
+    for lint in lint_rules(rules.iter().copied()) {
+        log::warn!("{}", lint);
+    }
 }
 
 /** A way to customize how a [`Runner`] runs [`Rewrite`]s.
@@ -9,6 +9,11 @@ use thiserror::Error;
 ///
 /// This implements [`FromStr`], and will only parse if it has a
 /// leading `?`.
+/// A trailing `...`, as in `?xs...`, makes it a sequence variable that
+/// matches any number of consecutive children of an enode
+/// (see [`Var::is_sequence`]).
+/// In a [`Pattern`], a variable may also carry [`Annotation`]s, as in
+/// `?x:Num` or `?x:<pred>`.
 ///
 /// [`FromStr`]: std::str::FromStr
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -18,6 +23,10 @@ pub struct Var(Symbol);
 pub enum VarParseError {
     #[error("pattern variable {0:?} should have a leading question mark")]
     MissingQuestionMark(String),
+    #[error("sequence variable {0:?} should have a name before the dots")]
+    UnnamedSequence(String),
+    #[error("pattern variable {0:?} should be annotated like ?x:Variant or ?x:<predicate>")]
+    BadAnnotation(String),
 }
 
 impl FromStr for Var {
@@ -27,6 +36,15 @@ impl FromStr for Var {
         use VarParseError::*;
 
         if s.starts_with('?') && s.len() > 1 {
+            if s == "?..." {
+                return Err(UnnamedSequence(s.to_owned()));
+            }
+            if let Some((var, annotations)) = s.split_once(':') {
+                let bad = |a| Annotation::parse(a).is_none();
+                if var.len() < 2 || var.ends_with("...") || annotations.split(':').any(bad) {
+                    return Err(BadAnnotation(s.to_owned()));
+                }
+            }
             Ok(Var(s.into()))
         } else {
             Err(MissingQuestionMark(s.to_owned()))
@@ -34,6 +52,34 @@ impl FromStr for Var {
     }
 }
 
+impl Var {
+    /// Returns true if this is a sequence variable like `?xs...`.
+    ///
+    /// A sequence variable may only be a child of a pattern enode.
+    /// It matches any run of that enode's children, including an empty one,
+    /// and is bound with [`Subst::insert_seq`] rather than [`Subst::insert`].
+    pub fn is_sequence(&self) -> bool {
+        self.0.as_str().ends_with("...")
+    }
+
+    /// Splits an annotated variable like `?x:Num` into `?x` and its annotations.
+    pub(crate) fn annotations(&self) -> Option<(Var, Vec<Annotation>)> {
+        let (var, annotations) = self.0.as_str().split_once(':')?;
+        // checked when parsing
+        let annotations = annotations.split(':').filter_map(Annotation::parse);
+        Some((Var(var.into()), annotations.collect()))
+    }
+
+    /// The inverse of [`Var::annotations`].
+    pub(crate) fn annotated(&self, annotations: &[Annotation]) -> Var {
+        let mut s = self.to_string();
+        for a in annotations {
+            s += &format!(":{}", a);
+        }
+        Var(s.into())
+    }
+}
+
 impl Display for Var {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         Display::fmt(&self.0, f)
@@ -46,11 +92,13 @@ impl Debug for Var {
     }
 }
 
-/// A substitition mapping [`Var`]s to eclass [`Id`]s.
+/// A substitition mapping [`Var`]s to eclass [`Id`]s,
+/// and sequence variables to lists of them.
 ///
 #[derive(Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Subst {
     pub(crate) vec: smallvec::SmallVec<[(Var, Id); 3]>,
+    pub(crate) seqs: Vec<(Var, Vec<Id>)>,
 }
 
 impl Subst {
@@ -58,6 +106,7 @@ impl Subst {
     pub fn with_capacity(capacity: usize) -> Self {
         Self {
             vec: smallvec::SmallVec::with_capacity(capacity),
+            seqs: vec![],
         }
     }
 
@@ -79,6 +128,26 @@ impl Subst {
             .iter()
             .find_map(|(v, id)| if *v == var { Some(id) } else { None })
     }
+
+    /// Insert the sequence bound to a sequence variable,
+    /// returning the old one if present.
+    pub fn insert_seq(&mut self, var: Var, ids: Vec<Id>) -> Option<Vec<Id>> {
+        for pair in &mut self.seqs {
+            if pair.0 == var {
+                return Some(std::mem::replace(&mut pair.1, ids));
+            }
+        }
+        self.seqs.push((var, ids));
+        None
+    }
+
+    /// Retrieve the sequence bound to a sequence variable,
+    /// returning `None` if not present.
+    pub fn get_seq(&self, var: Var) -> Option<&[Id]> {
+        self.seqs
+            .iter()
+            .find_map(|(v, ids)| if *v == var { Some(&ids[..]) } else { None })
+    }
 }
 
 impl std::ops::Index<Var> for Subst {
@@ -103,6 +172,12 @@ impl Debug for Subst {
                 write!(f, ", ")?;
             }
         }
+        for (i, (var, ids)) in self.seqs.iter().enumerate() {
+            if len > 0 || i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {:?}", var, ids)?;
+        }
         write!(f, "}}")
     }
 }
@@ -118,5 +193,17 @@ mod tests {
         assert!(Var::from_str("a").is_err());
         assert!(Var::from_str("a?").is_err());
         assert!(Var::from_str("?").is_err());
+        assert!(Var::from_str("?xs...").unwrap().is_sequence());
+        assert!(!Var::from_str("?xs").unwrap().is_sequence());
+        assert!(Var::from_str("?...").is_err());
+
+        let annotated = Var::from_str("?x:Num:<pred>").unwrap();
+        let (x, annotations) = annotated.annotations().unwrap();
+        assert_eq!(x, Var::from_str("?x").unwrap());
+        assert_eq!(x.annotated(&annotations).to_string(), "?x:Num:<pred>");
+        assert!(Var::from_str("?x:").is_err());
+        assert!(Var::from_str("?x:<>").is_err());
+        assert!(Var::from_str("?:Num").is_err());
+        assert!(Var::from_str("?xs...:Num").is_err());
     }
 }
@@ -1,26 +1,73 @@
 use std::collections::HashSet;
 
 use crate::udp_lang::*;
-use crate::{Analysis, DidMerge, EGraph, Id, Language};
+use crate::{merge_intersection, Analysis, DidMerge, EGraph, Id, IdMap, Language, Lattice};
 
+/// The e-class analysis for USr terms.
 #[derive(Default, Clone)]
 pub struct UAnalysis;
 
-// Metadata for each class
-#[derive(Debug, PartialEq, Eq)]
+/// Metadata for each class
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Data {
-    // Set of free variables by their class ID
+    /// Set of free variables by their class ID
     pub free: HashSet<Id>,
+    /// Value of the class if it is a constant natural number
+    pub constant: Option<i32>,
+    /// The class only takes the values 0 and 1 (predicates and squashed terms)
+    pub pred: bool,
+    /// The class is never 0
+    pub nonzero: bool,
+    /// Whether the class is a tuple, a value or a semiring element, if known
+    pub sort: Option<Sort>,
+    /// The class is a bare name, like a bound variable or an attribute,
+    /// so it is never a semiring element
+    pub name: bool,
 }
 
 impl Analysis<USr> for UAnalysis {
     type Data = Data;
 
     fn merge(&mut self, to: &mut Data, from: Data) -> DidMerge {
-        let before_len = to.free.len();
-        // to.free.extend(from.free);
-        to.free.retain(|i| from.free.contains(i));
-        DidMerge(before_len != to.free.len(), true)
+        let mut did = merge_intersection(&mut to.free, from.free);
+
+        match (to.constant, from.constant) {
+            (None, Some(_)) => {
+                to.constant = from.constant;
+                did.0 = true;
+            }
+            (Some(_), None) => did.1 = true,
+            // only an unsound rule or union gets here, so report it
+            // rather than abort the rebuild
+            (Some(a), Some(b)) if a != b => {
+                log::error!("Merged constants {} and {}, keeping {}", a, b, a);
+                did.1 = true;
+            }
+            (Some(_), Some(_)) | (None, None) => (),
+        }
+
+        // both flags are facts about the class, so either side proving one is enough
+        did = did | to.pred.join(from.pred) | to.nonzero.join(from.nonzero);
+
+        did = did | to.name.join(from.name);
+
+        // the sort guards on the rules keep values and semiring elements
+        // apart, so a merge across sorts comes from an ill-sorted term or
+        // union, like a variable used as a semiring element
+        match (to.sort, from.sort) {
+            (None, Some(_)) => {
+                to.sort = from.sort;
+                did.0 = true;
+            }
+            (Some(_), None) => did.1 = true,
+            (Some(a), Some(b)) if a != b => {
+                log::warn!("Merged {} with {}, keeping {}", a, b, a);
+                did.1 = true;
+            }
+            (Some(_), Some(_)) | (None, None) => (),
+        }
+
+        did
     }
 
     fn make(egraph: &EGraph<USr, UAnalysis>, enode: &USr) -> Data {
@@ -48,6 +95,64 @@ impl Analysis<USr> for UAnalysis {
             _ => enode.for_each(|c| free.extend(&egraph[c].data.free)),
         }
 
-        Data { free }
+        let x = |i: &Id| &egraph[*i].data;
+        let constant = match enode {
+            USr::Num(n) => Some(*n),
+            USr::Add(cs) => cs.iter().try_fold(0i32, |n, c| n.checked_add(x(c).constant?)),
+            USr::Mul(cs) => cs.iter().try_fold(1i32, |n, c| n.checked_mul(x(c).constant?)),
+            USr::Sqs(a) => x(a).constant.map(|a| (a != 0) as i32),
+            USr::Neg(a) => x(a).constant.map(|a| (a == 0) as i32),
+            _ => None,
+        };
+
+        let pred = match enode {
+            USr::Cnd(_) | USr::Sqs(_) | USr::Neg(_) => true,
+            USr::Mul(cs) => cs.iter().all(|c| x(c).pred),
+            _ => matches!(constant, Some(0) | Some(1)),
+        };
+
+        let nonzero = match enode {
+            USr::Add(cs) => cs.iter().any(|c| x(c).nonzero),
+            USr::Mul(cs) => cs.iter().all(|c| x(c).nonzero),
+            USr::Sqs(a) => x(a).nonzero,
+            _ => matches!(constant, Some(n) if n != 0),
+        };
+
+        // the sorts of names depend on where they are used, so they
+        // don't get one, but they are never semiring elements
+        let sort = Schema::sort_of(enode, |c| egraph[c].data.sort);
+        let name = matches!(enode, USr::Symbol(_));
+
+        Data { free, constant, pred, nonzero, sort, name }
+    }
+
+    fn modify(egraph: &mut EGraph<USr, UAnalysis>, id: Id) {
+        if let Some(n) = egraph[id].data.constant {
+            let folded = egraph.add(USr::Num(n));
+            egraph.union(id, folded);
+        }
+    }
+
+    // predicates for annotated pattern variables like ?x:<pred>
+    fn predicate(&self, name: &str, data: &Data) -> Option<bool> {
+        match name {
+            // x only takes the values 0 and 1, so it is idempotent and equal to its squash
+            "pred" => Some(data.pred),
+            "nonzero" => Some(data.nonzero),
+            // x is not a value or a name, so semiring identities may apply to it;
+            // variables stand for semiring elements as well as tuples, so
+            // tuples are allowed
+            "semiring" => Some(!data.name && data.sort != Some(Sort::Value)),
+            _ => None,
+        }
+    }
+
+    fn predicates() -> Vec<&'static str> {
+        vec!["pred", "nonzero", "semiring"]
     }
-}
\ No newline at end of file
+
+    // free variables are stored by class, so they follow the classes when garbage is collected
+    fn renumber(&mut self, data: &mut Data, ids: &IdMap) {
+        data.free = data.free.iter().filter_map(|&v| ids.get(v)).collect();
+    }
+}
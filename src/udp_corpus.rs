@@ -0,0 +1,468 @@
+//! Corpora of UDP equivalences with their expected outcomes, and running
+//! them as a batch.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::udp_analysis::UAnalysis;
+use crate::udp_eval::{find_counterexample, Config};
+use crate::udp_lang::USr;
+use crate::udp_rewrites::rules;
+use crate::{udp_sql, Pattern, RecExpr, Report, Rewrite, RewriteScheduler, Runner, StopReason};
+
+/// What a corpus case is expected to, or did, come to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-1", derive(serde::Serialize))]
+pub enum Outcome {
+    /// The rules proved both sides equal
+    Proved,
+    /// A counterexample database was found
+    Refuted,
+    /// The run ended without a proof or a counterexample
+    Unknown,
+    /// The run hit its time limit without a proof or a counterexample
+    Timeout,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Proved => "proved",
+            Outcome::Refuted => "refuted",
+            Outcome::Unknown => "unknown",
+            Outcome::Timeout => "timeout",
+        })
+    }
+}
+
+impl FromStr for Outcome {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "proved" => Ok(Outcome::Proved),
+            "refuted" => Ok(Outcome::Refuted),
+            "unknown" => Ok(Outcome::Unknown),
+            "timeout" => Ok(Outcome::Timeout),
+            _ => Err(format!("unknown verdict {}", s)),
+        }
+    }
+}
+
+/// One pair of terms to check
+#[derive(Clone)]
+pub struct Case {
+    /// Name of the case, unique in its corpus
+    pub name: String,
+    /// Line of the case header in the corpus file
+    pub line: usize,
+    /// The verdict the case should get
+    pub expect: Outcome,
+    /// Left-hand term
+    pub lhs: RecExpr<USr>,
+    /// Right-hand term
+    pub rhs: RecExpr<USr>,
+    /// Intermediate terms added to the e-graph as hints for the proof
+    pub steps: Vec<RecExpr<USr>>,
+    /// Rules added to [`rules`] for this case only, like key constraints
+    pub constraints: Vec<Rewrite<USr, UAnalysis>>,
+    /// Overrides the runner's iteration limit
+    pub iter_limit: Option<usize>,
+    /// Overrides the runner's node limit
+    pub node_limit: Option<usize>,
+    /// Overrides the runner's time limit
+    pub time_limit: Option<Duration>,
+}
+
+/// A list of UDP equivalence cases, usually read from a file.
+///
+/// Each case starts with an unindented `case <name>` line, followed by
+/// indented fields:
+///
+/// ```text
+/// # a comment
+/// case key-join
+///     expect proved
+///     lhs (sig t (* ([] (= (var t) (var x))) (R (var t))))
+///     rhs (R (var x))
+///     step (sig t (* ([] (= (var t) (var x))) (R (var x))))
+///     constraint KEY (* (R ?a) (R ?b)) => (* ([] (= ?a ?b)) (R ?a))
+///     iter_limit 20
+///     node_limit 5000
+///     time_limit 2.5
+/// ```
+///
+/// `lhs` and `rhs` are USr terms; `lhs.sql` and `rhs.sql` give SQL queries
+/// instead, whose output tuple is named by `output` (default `t`).
+/// Each `step` is a USr term the proof goes through, for searches that
+/// don't find it on their own.
+/// A value runs on over the following lines that are indented further than its field.
+/// `expect` is one of `proved`, `refuted`, `unknown` and `timeout`, and
+/// defaults to `proved`; `time_limit` is in seconds.
+#[derive(Clone, Default)]
+pub struct Corpus {
+    /// The cases, in file order
+    pub cases: Vec<Case>,
+}
+
+/// An error in a corpus file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorpusError {
+    /// 1-based line of the error
+    pub line: usize,
+    /// What went wrong
+    pub message: String,
+}
+
+impl fmt::Display for CorpusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for CorpusError {}
+
+// a case as written, before its terms are parsed
+#[derive(Default)]
+struct RawCase {
+    name: String,
+    line: usize,
+    fields: Vec<(usize, String, String)>,
+}
+
+fn parse_term(value: &str, sql: bool, output: &str) -> Result<RecExpr<USr>, String> {
+    if sql {
+        udp_sql::parse(value, output).map_err(|e| e.to_string())
+    } else {
+        value.parse().map_err(|e| format!("bad term: {}", e))
+    }
+}
+
+fn parse_constraint(value: &str) -> Result<Rewrite<USr, UAnalysis>, String> {
+    let (name, rule) = value
+        .split_once(char::is_whitespace)
+        .ok_or("expected `constraint NAME lhs => rhs`")?;
+    let (lhs, rhs) = rule.split_once("=>").ok_or("expected `=>` in constraint")?;
+    let lhs: Pattern<USr> = lhs
+        .trim()
+        .parse()
+        .map_err(|e| format!("bad pattern: {}", e))?;
+    let rhs: Pattern<USr> = rhs
+        .trim()
+        .parse()
+        .map_err(|e| format!("bad pattern: {}", e))?;
+    Rewrite::new(name, lhs, rhs)
+}
+
+impl RawCase {
+    fn build(self) -> Result<Case, CorpusError> {
+        let output = self
+            .fields
+            .iter()
+            .rev()
+            .find(|(_, k, _)| k == "output")
+            .map_or("t", |(_, _, v)| v.as_str())
+            .to_owned();
+        let mut case = Case {
+            name: self.name,
+            line: self.line,
+            expect: Outcome::Proved,
+            lhs: RecExpr::default(),
+            rhs: RecExpr::default(),
+            steps: vec![],
+            constraints: vec![],
+            iter_limit: None,
+            node_limit: None,
+            time_limit: None,
+        };
+        let (mut lhs, mut rhs) = (false, false);
+        for (line, key, value) in self.fields {
+            let error = |message: String| CorpusError { line, message };
+            let number = |v: &str| v.parse().map_err(|_| error(format!("bad {}: {}", key, v)));
+            match key.as_str() {
+                "expect" => case.expect = value.parse().map_err(error)?,
+                "lhs" | "lhs.sql" => {
+                    case.lhs = parse_term(&value, key.ends_with(".sql"), &output).map_err(error)?;
+                    lhs = true;
+                }
+                "rhs" | "rhs.sql" => {
+                    case.rhs = parse_term(&value, key.ends_with(".sql"), &output).map_err(error)?;
+                    rhs = true;
+                }
+                "step" => case.steps.push(parse_term(&value, false, &output).map_err(error)?),
+                "constraint" => case
+                    .constraints
+                    .push(parse_constraint(&value).map_err(error)?),
+                "output" => (),
+                "iter_limit" => case.iter_limit = Some(number(&value)?),
+                "node_limit" => case.node_limit = Some(number(&value)?),
+                "time_limit" => {
+                    let secs: f64 = value
+                        .parse()
+                        .map_err(|_| error(format!("bad time_limit: {}", value)))?;
+                    case.time_limit = Some(Duration::from_secs_f64(secs));
+                }
+                _ => return Err(error(format!("unknown field {}", key))),
+            }
+        }
+        for (present, side) in [(lhs, "lhs"), (rhs, "rhs")] {
+            if !present {
+                return Err(CorpusError {
+                    line: case.line,
+                    message: format!("case {} has no {}", case.name, side),
+                });
+            }
+        }
+        Ok(case)
+    }
+}
+
+fn indent(text: &str) -> usize {
+    text.len() - text.trim_start().len()
+}
+
+impl FromStr for Corpus {
+    type Err = CorpusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut raw: Vec<RawCase> = vec![];
+        // the field being read and its indentation; lines indented
+        // further than it continue its value
+        let mut open: Option<(usize, (usize, String, String))> = None;
+        for (i, text) in s.lines().enumerate() {
+            let line = i + 1;
+            let text = text.split('#').next().unwrap();
+            if text.trim().is_empty() {
+                continue;
+            }
+            if let Some((depth, (_, _, value))) = &mut open {
+                if indent(text) > *depth {
+                    value.push(' ');
+                    value.push_str(text.trim());
+                    continue;
+                }
+            }
+            if let Some((_, field)) = open.take() {
+                raw.last_mut().unwrap().fields.push(field);
+            }
+
+            if indent(text) == 0 {
+                let name = match text.trim().strip_prefix("case ") {
+                    Some(name) => name.trim(),
+                    None => {
+                        return Err(CorpusError {
+                            line,
+                            message: "expected `case <name>`".into(),
+                        })
+                    }
+                };
+                if raw.iter().any(|c| c.name == name) {
+                    return Err(CorpusError {
+                        line,
+                        message: format!("duplicate case {}", name),
+                    });
+                }
+                raw.push(RawCase {
+                    name: name.to_owned(),
+                    line,
+                    ..Default::default()
+                });
+            } else {
+                let field = text.trim();
+                let (key, value) = field.split_once(char::is_whitespace).unwrap_or((field, ""));
+                if raw.is_empty() {
+                    return Err(CorpusError {
+                        line,
+                        message: format!("field {} outside of a case", key),
+                    });
+                }
+                let field = (line, key.to_owned(), value.trim().to_owned());
+                open = Some((indent(text), field));
+            }
+        }
+        if let Some((_, field)) = open {
+            raw.last_mut().unwrap().fields.push(field);
+        }
+        let cases = raw
+            .into_iter()
+            .map(RawCase::build)
+            .collect::<Result<_, _>>()?;
+        Ok(Corpus { cases })
+    }
+}
+
+/// The result of running one [`Case`]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde-1", derive(serde::Serialize))]
+pub struct CaseResult {
+    /// Name of the case
+    pub name: String,
+    /// The verdict the case should get
+    pub expected: Outcome,
+    /// The verdict it got
+    pub outcome: Outcome,
+    /// The counterexample, for refuted cases
+    pub counterexample: Option<String>,
+    /// The runner's report
+    pub report: Report,
+}
+
+impl CaseResult {
+    /// Whether the case got its expected verdict
+    pub fn as_expected(&self) -> bool {
+        self.expected == self.outcome
+    }
+}
+
+/// The results of running a whole [`Corpus`]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde-1", derive(serde::Serialize))]
+pub struct BatchReport {
+    /// One result per case, in corpus order
+    pub results: Vec<CaseResult>,
+}
+
+impl BatchReport {
+    /// The cases that did not get their expected verdict
+    pub fn unexpected(&self) -> impl Iterator<Item = &CaseResult> {
+        self.results.iter().filter(|r| !r.as_expected())
+    }
+
+    /// How many cases got `outcome`
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.results.iter().filter(|r| r.outcome == outcome).count()
+    }
+
+    /// The per-case results and reports as pretty-printed JSON
+    #[cfg(feature = "reports")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl fmt::Display for BatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self
+            .results
+            .iter()
+            .map(|r| r.name.len())
+            .max()
+            .unwrap_or(0)
+            .max(4);
+        writeln!(
+            f,
+            "  {:width$}  {:8}  {:8}  {:>5}  {:>7}  {:>8}",
+            "case",
+            "expected",
+            "outcome",
+            "iters",
+            "nodes",
+            "time",
+            width = width
+        )?;
+        for r in &self.results {
+            writeln!(
+                f,
+                "{} {:width$}  {:8}  {:8}  {:>5}  {:>7}  {:>8.3}",
+                if r.as_expected() { ' ' } else { '!' },
+                r.name,
+                r.expected.to_string(),
+                r.outcome.to_string(),
+                r.report.iterations,
+                r.report.egraph_nodes,
+                r.report.total_time,
+                width = width
+            )?;
+        }
+        write!(
+            f,
+            "{}/{} as expected: {} proved, {} refuted, {} unknown, {} timeout",
+            self.results.len() - self.unexpected().count(),
+            self.results.len(),
+            self.count(Outcome::Proved),
+            self.count(Outcome::Refuted),
+            self.count(Outcome::Unknown),
+            self.count(Outcome::Timeout),
+        )
+    }
+}
+
+/// Runs one case with [`rules`] plus its constraints, and its steps in the
+/// e-graph, stopping as soon as both sides are equal. If they are not, and the case has no constraints,
+/// searches for a counterexample.
+pub fn run_case<S>(case: &Case, scheduler: S, config: &Config) -> CaseResult
+where
+    S: RewriteScheduler<USr, UAnalysis> + 'static,
+{
+    let mut rls = rules();
+    rls.extend(case.constraints.iter().cloned());
+
+    let mut runner = Runner::default()
+        .with_scheduler(scheduler)
+        .with_expr(&case.lhs)
+        .with_expr(&case.rhs);
+    for step in &case.steps {
+        runner = runner.with_expr(step);
+    }
+    runner = runner.with_hook(|runner| {
+            if runner.egraph.find(runner.roots[0]) == runner.egraph.find(runner.roots[1]) {
+                Err("proved".into())
+            } else {
+                Ok(())
+            }
+        });
+    if let Some(limit) = case.iter_limit {
+        runner = runner.with_iter_limit(limit);
+    }
+    if let Some(limit) = case.node_limit {
+        runner = runner.with_node_limit(limit);
+    }
+    if let Some(limit) = case.time_limit {
+        runner = runner.with_time_limit(limit);
+    }
+    let runner = runner.run(&rls);
+
+    let proved = runner.egraph.find(runner.roots[0]) == runner.egraph.find(runner.roots[1]);
+    // random databases need not satisfy the constraints, so a difference
+    // on one refutes nothing
+    let search = if proved || !case.constraints.is_empty() {
+        None
+    } else {
+        find_counterexample(&case.lhs, &case.rhs, config)
+    };
+    let mut counterexample = None;
+    let outcome = if proved {
+        Outcome::Proved
+    } else if let Some(cex) = search {
+        counterexample = Some(cex.to_string());
+        Outcome::Refuted
+    } else if let Some(StopReason::TimeLimit(_)) = runner.stop_reason {
+        Outcome::Timeout
+    } else {
+        Outcome::Unknown
+    };
+    CaseResult {
+        name: case.name.clone(),
+        expected: case.expect,
+        outcome,
+        counterexample,
+        report: runner.report(),
+    }
+}
+
+/// Runs every case of `corpus`, each with a fresh scheduler from `scheduler`
+/// and the default counterexample search
+pub fn run_corpus<S>(corpus: &Corpus, scheduler: impl Fn() -> S) -> BatchReport
+where
+    S: RewriteScheduler<USr, UAnalysis> + 'static,
+{
+    let config = Config::default();
+    BatchReport {
+        results: corpus
+            .cases
+            .iter()
+            .map(|case| run_case(case, scheduler(), &config))
+            .collect(),
+    }
+}
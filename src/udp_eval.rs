@@ -0,0 +1,497 @@
+//! Evaluating USr terms on small finite databases, to look for
+//! counterexamples to equivalences the rules can't prove.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::udp_analysis::UAnalysis;
+use crate::udp_lang::USr;
+use crate::{Id, Language, RecExpr, Rewrite, Runner, Symbol};
+
+/// A value a USr term can evaluate to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Value {
+    /// A natural number (semiring element) or an attribute value
+    Int(i64),
+    /// A tuple, as an index into [`Database::domain`]
+    Tuple(usize),
+    /// An uninterpreted name, like an attribute name in `(. t k)`
+    Name(Symbol),
+}
+
+/// A tuple of the finite domain, as attribute name/value pairs
+pub type Tuple = Vec<(Symbol, i64)>;
+
+/// An interpretation for an `Other` function symbol
+pub type Function = fn(&Database, &[Value]) -> Result<Value, EvalError>;
+
+/// Why a term could not be evaluated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvalError {
+    /// A variable that is neither bound by `sig`/`let` nor given in the environment
+    Unbound(Symbol),
+    /// An operator with no relation table or function interpretation
+    UnknownOp(Symbol),
+    /// An operator applied to a value of the wrong kind
+    Sort(String),
+    /// An operator this evaluator does not interpret
+    Unsupported(&'static str),
+    /// A sum, product or multiplicity that doesn't fit in an `i64`
+    Overflow(&'static str),
+    /// A tuple outside the domain of the database
+    NoTuple(usize),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::Unbound(v) => write!(f, "unbound variable {}", v),
+            EvalError::UnknownOp(op) => write!(f, "no relation or function named {}", op),
+            EvalError::Sort(msg) => write!(f, "ill-sorted term: {}", msg),
+            EvalError::Unsupported(op) => write!(f, "cannot evaluate {}", op),
+            EvalError::Overflow(what) => write!(f, "{} overflows", what),
+            EvalError::NoTuple(t) => write!(f, "no tuple t{} in the domain", t),
+        }
+    }
+}
+
+/// A concrete database: a finite tuple domain, relation tables with
+/// multiplicities, and interpretations for function symbols
+#[derive(Debug, Clone, Default)]
+pub struct Database {
+    /// The tuples `sig` ranges over
+    pub domain: Vec<Tuple>,
+    /// Multiplicity of each argument vector, per relation; missing entries are 0
+    pub relations: BTreeMap<Symbol, BTreeMap<Vec<Value>, u64>>,
+    /// Interpretations of `Other` operators
+    pub functions: HashMap<Symbol, Function>,
+}
+
+fn project(db: &Database, t: Value, a: Value) -> Result<Value, EvalError> {
+    match (t, a) {
+        (Value::Tuple(t), Value::Name(a)) => {
+            let tuple = db.domain.get(t).ok_or(EvalError::NoTuple(t))?;
+            tuple
+                .iter()
+                .find(|(name, _)| *name == a)
+                .map(|(_, v)| Value::Int(*v))
+                .ok_or_else(|| EvalError::Sort(format!("tuple {} has no attribute {}", t, a)))
+        }
+        _ => Err(EvalError::Sort(format!("cannot project {} from {}", a, t))),
+    }
+}
+
+fn int(v: Value) -> Result<i64, EvalError> {
+    match v {
+        Value::Int(n) => Ok(n),
+        _ => Err(EvalError::Sort(format!("expected a number, got {:?}", v))),
+    }
+}
+
+fn name(expr: &RecExpr<USr>, id: Id) -> Result<Symbol, EvalError> {
+    match &expr[id] {
+        USr::Symbol(s) => Ok(*s),
+        n => Err(EvalError::Sort(format!("expected a variable name, got {:?}", n))),
+    }
+}
+
+fn root(expr: &RecExpr<USr>) -> Id {
+    Id::from(expr.as_ref().len() - 1)
+}
+
+impl Database {
+    /// Evaluates `expr` with the free variables given by `env`
+    pub fn eval(&self, expr: &RecExpr<USr>, env: &HashMap<Symbol, Value>) -> Result<Value, EvalError> {
+        let mut env = env.clone();
+        self.eval_in(expr, root(expr), &mut env)
+    }
+
+    fn eval_in(
+        &self,
+        expr: &RecExpr<USr>,
+        id: Id,
+        env: &mut HashMap<Symbol, Value>,
+    ) -> Result<Value, EvalError> {
+        let ev = |i: &Id, env: &mut HashMap<Symbol, Value>| self.eval_in(expr, *i, env);
+        let truth = |b: bool| Ok(Value::Int(b as i64));
+        match &expr[id] {
+            USr::Num(n) => Ok(Value::Int(*n as i64)),
+            USr::Var(v) => {
+                let v = name(expr, *v)?;
+                env.get(&v).copied().ok_or(EvalError::Unbound(v))
+            }
+            USr::Symbol(s) => Ok(env.get(s).copied().unwrap_or(Value::Name(*s))),
+            USr::Add(cs) => cs.iter().try_fold(0i64, |n, c| {
+                n.checked_add(int(ev(c, env)?)?).ok_or(EvalError::Overflow("sum"))
+            }).map(Value::Int),
+            USr::Mul(cs) => cs.iter().try_fold(1i64, |n, c| {
+                n.checked_mul(int(ev(c, env)?)?).ok_or(EvalError::Overflow("product"))
+            }).map(Value::Int),
+            USr::Eql([a, b]) => truth(ev(a, env)? == ev(b, env)?),
+            USr::Neq([a, b]) => truth(ev(a, env)? != ev(b, env)?),
+            USr::Neg(a) => truth(int(ev(a, env)?)? == 0),
+            USr::Sqs(a) | USr::Cnd(a) => truth(int(ev(a, env)?)? != 0),
+            USr::Sum(_) => Err(EvalError::Unsupported("sum")),
+            USr::Proj([t, a]) => project(self, ev(t, env)?, ev(a, env)?),
+            USr::Ge([a, b]) => truth(int(ev(a, env)?)? >= int(ev(b, env)?)?),
+            USr::Le([a, b]) => truth(int(ev(a, env)?)? <= int(ev(b, env)?)?),
+            USr::Gt([a, b]) => truth(int(ev(a, env)?)? > int(ev(b, env)?)?),
+            USr::Lt([a, b]) => truth(int(ev(a, env)?)? < int(ev(b, env)?)?),
+            USr::Sig([v, a]) => {
+                let v = name(expr, *v)?;
+                let old = env.get(&v).copied();
+                let mut total = 0;
+                for t in 0..self.domain.len() {
+                    env.insert(v, Value::Tuple(t));
+                    let n = int(ev(a, env)?)?;
+                    total = i64::checked_add(total, n).ok_or(EvalError::Overflow("sum"))?;
+                }
+                restore(env, v, old);
+                Ok(Value::Int(total))
+            }
+            USr::Let([v, e, b]) => {
+                let v = name(expr, *v)?;
+                let e = ev(e, env)?;
+                let old = env.insert(v, e);
+                let result = ev(b, env);
+                restore(env, v, old);
+                result
+            }
+            USr::Rel(r, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| ev(a, env))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let table = self.relations.get(&r.0).ok_or(EvalError::UnknownOp(r.0))?;
+                let n = table.get(&args).copied().unwrap_or(0);
+                let n = i64::try_from(n).map_err(|_| EvalError::Overflow("multiplicity"))?;
+                Ok(Value::Int(n))
+            }
+            USr::Other(op, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| ev(a, env))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let f = self.functions.get(op).ok_or(EvalError::UnknownOp(*op))?;
+                f(self, &args)
+            }
+        }
+    }
+}
+
+fn restore(env: &mut HashMap<Symbol, Value>, v: Symbol, old: Option<Value>) {
+    match old {
+        Some(old) => env.insert(v, old),
+        None => env.remove(&v),
+    };
+}
+
+impl fmt::Display for Database {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, t) in self.domain.iter().enumerate() {
+            write!(f, "t{} = (", i)?;
+            for (j, (a, v)) in t.iter().enumerate() {
+                let sep = if j == 0 { "" } else { ", " };
+                write!(f, "{}{}: {}", sep, a, v)?;
+            }
+            writeln!(f, ")")?;
+        }
+        for (r, table) in &self.relations {
+            for (args, n) in table {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                writeln!(f, "{}({}) x {}", r, args.join(", "), n)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Tuple(t) => write!(f, "t{}", t),
+            Value::Name(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// The relations, attributes and free variables a set of terms mentions,
+/// used to generate databases they can be evaluated on
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    /// Relation names with their arity
+    pub relations: BTreeMap<Symbol, usize>,
+    /// Attribute names used in projections
+    pub attributes: BTreeSet<Symbol>,
+    /// Free variables, and whether each one is used as a number rather than a tuple
+    pub free: BTreeMap<Symbol, bool>,
+    /// Numeric literals, so generated attribute values straddle them
+    pub constants: BTreeSet<i64>,
+}
+
+impl Schema {
+    /// Collects the schema of `exprs`
+    pub fn infer(exprs: &[&RecExpr<USr>]) -> Self {
+        let mut schema = Schema::default();
+        for expr in exprs {
+            schema.walk(expr, root(expr), &mut vec![], false);
+        }
+        schema
+    }
+
+    fn add_free(&mut self, s: Symbol, bound: &[Symbol], numeric: bool) {
+        if !bound.contains(&s) {
+            *self.free.entry(s).or_default() |= numeric;
+        }
+    }
+
+    fn walk_bound(
+        &mut self,
+        expr: &RecExpr<USr>,
+        v: Id,
+        body: Id,
+        bound: &mut Vec<Symbol>,
+        numeric: bool,
+    ) {
+        match &expr[v] {
+            USr::Symbol(s) => {
+                bound.push(*s);
+                self.walk(expr, body, bound, numeric);
+                bound.pop();
+            }
+            _ => self.walk(expr, body, bound, numeric),
+        }
+    }
+
+    fn walk(
+        &mut self,
+        expr: &RecExpr<USr>,
+        id: Id,
+        bound: &mut Vec<Symbol>,
+        numeric: bool,
+    ) {
+        match &expr[id] {
+            USr::Num(n) => {
+                self.constants.insert(*n as i64);
+            }
+            USr::Var(v) => {
+                if let USr::Symbol(s) = &expr[*v] {
+                    self.add_free(*s, bound, numeric)
+                }
+            }
+            USr::Symbol(s) => self.add_free(*s, bound, numeric),
+            USr::Sig([v, a]) => self.walk_bound(expr, *v, *a, bound, true),
+            USr::Let([v, e, b]) => {
+                self.walk(expr, *e, bound, false);
+                self.walk_bound(expr, *v, *b, bound, numeric);
+            }
+            USr::Proj([t, a]) => {
+                if let USr::Symbol(a) = &expr[*a] {
+                    self.attributes.insert(*a);
+                }
+                self.walk(expr, *t, bound, false);
+            }
+            USr::Rel(r, args) => {
+                self.relations.insert(r.0, args.len());
+                for a in args {
+                    self.walk(expr, *a, bound, false);
+                }
+            }
+            USr::Other(_, args) => {
+                for a in args {
+                    self.walk(expr, *a, bound, false);
+                }
+            }
+            USr::Eql(cs) | USr::Neq(cs) | USr::Ge(cs) | USr::Le(cs) | USr::Gt(cs) | USr::Lt(cs) => {
+                for c in cs {
+                    self.walk(expr, *c, bound, false);
+                }
+            }
+            n => {
+                for c in n.children() {
+                    self.walk(expr, *c, bound, true);
+                }
+            }
+        }
+    }
+}
+
+/// Knobs for the random counterexample search
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How many databases to try
+    pub databases: usize,
+    /// How many assignments of the free variables to try per database
+    pub assignments: usize,
+    /// Largest tuple domain to generate
+    pub max_domain: usize,
+    /// Largest multiplicity of a tuple in a relation
+    pub max_multiplicity: u64,
+    /// Seed of the generator, so searches are reproducible
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            databases: 200,
+            assignments: 4,
+            max_domain: 3,
+            max_multiplicity: 2,
+            seed: 0x5eed,
+        }
+    }
+}
+
+// xorshift64*, plenty for picking small databases
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n.max(1)
+    }
+}
+
+/// A database and assignment of free variables on which two terms differ
+#[derive(Debug, Clone)]
+pub struct Counterexample {
+    /// The witness database
+    pub database: Database,
+    /// Values of the free variables
+    pub env: HashMap<Symbol, Value>,
+    /// What the left term evaluates to
+    pub lhs: Value,
+    /// What the right term evaluates to
+    pub rhs: Value,
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.database)?;
+        let env: BTreeMap<_, _> = self.env.iter().map(|(v, x)| (v.as_str(), x)).collect();
+        for (v, x) in env {
+            writeln!(f, "{} := {}", v, x)?;
+        }
+        write!(f, "lhs = {}, rhs = {}", self.lhs, self.rhs)
+    }
+}
+
+fn random_database(schema: &Schema, config: &Config, rng: &mut Rng) -> Database {
+    let mut values: BTreeSet<i64> = (0..3).collect();
+    for c in &schema.constants {
+        values.extend([c - 1, *c, c + 1]);
+    }
+    let values: Vec<i64> = values.into_iter().collect();
+
+    let mut db = Database::default();
+    let size = 1 + rng.below(config.max_domain as u64) as usize;
+    // tuples are values, so the domain must not repeat one; there may not
+    // be `size` different tuples at all, so give up after a while
+    let mut attempts = 10 * size;
+    while db.domain.len() < size && attempts > 0 {
+        attempts -= 1;
+        let t: Tuple = schema
+            .attributes
+            .iter()
+            .map(|a| (*a, values[rng.below(values.len() as u64) as usize]))
+            .collect();
+        if !db.domain.contains(&t) {
+            db.domain.push(t);
+        }
+    }
+
+    for (r, arity) in &schema.relations {
+        let mut table = BTreeMap::new();
+        let mut key = vec![0; *arity];
+        loop {
+            let n = rng.below(config.max_multiplicity + 1);
+            if n > 0 {
+                table.insert(key.iter().map(|t| Value::Tuple(*t)).collect(), n);
+            }
+            // next key vector over the domain, in odometer order
+            match key.iter().rposition(|t| t + 1 < db.domain.len()) {
+                Some(i) => {
+                    key[i] += 1;
+                    key[i + 1..].iter_mut().for_each(|t| *t = 0);
+                }
+                None => break,
+            }
+        }
+        db.relations.insert(*r, table);
+    }
+    db
+}
+
+/// Searches small random databases for one where `lhs` and `rhs` evaluate differently.
+/// Databases or assignments on which either term fails to evaluate are skipped.
+pub fn find_counterexample(
+    lhs: &RecExpr<USr>,
+    rhs: &RecExpr<USr>,
+    config: &Config,
+) -> Option<Counterexample> {
+    let schema = Schema::infer(&[lhs, rhs]);
+    let mut rng = Rng(config.seed | 1);
+    for _ in 0..config.databases {
+        let database = random_database(&schema, config, &mut rng);
+        for _ in 0..config.assignments {
+            let env: HashMap<Symbol, Value> = schema
+                .free
+                .iter()
+                .map(|(v, numeric)| {
+                    let x = if *numeric {
+                        Value::Int(rng.below(config.max_multiplicity + 1) as i64)
+                    } else {
+                        Value::Tuple(rng.below(database.domain.len() as u64) as usize)
+                    };
+                    (*v, x)
+                })
+                .collect();
+            match (database.eval(lhs, &env), database.eval(rhs, &env)) {
+                (Ok(l), Ok(r)) if l != r => {
+                    return Some(Counterexample {
+                        database,
+                        env,
+                        lhs: l,
+                        rhs: r,
+                    })
+                }
+                _ => (),
+            }
+        }
+    }
+    None
+}
+
+/// The outcome of checking a UDP equivalence
+#[derive(Debug, Clone)]
+pub enum Verdict {
+    /// The rules proved both terms equal
+    Proved,
+    /// The terms differ on the given database
+    Refuted(Box<Counterexample>),
+    /// Neither a proof nor a counterexample was found
+    Unknown,
+}
+
+/// Saturates `lhs` and `rhs` with `rules`, and if that does not prove them
+/// equal, looks for a counterexample database
+pub fn check(
+    lhs: &RecExpr<USr>,
+    rhs: &RecExpr<USr>,
+    rules: &[Rewrite<USr, UAnalysis>],
+    config: &Config,
+) -> Verdict {
+    let runner = Runner::default().with_expr(lhs).with_expr(rhs).run(rules);
+    if runner.egraph.find(runner.roots[0]) == runner.egraph.find(runner.roots[1]) {
+        Verdict::Proved
+    } else if let Some(cex) = find_counterexample(lhs, rhs, config) {
+        Verdict::Refuted(Box::new(cex))
+    } else {
+        Verdict::Unknown
+    }
+}
@@ -1,15 +1,17 @@
-use crate::{define_language, Symbol};
-use crate::Id;
-use crate::language::Language;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{define_language, FromOpError, Id, Language, RecExpr, RecExprParseError, Symbol};
 
 define_language! {
     pub enum USr {
         Num(i32),
-        
+
         "var" = Var(Id),
 
-        "+" = Add([Id; 2]),
-        "*" = Mul([Id; 2]),
+        #[ac] "+" = Add(Vec<Id>),
+        #[ac] "*" = Mul(Vec<Id>),
         "=" = Eql([Id; 2]),
         "!=" = Neq([Id; 2]),
 
@@ -21,7 +23,203 @@ define_language! {
         "sig" = Sig([Id; 2]),
         "let" = Let([Id; 3]),
 
+        // attribute projection of a tuple
+        "." = Proj([Id; 2]),
+        ">=" = Ge([Id; 2]),
+        "<=" = Le([Id; 2]),
+        ">" = Gt([Id; 2]),
+        "<" = Lt([Id; 2]),
+
         Symbol(Symbol),
+        Rel(RelName, Vec<Id>),
         Other(Symbol, Vec<Id>),
     }
 }
+
+/// Name of a relation; relation names start with an uppercase letter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RelName(pub Symbol);
+
+impl FromStr for RelName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.chars().next() {
+            Some(c) if c.is_ascii_uppercase() => Ok(RelName(s.into())),
+            _ => Err(format!("{} is not a relation name", s)),
+        }
+    }
+}
+
+impl fmt::Display for RelName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// What kind of thing a USr term denotes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Sort {
+    /// A tuple, like a `sig`-bound variable
+    Tuple,
+    /// An attribute value, like `(. t k)`
+    Value,
+    /// A U-semiring element: relations, predicates, sums and products
+    Semiring,
+}
+
+impl fmt::Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Sort::Tuple => "a tuple",
+            Sort::Value => "a value",
+            Sort::Semiring => "a semiring element",
+        })
+    }
+}
+
+/// Relations and attributes terms may refer to.
+/// An empty schema accepts any relation and attribute.
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    /// Sorts of the arguments of each relation
+    pub relations: BTreeMap<Symbol, Vec<Sort>>,
+    /// Attribute names allowed in projections
+    pub attributes: BTreeSet<Symbol>,
+}
+
+impl Schema {
+    /// The sort of `node`, given the sorts of its children; `None` when the
+    /// node alone does not determine it, like numbers and names
+    pub fn sort_of(node: &USr, child: impl Fn(Id) -> Option<Sort>) -> Option<Sort> {
+        match node {
+            USr::Var(_) => Some(Sort::Tuple),
+            USr::Proj(_) => Some(Sort::Value),
+            USr::Let([_, _, b]) => child(*b),
+            USr::Num(_) | USr::Symbol(_) | USr::Other(..) => None,
+            _ => Some(Sort::Semiring),
+        }
+    }
+}
+
+/// A term that is ill-sorted under some [`Schema`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortError {
+    /// The offending subterm
+    pub subterm: String,
+    /// What is wrong with it
+    pub message: String,
+}
+
+impl fmt::Display for SortError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ill-sorted term {}: {}", self.subterm, self.message)
+    }
+}
+
+impl std::error::Error for SortError {}
+
+fn check_node(
+    schema: &Schema,
+    node: &USr,
+    child: impl Fn(Id) -> Option<Sort>,
+    name: impl Fn(Id) -> Option<Symbol>,
+) -> Result<(), String> {
+    let expect = |id: &Id, allowed: &[Sort]| match child(*id) {
+        Some(s) if !allowed.contains(&s) => {
+            let allowed: Vec<String> = allowed.iter().map(|s| s.to_string()).collect();
+            Err(format!("{} expects {} but got {}", node, allowed.join(" or "), s))
+        }
+        _ => Ok(()),
+    };
+    let data = [Sort::Tuple, Sort::Value];
+    match node {
+        USr::Add(cs) | USr::Mul(cs) => cs.iter().try_for_each(|c| expect(c, &[Sort::Semiring])),
+        USr::Neg(c) | USr::Sqs(c) | USr::Cnd(c) | USr::Sum(c) => expect(c, &[Sort::Semiring]),
+        USr::Sig([_, c]) => expect(c, &[Sort::Semiring]),
+        USr::Ge(cs) | USr::Le(cs) | USr::Gt(cs) | USr::Lt(cs) => {
+            cs.iter().try_for_each(|c| expect(c, &[Sort::Value]))
+        }
+        USr::Eql([a, b]) | USr::Neq([a, b]) => {
+            expect(a, &data)?;
+            expect(b, &data)?;
+            match (child(*a), child(*b)) {
+                (Some(x), Some(y)) if x != y => Err(format!("{} compares {} with {}", node, x, y)),
+                _ => Ok(()),
+            }
+        }
+        USr::Proj([t, a]) => {
+            expect(t, &[Sort::Tuple])?;
+            match name(*a) {
+                Some(a) if schema.attributes.is_empty() || schema.attributes.contains(&a) => Ok(()),
+                Some(a) => Err(format!("unknown attribute {}", a)),
+                None => Err(format!("{} expects an attribute name", node)),
+            }
+        }
+        USr::Rel(r, args) => {
+            args.iter().try_for_each(|c| expect(c, &data))?;
+            match schema.relations.get(&r.0) {
+                Some(sorts) if sorts.len() != args.len() => Err(format!(
+                    "relation {} takes {} arguments but got {}",
+                    r,
+                    sorts.len(),
+                    args.len()
+                )),
+                Some(sorts) => args.iter().zip(sorts).try_for_each(|(c, s)| expect(c, &[*s])),
+                None if schema.relations.is_empty() => Ok(()),
+                None => Err(format!("unknown relation {}", r)),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Computes the sort of every node of `expr`, failing on the first ill-sorted one
+pub fn check_sorts(expr: &RecExpr<USr>, schema: &Schema) -> Result<Vec<Option<Sort>>, SortError> {
+    let nodes = expr.as_ref();
+    let mut sorts: Vec<Option<Sort>> = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let child = |id: Id| sorts[usize::from(id)];
+        let name = |id: Id| match &nodes[usize::from(id)] {
+            USr::Symbol(s) => Some(*s),
+            _ => None,
+        };
+        if let Err(message) = check_node(schema, node, child, name) {
+            return Err(SortError {
+                subterm: node.build_recexpr(|c| nodes[usize::from(c)].clone()).to_string(),
+                message,
+            });
+        }
+        let sort = Schema::sort_of(node, child);
+        sorts.push(sort);
+    }
+    Ok(sorts)
+}
+
+/// An error from [`parse`]
+#[derive(Debug)]
+pub enum ParseError {
+    /// The string is not a USr term
+    Syntax(RecExprParseError<FromOpError>),
+    /// The term is ill-sorted
+    Sort(SortError),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Syntax(e) => fmt::Display::fmt(e, f),
+            ParseError::Sort(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parses a USr term, rejecting ill-sorted ones like `(* (var t) (R t))`;
+/// `str::parse` only checks the syntax
+pub fn parse(s: &str, schema: &Schema) -> Result<RecExpr<USr>, ParseError> {
+    let expr = s.parse().map_err(ParseError::Syntax)?;
+    check_sorts(&expr, schema).map_err(ParseError::Sort)?;
+    Ok(expr)
+}
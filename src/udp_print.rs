@@ -0,0 +1,288 @@
+//! Printing USr terms as math in Unicode or LaTeX, and back as SQL.
+
+use crate::udp_lang::USr;
+use crate::udp_sql::SqlError;
+use crate::{Id, Language, RecExpr, Symbol};
+
+/// How to write U-semiring terms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notation {
+    /// Plain text with Unicode symbols: `Σ_t ‖R(t) × [t.a ≥ 12]‖`
+    Unicode,
+    /// LaTeX math mode: `\sum_{t} \lVert R(t) \times [t.a \geq 12] \rVert`
+    Latex,
+}
+
+struct Symbols {
+    sum: &'static str,
+    sig: (&'static str, &'static str),
+    squash: (&'static str, &'static str),
+    not: &'static str,
+    times: &'static str,
+    neq: &'static str,
+    ge: &'static str,
+    le: &'static str,
+    maps_to: &'static str,
+}
+
+const UNICODE: Symbols = Symbols {
+    sum: "Σ",
+    sig: ("Σ_", " "),
+    squash: ("‖", "‖"),
+    not: "not",
+    times: " × ",
+    neq: " ≠ ",
+    ge: " ≥ ",
+    le: " ≤ ",
+    maps_to: " ↦ ",
+};
+
+const LATEX: Symbols = Symbols {
+    sum: "\\sum",
+    sig: ("\\sum_{", "} "),
+    squash: ("\\lVert ", " \\rVert"),
+    not: "\\operatorname{not}",
+    times: " \\times ",
+    neq: " \\neq ",
+    ge: " \\geq ",
+    le: " \\leq ",
+    maps_to: " \\mapsto ",
+};
+
+// binding strength, loosest first; a `sig` extends as far right as it can
+const SIG: u8 = 0;
+const ADD: u8 = 1;
+const MUL: u8 = 2;
+const ATOM: u8 = 3;
+
+fn precedence(node: &USr) -> u8 {
+    match node {
+        USr::Sig(_) => SIG,
+        USr::Add(_) => ADD,
+        USr::Mul(_) => MUL,
+        _ => ATOM,
+    }
+}
+
+struct Printer<'a> {
+    expr: &'a RecExpr<USr>,
+    sym: Symbols,
+    latex: bool,
+}
+
+impl Printer<'_> {
+    fn name(&self, s: Symbol) -> String {
+        match self.latex {
+            true => s.as_str().replace('_', "\\_"),
+            false => s.as_str().to_owned(),
+        }
+    }
+
+    fn print(&self, id: Id, context: u8) -> String {
+        let node = &self.expr[id];
+        let s = self.print_node(node);
+        if precedence(node) < context {
+            format!("({})", s)
+        } else {
+            s
+        }
+    }
+
+    fn print_node(&self, node: &USr) -> String {
+        let p = |id: &Id, context| self.print(*id, context);
+        let sym = &self.sym;
+        match node {
+            USr::Num(n) => n.to_string(),
+            USr::Symbol(s) => self.name(*s),
+            USr::Var(v) => p(v, ATOM),
+            USr::Add(cs) => cs.iter().map(|c| p(c, ADD)).collect::<Vec<_>>().join(" + "),
+            USr::Mul(cs) => cs.iter().map(|c| p(c, MUL)).collect::<Vec<_>>().join(sym.times),
+            USr::Eql([a, b]) => format!("{} = {}", p(a, ATOM), p(b, ATOM)),
+            USr::Neq([a, b]) => format!("{}{}{}", p(a, ATOM), sym.neq, p(b, ATOM)),
+            USr::Ge([a, b]) => format!("{}{}{}", p(a, ATOM), sym.ge, p(b, ATOM)),
+            USr::Le([a, b]) => format!("{}{}{}", p(a, ATOM), sym.le, p(b, ATOM)),
+            USr::Gt([a, b]) => format!("{} > {}", p(a, ATOM), p(b, ATOM)),
+            USr::Lt([a, b]) => format!("{} < {}", p(a, ATOM), p(b, ATOM)),
+            USr::Neg(a) => format!("{}({})", sym.not, p(a, SIG)),
+            USr::Sqs(a) => format!("{}{}{}", sym.squash.0, p(a, SIG), sym.squash.1),
+            USr::Cnd(a) => format!("[{}]", p(a, SIG)),
+            USr::Sum(a) => format!("{}({})", sym.sum, p(a, SIG)),
+            USr::Sig([v, a]) => {
+                // nested sigs read as one run of binders
+                let body = match &self.expr[*a] {
+                    USr::Sig(_) => p(a, SIG),
+                    _ => p(a, MUL),
+                };
+                format!("{}{}{}{}", sym.sig.0, p(v, ATOM), sym.sig.1, body)
+            }
+            USr::Let([v, e, b]) => {
+                format!("{}[{}{}{}]", p(b, ATOM), p(v, ATOM), sym.maps_to, p(e, SIG))
+            }
+            USr::Proj([t, a]) => format!("{}.{}", p(t, ATOM), p(a, ATOM)),
+            USr::Rel(r, args) => self.apply(&self.name(r.0), args),
+            USr::Other(f, args) => self.apply(&self.name(*f), args),
+        }
+    }
+
+    fn apply(&self, f: &str, args: &[Id]) -> String {
+        let args: Vec<String> = args.iter().map(|a| self.print(*a, SIG)).collect();
+        format!("{}({})", f, args.join(", "))
+    }
+}
+
+/// Renders `expr` in U-semiring notation
+pub fn to_math(expr: &RecExpr<USr>, notation: Notation) -> String {
+    let printer = Printer {
+        expr,
+        sym: match notation {
+            Notation::Unicode => UNICODE,
+            Notation::Latex => LATEX,
+        },
+        latex: notation == Notation::Latex,
+    };
+    printer.print(Id::from(expr.as_ref().len() - 1), SIG)
+}
+
+struct SqlPrinter<'a> {
+    expr: &'a RecExpr<USr>,
+}
+
+impl SqlPrinter<'_> {
+    fn error<T>(&self, id: Id, what: &str) -> Result<T, SqlError> {
+        let subterm = self.expr[id].build_recexpr(|c| self.expr[c].clone());
+        Err(SqlError::new(None, format!("cannot write {} in SQL: {}", subterm, what)))
+    }
+
+    fn name(&self, id: Id) -> Result<Symbol, SqlError> {
+        match &self.expr[id] {
+            USr::Symbol(s) => Ok(*s),
+            _ => self.error(id, "expected a name"),
+        }
+    }
+
+    fn var(&self, id: Id) -> Option<Symbol> {
+        match &self.expr[id] {
+            USr::Var(v) => self.name(*v).ok(),
+            _ => None,
+        }
+    }
+
+    fn factors(&self, id: Id, out: &mut Vec<Id>) {
+        match &self.expr[id] {
+            USr::Mul(cs) => cs.iter().for_each(|c| self.factors(*c, out)),
+            USr::Num(1) => (),
+            _ => out.push(id),
+        }
+    }
+
+    fn query(&self, id: Id, output: Option<Symbol>) -> Result<String, SqlError> {
+        match &self.expr[id] {
+            USr::Add(cs) => {
+                let queries: Result<Vec<_>, _> = cs.iter().map(|c| self.query(*c, output)).collect();
+                Ok(queries?.join(" UNION ALL "))
+            }
+            _ => self.select(id, output),
+        }
+    }
+
+    fn select(&self, id: Id, output: Option<Symbol>) -> Result<String, SqlError> {
+        let (distinct, mut body) = match &self.expr[id] {
+            USr::Sqs(a) => (true, *a),
+            _ => (false, id),
+        };
+        let mut aliases = vec![];
+        while let USr::Sig([v, a]) = &self.expr[body] {
+            aliases.push(self.name(*v)?);
+            body = *a;
+        }
+
+        let mut factors = vec![];
+        self.factors(body, &mut factors);
+        let mut tables = vec![None; aliases.len()];
+        let mut selected = None;
+        let mut conditions = vec![];
+        for f in factors {
+            match &self.expr[f] {
+                USr::Rel(r, args) => {
+                    let i = match args.as_slice() {
+                        [a] => self.var(*a).and_then(|a| aliases.iter().position(|x| *x == a)),
+                        _ => None,
+                    };
+                    match i {
+                        Some(i) if tables[i].is_none() => tables[i] = Some(r.0),
+                        _ => return self.error(f, "a relation must range over one summed tuple"),
+                    }
+                }
+                USr::Cnd(c) => match (&self.expr[*c], output) {
+                    (USr::Eql([a, b]), Some(t)) if selected.is_none() && self.var(*a) == Some(t) => {
+                        match self.var(*b) {
+                            Some(alias) => selected = Some(alias),
+                            None => return self.error(*b, "the output must be a summed tuple"),
+                        }
+                    }
+                    _ => conditions.push(self.condition(*c)?),
+                },
+                USr::Sqs(q) => conditions.push(format!("EXISTS ({})", self.query(*q, None)?)),
+                USr::Neg(q) => conditions.push(format!("NOT EXISTS ({})", self.query(*q, None)?)),
+                _ => return self.error(f, "not a relation, predicate or subquery"),
+            }
+        }
+
+        let mut from = vec![];
+        for (alias, table) in aliases.iter().zip(&tables) {
+            match table {
+                Some(table) => from.push(format!("{} {}", table, alias)),
+                None => return self.error(id, &format!("{} ranges over no relation", alias)),
+            }
+        }
+        let selected = match (output, selected) {
+            (None, _) => "*".to_owned(),
+            (Some(_), Some(alias)) => format!("{}.*", alias),
+            (Some(t), None) => return self.error(id, &format!("no [{} = ...] picks the output", t)),
+        };
+
+        let mut sql = format!(
+            "SELECT {}{} FROM {}",
+            if distinct { "DISTINCT " } else { "" },
+            selected,
+            from.join(", ")
+        );
+        if !conditions.is_empty() {
+            sql += " WHERE ";
+            sql += &conditions.join(" AND ");
+        }
+        Ok(sql)
+    }
+
+    fn condition(&self, id: Id) -> Result<String, SqlError> {
+        let (op, [a, b]) = match &self.expr[id] {
+            USr::Eql(cs) => ("=", cs),
+            USr::Neq(cs) => ("<>", cs),
+            USr::Ge(cs) => (">=", cs),
+            USr::Le(cs) => ("<=", cs),
+            USr::Gt(cs) => (">", cs),
+            USr::Lt(cs) => ("<", cs),
+            _ => return self.error(id, "not a comparison"),
+        };
+        Ok(format!("{} {} {}", self.operand(*a)?, op, self.operand(*b)?))
+    }
+
+    fn operand(&self, id: Id) -> Result<String, SqlError> {
+        match &self.expr[id] {
+            USr::Num(n) => Ok(n.to_string()),
+            USr::Proj([t, a]) => match self.var(*t) {
+                Some(t) => Ok(format!("{}.{}", t, self.name(*a)?)),
+                None => self.error(id, "can only project from a tuple variable"),
+            },
+            _ => self.error(id, "not an attribute or a number"),
+        }
+    }
+}
+
+/// Renders `expr` as a SQL query producing the tuples `output`, if it has a
+/// shape SQL can express: `UNION ALL`s of `sig`s over products of relation
+/// atoms, `[]` comparisons, and squashed or negated subqueries
+pub fn to_sql(expr: &RecExpr<USr>, output: &str) -> Result<String, SqlError> {
+    let printer = SqlPrinter { expr };
+    printer.query(Id::from(expr.as_ref().len() - 1), Some(output.into()))
+}
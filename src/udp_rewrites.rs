@@ -7,17 +7,6 @@ fn var(s: &str) -> Var {
     s.parse().unwrap()
 }
 
-fn both(
-    f: impl Fn(&mut EGraph<USr, UAnalysis>, Id, &Subst) -> bool,
-    g: impl Fn(&mut EGraph<USr, UAnalysis>, Id, &Subst) -> bool,
-) -> impl Fn(&mut EGraph<USr, UAnalysis>, Id, &Subst) -> bool {
-    move |egraph, id, subst| f(egraph, id, subst) && g(egraph, id, subst)
-}
-
-fn is_not_same_var(v1: Var, v2: Var) -> impl Fn(&mut EGraph<USr, UAnalysis>, Id, &Subst) -> bool {
-    move |egraph, _, subst| egraph.find(subst[v1]) != egraph.find(subst[v2])
-}
-
 fn free(x: Var, b: Var) -> impl Fn(&mut EGraph<USr, UAnalysis>, Id, &Subst) -> bool {
     move |egraph, _, subst| egraph[subst[b]].data.free.contains(&subst[x])
 }
@@ -42,8 +31,26 @@ impl Applier<USr, UAnalysis> for RenameSig {
     }
 }
 
+/// The conditions and appliers of the rules below, for writing UDP rules in
+/// rule files: `free(?x, ?b)`, `not_free(?x, ?b)` and `rename_sig(?fresh, pattern)`
+pub fn registry() -> RuleRegistry<USr, UAnalysis> {
+    let vars = |args: &[RuleArg<USr>]| match args {
+        [x, b] => Ok((x.var()?, b.var()?)),
+        _ => Err("expected two variables".to_owned()),
+    };
+    RuleRegistry::default()
+        .with_condition("free", move |args| vars(args).map(|(x, b)| free(x, b)))
+        .with_condition("not_free", move |args| vars(args).map(|(x, b)| not_free(x, b)))
+        .with_applier("rename_sig", |args| match args {
+            [fresh, e] => Ok(RenameSig { fresh: fresh.var()?, e: e.pattern() }),
+            _ => Err("expected a variable and a pattern".to_owned()),
+        })
+}
+
 pub fn rules() -> Vec<Rewrite<USr, UAnalysis>> {
-    // USr axioms
+    // USr axioms; + and * are AC, so patterns match them modulo
+    // associativity and commutativity, but AC matching only finds sums that
+    // are already in the e-graph, so these still add the reordered ones
     let mut rls = vec![
         rw!("assoc-add";   "(+ ?a (+ ?b ?c))" => "(+ (+ ?a ?b) ?c)"),
         rw!("assoc-add-r"; "(+ (+ ?a ?b) ?c)" => "(+ ?a (+ ?b ?c))"),
@@ -52,13 +59,13 @@ pub fn rules() -> Vec<Rewrite<USr, UAnalysis>> {
 
         rw!("comm-add";  "(+ ?a ?b)" => "(+ ?b ?a)"),
         rw!("comm-mul";  "(* ?a ?b)" => "(* ?b ?a)"),
-        
-        rw!("zero-add"; "(+ ?a 0)" => "?a"),
-        rw!("zero-mul"; "(* ?a 0)" => "0"),
-        rw!("one-mul";  "(* ?a 1)" => "?a"),
+
+        rw!("zero-add"; "(+ ?a:<semiring> 0)" => "?a"),
+        rw!("zero-mul"; "(* ?a:<semiring> 0)" => "0"),
+        rw!("one-mul";  "(* ?a:<semiring> 1)" => "?a"),
     
-        rw!("add-zero"; "?a" => "(+ ?a 0)"),
-        rw!("mul-one";  "?a" => "(* ?a 1)"),
+        rw!("add-zero"; "?a:<semiring>" => "(+ ?a 0)"),
+        rw!("mul-one";  "?a:<semiring>" => "(* ?a 1)"),
 
         rw!("distribute"; "(* ?a (+ ?b ?c))" => "(+ (* ?a ?b) (* ?a ?c))"),
         rw!("factor"    ; "(+ (* ?a ?b) (* ?a ?c))" => "(* ?a (+ ?b ?c))"),    
@@ -69,7 +76,7 @@ pub fn rules() -> Vec<Rewrite<USr, UAnalysis>> {
         rw!("let-const"; "(let ?v ?e ?c)" => "?c" if not_free(var("?v"), var("?c"))),
         rw!("let-var-same"; "(let ?v ?e (var ?v))" => "?e"),
         rw!("let-var-diff"; "(let ?v1 ?e (var ?v2))" => "(var ?v2)"
-            if is_not_same_var(var("?v1"), var("?v2"))),
+            if !match "?v1" in ?v2),
         rw!("let-sig-same"; "(let ?v1 ?e (sig ?v1 ?body))" => "(sig ?v1 ?body)"),
         rw!("let-sig-diff-free"; 
             "(let ?v1 ?e (sig ?v2 ?body))" => 
@@ -77,57 +84,60 @@ pub fn rules() -> Vec<Rewrite<USr, UAnalysis>> {
                 fresh: var("?fresh"),
                 e: "(sig ?fresh (let ?v1 ?e (let ?v2 ?fresh ?body)))".parse().unwrap()
             }}
-            if both(is_not_same_var(var("?v1"), var("?v2")), free(var("?v2"), var("?e")))
+            if !match "?v1" in ?v2
+            if free(var("?v2"), var("?e"))
         ),
         rw!("let-sig-diff-bound"; 
             "(let ?v1 ?e (sig ?v2 ?body))" => "(sig ?v2 (let ?v1 ?e ?body))"
-            if both(is_not_same_var(var("?v1"), var("?v2")), not_free(var("?v2"), var("?e")))
+            if !match "?v1" in ?v2
+            if not_free(var("?v2"), var("?e"))
         ),
-        rw!("let-add";    "(let ?v ?e (+ ?a ?b))" => "(+ (let ?v ?e ?a) (let ?v ?e ?b))"),
-        rw!("let-add-r";  "(+ (let ?v ?e ?a) (let ?v ?e ?b))" => "(let ?v ?e (+ ?a ?b))"),
-        rw!("let-eq";     "(let ?v ?e (= ?a ?b))" => "(= (let ?v ?e ?a) (let ?v ?e ?b))"),
-        rw!("let-eq-r";   "(= (let ?v ?e ?a) (let ?v ?e ?b))" => "(let ?v ?e (= ?a ?b))"),
     ]);
+    rls.extend([
+        rw!("let-add";    "(let ?v ?e (+ ?a ?b))" <=> "(+ (let ?v ?e ?a) (let ?v ?e ?b))"),
+        rw!("let-eq";     "(let ?v ?e (= ?a ?b))" <=> "(= (let ?v ?e ?a) (let ?v ?e ?b))"),
+    ].concat());
 
     // squash axioms
+    rls.extend([
+        rw!("1-a";   "(|| 0)" <=> "0"),
+        rw!("2";   "(|| (+ (|| ?x) ?y))" <=> "(|| (+ ?x ?y))"),
+        rw!("3";   "(* (|| ?x) (|| ?y))" <=> "(|| (* ?x ?y))"),
+        rw!("4";   "(* (|| ?x) (|| ?x))" <=> "(|| ?x)"),
+    ].concat());
     rls.extend(vec![
-        rw!("1-a";   "(|| 0)" => "0"),
-        rw!("1-a-r"; "0" => "(|| 0)"),
         rw!("1-b"; "(|| (+ 1 ?x))" => "1"),
-        rw!("2";   "(|| (+ (|| ?x) ?y))" => "(|| (+ ?x ?y))"),
-        rw!("2-r"; "(|| (+ ?x ?y))" => "(|| (+ (|| ?x) ?y))"),
-        rw!("3";   "(* (|| ?x) (|| ?y))" => "(|| (* ?x ?y))"),
-        rw!("3-r"; "(|| (* ?x ?y))" => "(* (|| ?x) (|| ?y))"),
-        rw!("4";   "(* (|| ?x) (|| ?x))" => "(|| ?x)"),
-        rw!("4-r"; "(|| ?x)" => "(* (|| ?x) (|| ?x))"),
-        rw!("5";   "(* ?x (|| ?x))" => "?x"),
-        rw!("5-r"; "?x" => "(* ?x (|| ?x))"),
-        rw!("6";   "(|| ?x)" => "?x" if ConditionEqual::parse("?x", "(* ?x ?x))")),
-        rw!("6-r"; "?x" => "(|| ?x)" if ConditionEqual::parse("?x", "(* ?x ?x))")),
+        // 5 and 6 have annotations on one side only, so they are written one way at a time
+        rw!("5";   "(* ?x:<semiring> (|| ?x))" => "?x"),
+        rw!("5-rev"; "?x:<semiring>" => "(* ?x (|| ?x))"),
+        rw!("6";   "(|| ?x:<pred>)" => "?x"),
+        rw!("6-rev"; "?x:<pred>" => "(|| ?x)"),
+        rw!("pred-idem"; "(* ?x:<pred> ?x)" => "?x"),
+        rw!("nonzero-sqs"; "(|| ?x:<nonzero>)" => "1"),
     ]);
 
     // negation axioms
+    rls.extend([
+        rw!("n-1";   "(not 0)" <=> "1"),
+        rw!("n-2";   "(not (* ?x ?y))" <=> "(|| (+ (not ?x) (not ?y)))"),
+        rw!("n-3";   "(not (+ ?x ?y))" <=> "(* (not ?x) (not ?y))"),
+    ].concat());
     rls.extend(vec![
-        rw!("n-1";   "(not 0)" => "1"),
-        rw!("n-1-r"; "1" => "(not 0)"),
-        rw!("n-2";   "(not (* ?x ?y))" => "(|| (+ (not ?x) (not ?y)))"),
-        rw!("n-2-r"; "(|| (+ (not ?x) (not ?y)))" => "(not (* ?x ?y))"),
-        rw!("n-3";   "(not (+ ?x ?y))" => "(* (not ?x) (not ?y))"),
-        rw!("n-3-r"; "(* (not ?x) (not ?y))" => "(not (+ ?x ?y))"),
         rw!("n-4-a"; "(not (|| ?x))" => "(|| (not ?x))"),
         rw!("n-4-b"; "(|| (not ?x))" => "(not ?x)"),
         rw!("n-4-c"; "(not ?x)" => "(not (|| ?x))"),
+        rw!("nonzero-not"; "(not ?x:<nonzero>)" => "0"),
     ]);
 
     // summation axioms
+    rls.extend([
+        rw!("7";   "(sig ?t (+ ?a ?b))" <=> "(+ (sig ?t ?a) (sig ?t ?b))"),
+        rw!("9-bound"; "(* ?b (sig ?x ?a))" <=> "(sig ?x (* ?b ?a))"
+                if not_free(var("?x"), var("?b"))),
+        rw!("10";   "(|| (sig ?t ?a))" <=> "(|| (sig ?t (|| ?a)))"),
+    ].concat());
     rls.extend(vec![
-        rw!("7";   "(sig ?t (+ ?a ?b))" => "(+ (sig ?t ?a) (sig ?t ?b))"),
-        rw!("7-r"; "(+ (sig ?t ?a) (sig ?t ?b))" => "(sig ?t (+ ?a ?b))"),
         rw!("8"; "(sig ?s (sig ?t ?a))" => "(sig ?s (sig ?t ?a))"),
-        rw!("9-bound";   "(* ?b (sig ?x ?a))" => "(sig ?x (* ?b ?a))"
-                if not_free(var("?x"), var("?b"))),
-        rw!("9-bound-r"; "(sig ?x (* ?b ?a))" => "(* ?b (sig ?x ?a))"
-                if not_free(var("?x"), var("?b"))),
         rw!("9-free";
             "(* ?b (sig ?x ?a))" =>
             { RenameSig {
@@ -135,22 +145,19 @@ pub fn rules() -> Vec<Rewrite<USr, UAnalysis>> {
                 e: "(sig ?fresh (* ?b (let ?x ?fresh ?a)))".parse().unwrap()
             }}
             if free(var("?x"), var("?b"))),
-        rw!("10";   "(|| (sig ?t ?a))" => "(|| (sig ?t (|| ?a)))"),
-        rw!("10-r"; "(|| (sig ?t (|| ?a)))" => "(|| (sig ?t ?a))"),
     ]);
 
     // conditional axioms
+    rls.extend([
+        rw!("neq";   "(not (= ?x ?y))" <=> "(!= ?x ?y)"),
+        rw!("11";   "([] ?b)" <=> "(|| ([] ?b))"),
+    ].concat());
     rls.extend(vec![
         rw!("eq-comm"; "(= ?x ?y)" => "(= ?y ?x)"),
-        rw!("neq";   "(not (= ?x ?y))" => "(!= ?x ?y)"),
-        rw!("neq-r"; "(!= ?x ?y)" => "(not (= ?x ?y))"),
-
-        rw!("11";   "([] ?b)" => "(|| ([] ?b))"),
-        rw!("11-r";   "(|| ([] ?b))" => "([] ?b)"),
         rw!("12"; "(+ ([] (= ?a ?b)) ([] (!= ?a ?b)))"=>"1"),
         rw!("13"; "(* ?e ([] (= (var ?x) ?y)))" => "(* (let ?x ?y ?e) ([] (= (var ?x) ?y)))"),
         rw!("14"; "(sig ?t ([] (= (var ?t) ?e)))" => "1" if not_free(var("?t"), var("?e"))),
     ]);
-    
+
     rls
 }
@@ -0,0 +1,162 @@
+//! Checking and extracting sum-product normal form (SPNF) USr terms.
+
+use std::fmt;
+
+use crate::udp_lang::USr;
+use crate::udp_rewrites::rules;
+use crate::{CostFunction, Extractor, Id, Language, RecExpr, Runner};
+
+/// The outermost operator of a term, as far as sum-product normal form cares
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Shape {
+    /// A `sig`
+    Sig,
+    /// A `+`
+    Add,
+    /// A product, or an atom: `[]`, a relation, a squash, a negation, ...
+    Product,
+}
+
+impl Shape {
+    fn of(node: &USr) -> Shape {
+        match node {
+            USr::Sig(_) => Shape::Sig,
+            USr::Add(_) => Shape::Add,
+            _ => Shape::Product,
+        }
+    }
+}
+
+/// Why a term is not in sum-product normal form
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// `(* a (+ b c))`: the product should be distributed over the sum
+    SumUnderProduct,
+    /// `(* a (sig t b))`: the summation should be pulled above the product
+    SigUnderProduct,
+    /// `(sig t (+ a b))`: the summation should be split over the sum
+    SumUnderSig,
+    /// `(let v e b)`: the substitution should be carried out
+    Let,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Violation::SumUnderProduct => "a sum under a product; distribute the product over it",
+            Violation::SigUnderProduct => "a sig under a product; pull the summation to the top",
+            Violation::SumUnderSig => "a sum under a sig; split the summation over it",
+            Violation::Let => "a let; substitute it away",
+        })
+    }
+}
+
+fn violations(node: &USr, shape: impl Fn(Id) -> Shape) -> usize {
+    match node {
+        USr::Mul(cs) => cs
+            .iter()
+            .filter(|c| shape(**c) != Shape::Product)
+            .count(),
+        USr::Sig([_, body]) => (shape(*body) == Shape::Add) as usize,
+        USr::Let(_) => 1,
+        _ => 0,
+    }
+}
+
+/// Cost of a term for SPNF extraction: the number of places it breaks
+/// normal form, then its size
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct SpnfCost {
+    /// Number of [`Violation`]s in the term
+    pub violations: usize,
+    /// Number of nodes in the term
+    pub size: usize,
+    /// Outermost operator of the term
+    pub shape: Shape,
+}
+
+/// A [`CostFunction`] that prefers terms in sum-product normal form: sums of
+/// `sig`s over products of `[]` predicates and relation atoms
+pub struct Spnf;
+
+impl CostFunction<USr> for Spnf {
+    type Cost = SpnfCost;
+
+    fn cost<C>(&mut self, enode: &USr, mut costs: C) -> Self::Cost
+    where
+        C: FnMut(Id) -> Self::Cost,
+    {
+        let mut cost = SpnfCost {
+            violations: 0,
+            size: 1,
+            shape: Shape::of(enode),
+        };
+        let mut shapes = vec![];
+        for c in enode.children() {
+            let child = costs(*c);
+            cost.violations += child.violations;
+            cost.size += child.size;
+            shapes.push((*c, child.shape));
+        }
+        let shape = |id| shapes.iter().find(|(c, _)| *c == id).unwrap().1;
+        cost.violations += violations(enode, shape);
+        cost
+    }
+}
+
+/// A term that is not in sum-product normal form
+#[derive(Debug, Clone)]
+pub struct SpnfError {
+    /// The whole term
+    pub term: RecExpr<USr>,
+    /// The innermost subterm where normal form breaks
+    pub subterm: RecExpr<USr>,
+    /// What is wrong with the subterm
+    pub violation: Violation,
+}
+
+impl fmt::Display for SpnfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "not in SPNF: {} has {}\nin {}",
+            self.subterm, self.violation, self.term
+        )
+    }
+}
+
+impl std::error::Error for SpnfError {}
+
+/// Checks that `expr` is in sum-product normal form, pointing at the
+/// innermost offending subterm if not
+pub fn check_spnf(expr: &RecExpr<USr>) -> Result<(), SpnfError> {
+    let nodes = expr.as_ref();
+    let shape = |id: Id| Shape::of(&nodes[usize::from(id)]);
+    // children come before parents, so the first hit is an innermost one
+    for node in nodes {
+        if violations(node, shape) == 0 {
+            continue;
+        }
+        let violation = match node {
+            USr::Let(_) => Violation::Let,
+            USr::Sig(_) => Violation::SumUnderSig,
+            _ if node.any(|c| shape(c) == Shape::Add) => Violation::SumUnderProduct,
+            _ => Violation::SigUnderProduct,
+        };
+        return Err(SpnfError {
+            term: expr.clone(),
+            subterm: node.build_recexpr(|c| nodes[usize::from(c)].clone()),
+            violation,
+        });
+    }
+    Ok(())
+}
+
+/// Saturates `expr` with the UDP rules and extracts its sum-product normal form
+pub fn normalize(expr: &RecExpr<USr>) -> Result<RecExpr<USr>, SpnfError> {
+    let runner = Runner::default().with_expr(expr).run(&rules());
+    let extractor = Extractor::new(&runner.egraph, Spnf);
+    let (_, best) = extractor.find_best(runner.roots[0]);
+    check_spnf(&best)?;
+    Ok(best)
+}
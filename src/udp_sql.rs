@@ -0,0 +1,291 @@
+//! Translating a small subset of SQL queries into USr terms.
+
+use std::fmt;
+
+use crate::udp_lang::USr;
+use crate::RecExpr;
+
+/// An error translating between SQL and USr
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqlError {
+    /// Byte offset in the SQL text, for parse errors
+    pub offset: Option<usize>,
+    /// What went wrong
+    pub message: String,
+}
+
+impl SqlError {
+    pub(crate) fn new(offset: Option<usize>, message: impl Into<String>) -> Self {
+        SqlError {
+            offset,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} at byte {}", self.message, offset),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for SqlError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Int(i32),
+    Punct(&'static str),
+}
+
+fn tokenize(sql: &str) -> Result<Vec<(usize, Token)>, SqlError> {
+    const PUNCT: &[&str] = &["<>", "!=", ">=", "<=", ">", "<", "=", ".", "*", ",", "(", ")"];
+    let mut tokens = vec![];
+    let mut rest = sql;
+    loop {
+        rest = rest.trim_start();
+        let offset = sql.len() - rest.len();
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Ok(tokens),
+        };
+        let len = if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push((offset, Token::Ident(rest[..len].to_owned())));
+            len
+        } else if c.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let n = rest[..len]
+                .parse()
+                .map_err(|_| SqlError::new(Some(offset), "integer literal out of range"))?;
+            tokens.push((offset, Token::Int(n)));
+            len
+        } else if let Some(p) = PUNCT.iter().find(|p| rest.starts_with(**p)) {
+            tokens.push((offset, Token::Punct(p)));
+            p.len()
+        } else {
+            return Err(SqlError::new(Some(offset), format!("unexpected character {:?}", c)));
+        };
+        rest = &rest[len..];
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |(o, _)| *o)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, SqlError> {
+        Err(SqlError::new(Some(self.offset()), message))
+    }
+
+    fn peek_keyword(&self, kw: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some((_, Token::Ident(s))) if s.eq_ignore_ascii_case(kw))
+    }
+
+    fn eat_keyword(&mut self, kw: &str) -> bool {
+        let found = self.peek_keyword(kw);
+        self.pos += found as usize;
+        found
+    }
+
+    fn keyword(&mut self, kw: &str) -> Result<(), SqlError> {
+        match self.eat_keyword(kw) {
+            true => Ok(()),
+            false => self.error(format!("expected {}", kw)),
+        }
+    }
+
+    fn eat_punct(&mut self, p: &str) -> bool {
+        let found = matches!(self.tokens.get(self.pos), Some((_, Token::Punct(q))) if *q == p);
+        self.pos += found as usize;
+        found
+    }
+
+    fn punct(&mut self, p: &str) -> Result<(), SqlError> {
+        match self.eat_punct(p) {
+            true => Ok(()),
+            false => self.error(format!("expected {:?}", p)),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, SqlError> {
+        match self.tokens.get(self.pos) {
+            Some((_, Token::Ident(s))) if !is_keyword(s) => {
+                self.pos += 1;
+                Ok(s.clone())
+            }
+            _ => self.error("expected a name"),
+        }
+    }
+
+    // query := select (UNION ALL select)*
+    fn query(&mut self, output: Option<&str>) -> Result<String, SqlError> {
+        let mut selects = vec![self.select(output)?];
+        while self.eat_keyword("UNION") {
+            self.keyword("ALL")?;
+            selects.push(self.select(output)?);
+        }
+        Ok(nest("+", selects))
+    }
+
+    // select := SELECT [DISTINCT] (* | alias.*) FROM tables [WHERE conds]
+    fn select(&mut self, output: Option<&str>) -> Result<String, SqlError> {
+        self.keyword("SELECT")?;
+        let distinct = self.eat_keyword("DISTINCT");
+        let selected_at = self.offset();
+        let selected = match self.eat_punct("*") {
+            true => None,
+            false => {
+                let alias = self.ident()?;
+                self.punct(".")?;
+                self.punct("*")?;
+                Some(alias)
+            }
+        };
+
+        self.keyword("FROM")?;
+        let mut tables = vec![];
+        loop {
+            let at = self.offset();
+            let table = self.ident()?;
+            if !table.starts_with(|c: char| c.is_ascii_uppercase()) {
+                return Err(SqlError::new(
+                    Some(at),
+                    format!("table {} must start with an uppercase letter", table),
+                ));
+            }
+            self.eat_keyword("AS");
+            let at = self.offset();
+            let alias = self.ident()?;
+            // the output guard would be bound by the alias' own `sig`
+            if output == Some(alias.as_str()) {
+                return Err(SqlError::new(
+                    Some(at),
+                    format!("alias {} is the name of the output tuple", alias),
+                ));
+            }
+            tables.push((table, alias));
+            if !self.eat_punct(",") {
+                break;
+            }
+        }
+
+        let mut factors = vec![];
+        if let Some(t) = output {
+            let alias = match (selected, tables.as_slice()) {
+                (Some(alias), _) => alias,
+                (None, [(_, alias)]) => alias.clone(),
+                (None, _) => {
+                    return Err(SqlError::new(
+                        Some(selected_at),
+                        "SELECT * from several tables has no single output tuple; use alias.*",
+                    ))
+                }
+            };
+            if !tables.iter().any(|(_, a)| *a == alias) {
+                return Err(SqlError::new(Some(selected_at), format!("unknown alias {}", alias)));
+            }
+            factors.push(format!("([] (= (var {}) (var {})))", t, alias));
+        }
+        if self.eat_keyword("WHERE") {
+            loop {
+                factors.push(self.condition()?);
+                if !self.eat_keyword("AND") {
+                    break;
+                }
+            }
+        }
+        factors.extend(tables.iter().map(|(r, a)| format!("({} (var {}))", r, a)));
+
+        let mut body = nest("*", factors);
+        for (_, alias) in tables.iter().rev() {
+            body = format!("(sig {} {})", alias, body);
+        }
+        Ok(match distinct {
+            true => format!("(|| {})", body),
+            false => body,
+        })
+    }
+
+    // cond := [NOT] EXISTS (query) | operand op operand
+    fn condition(&mut self) -> Result<String, SqlError> {
+        let negated = self.eat_keyword("NOT");
+        if negated || self.peek_keyword("EXISTS") {
+            self.keyword("EXISTS")?;
+            self.punct("(")?;
+            let query = self.query(None)?;
+            self.punct(")")?;
+            let op = if negated { "not" } else { "||" };
+            return Ok(format!("({} {})", op, query));
+        }
+
+        let lhs = self.operand()?;
+        let op = match self.tokens.get(self.pos) {
+            Some((_, Token::Punct(p))) if ["=", "<>", "!=", ">=", "<=", ">", "<"].contains(p) => *p,
+            _ => return self.error("expected a comparison"),
+        };
+        self.pos += 1;
+        let rhs = self.operand()?;
+        let op = if op == "<>" { "!=" } else { op };
+        Ok(format!("([] ({} {} {}))", op, lhs, rhs))
+    }
+
+    // operand := alias.attribute | integer
+    fn operand(&mut self) -> Result<String, SqlError> {
+        if let Some((_, Token::Int(n))) = self.tokens.get(self.pos) {
+            let n = *n;
+            self.pos += 1;
+            return Ok(n.to_string());
+        }
+        let alias = self.ident()?;
+        self.punct(".")?;
+        let attr = self.ident()?;
+        Ok(format!("(. (var {}) {})", alias, attr))
+    }
+}
+
+fn is_keyword(s: &str) -> bool {
+    ["SELECT", "DISTINCT", "FROM", "AS", "WHERE", "AND", "NOT", "EXISTS", "UNION", "ALL"]
+        .iter()
+        .any(|kw| s.eq_ignore_ascii_case(kw))
+}
+
+// right-nested binary application, so `a * b * c` is `(* a (* b c))`
+fn nest(op: &str, mut terms: Vec<String>) -> String {
+    let mut acc = terms.pop().unwrap();
+    while let Some(t) = terms.pop() {
+        acc = format!("({} {} {})", op, t, acc);
+    }
+    acc
+}
+
+/// Translates a SQL query to a USr term whose free variable `output` is the result tuple.
+///
+/// This covers `SELECT [DISTINCT] alias.* FROM ... WHERE ...` blocks joined by
+/// `UNION ALL`, with comparisons of attributes and integers and
+/// `[NOT] EXISTS` subqueries as conditions.
+pub fn parse(sql: &str, output: &str) -> Result<RecExpr<USr>, SqlError> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        pos: 0,
+        end: sql.len(),
+    };
+    let term = parser.query(Some(output))?;
+    if parser.pos < parser.tokens.len() {
+        return parser.error("unexpected input after the query");
+    }
+    term.parse()
+        .map_err(|e| SqlError::new(None, format!("produced a bad term {}: {}", term, e)))
+}
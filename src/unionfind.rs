@@ -5,6 +5,9 @@ use std::fmt::Debug;
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 pub struct UnionFind {
     parents: Vec<Id>,
+    /// The old parents of sets whose parent changed, if recording
+    #[cfg_attr(feature = "serde-1", serde(skip))]
+    trail: Option<Vec<(Id, Id)>>,
 }
 
 impl UnionFind {
@@ -22,8 +25,36 @@ impl UnionFind {
         self.parents[usize::from(query)]
     }
 
-    fn parent_mut(&mut self, query: Id) -> &mut Id {
-        &mut self.parents[usize::from(query)]
+    fn set_parent(&mut self, query: Id, parent: Id) {
+        let old = std::mem::replace(&mut self.parents[usize::from(query)], parent);
+        if let Some(trail) = &mut self.trail {
+            trail.push((query, old));
+        }
+    }
+
+    /// Starts recording changes so they can be undone with [`UnionFind::undo`].
+    pub fn record(&mut self) {
+        self.trail.get_or_insert_with(Vec::new);
+    }
+
+    /// Stops recording changes and forgets the recorded ones.
+    pub fn stop_recording(&mut self) {
+        self.trail = None;
+    }
+
+    /// The number of changes recorded so far.
+    pub fn recorded(&self) -> usize {
+        self.trail.as_ref().map_or(0, |trail| trail.len())
+    }
+
+    /// Undoes the changes recorded after the first `recorded` ones,
+    /// and forgets the sets made after there were `size` of them.
+    pub fn undo(&mut self, size: usize, recorded: usize) {
+        let trail = self.trail.as_mut().expect("not recording");
+        for (query, old) in trail.drain(recorded..).rev() {
+            self.parents[usize::from(query)] = old;
+        }
+        self.parents.truncate(size);
     }
 
     pub fn find(&self, mut current: Id) -> Id {
@@ -36,7 +67,7 @@ impl UnionFind {
     pub fn find_mut(&mut self, mut current: Id) -> Id {
         while current != self.parent(current) {
             let grandparent = self.parent(self.parent(current));
-            *self.parent_mut(current) = grandparent;
+            self.set_parent(current, grandparent);
             current = grandparent;
         }
         current
@@ -44,7 +75,7 @@ impl UnionFind {
 
     /// Given two leader ids, unions the two eclasses making root1 the leader.
     pub fn union(&mut self, root1: Id, root2: Id) -> Id {
-        *self.parent_mut(root2) = root1;
+        self.set_parent(root2, root1);
         root1
     }
 }
@@ -89,4 +120,28 @@ mod tests {
         let expected = vec![0, 0, 0, 0, 4, 5, 6, 6, 6, 6];
         assert_eq!(uf.parents, ids(expected));
     }
+
+    #[test]
+    fn union_find_undo() {
+        let id = Id::from;
+
+        let mut uf = UnionFind::default();
+        for _ in 0..4 {
+            uf.make_set();
+        }
+        uf.union(id(0), id(1));
+        uf.union(id(2), id(3));
+        let before = uf.parents.clone();
+
+        uf.record();
+        let (size, recorded) = (uf.size(), uf.recorded());
+        uf.make_set();
+        uf.union(id(0), id(2));
+        uf.union(id(0), id(4));
+        uf.find_mut(id(3));
+        assert_eq!(uf.parents, ids(vec![0, 0, 0, 0, 0]));
+
+        uf.undo(size, recorded);
+        assert_eq!(uf.parents, before);
+    }
 }
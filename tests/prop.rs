@@ -11,19 +11,24 @@ define_language! {
     }
 }
 
-type EGraph = egg::EGraph<Prop, ConstantFold>;
-type Rewrite = egg::Rewrite<Prop, ConstantFold>;
+// constant folding is a part of a product analysis, so more can go next to it
+type EGraph = egg::EGraph<Prop, (ConstantFold, ())>;
+type Rewrite = egg::Rewrite<Prop, (ConstantFold, ())>;
 
 #[derive(Default)]
 struct ConstantFold;
-impl Analysis<Prop> for ConstantFold {
+impl SubAnalysis<Prop> for ConstantFold {
     type Data = Option<(bool, PatternAst<Prop>)>;
     fn merge(&mut self, to: &mut Self::Data, from: Self::Data) -> DidMerge {
         merge_max(to, from)
     }
 
-    fn make(egraph: &EGraph, enode: &Prop) -> Self::Data {
-        let x = |i: &Id| egraph[*i].data.as_ref().map(|c| c.0);
+    fn make<N: Analysis<Prop>>(
+        egraph: &egg::EGraph<Prop, N>,
+        enode: &Prop,
+        part: impl Fn(&N::Data) -> &Self::Data,
+    ) -> Self::Data {
+        let x = |i: &Id| part(&egraph[*i].data).as_ref().map(|c| c.0);
         let result = match enode {
             Prop::Bool(c) => Some((*c, c.to_string().parse().unwrap())),
             Prop::Symbol(_) => None,
@@ -45,8 +50,12 @@ impl Analysis<Prop> for ConstantFold {
         result
     }
 
-    fn modify(egraph: &mut EGraph, id: Id) {
-        if let Some(c) = egraph[id].data.clone() {
+    fn modify<N: Analysis<Prop>>(
+        egraph: &mut egg::EGraph<Prop, N>,
+        id: Id,
+        part: impl Fn(&N::Data) -> &Self::Data,
+    ) {
+        if let Some(c) = part(&egraph[id].data).clone() {
             egraph.union_instantiations(
                 &c.1,
                 &c.0.to_string().parse().unwrap(),
@@ -66,6 +66,68 @@ fn udp_equality_semantics() {
     prove_eqs(&vec!["(sig t (* (var t) ([] (= (var t) (var e)))))","(var e)"], &rules())
 }
 
+#[test]
+fn udp_gc() {
+    let exprs = ["(|| (+ (* (var a) (|| (var x))) (var y)))", "(|| (+ (* (var a) (var x)) (var y)))"];
+    let mut runner = Runner::default().with_gc_every(1);
+    for e in &exprs {
+        runner = runner.with_expr(&e.parse().unwrap());
+    }
+    // nothing reaches this, so it is collected after the first iteration
+    let garbage = "(sig t (* (var t) (var z)))".parse().unwrap();
+    runner.egraph.add_expr(&garbage);
+    let runner = runner.run(&rules());
+    assert_eq!(runner.egraph.find(runner.roots[0]), runner.egraph.find(runner.roots[1]));
+    assert_eq!(runner.egraph.lookup_expr(&garbage), None);
+    let z = runner.egraph.lookup_expr(&"z".parse().unwrap());
+    assert_eq!(z, None);
+}
+
+#[test]
+#[should_panic(expected = "Can't collect garbage with explanations enabled")]
+fn udp_gc_explanations() {
+    let e = "(|| (+ (* (var a) (|| (var x))) (var y)))".parse().unwrap();
+    Runner::default().with_gc_every(1).with_explanations_enabled().with_expr(&e).run(&rules());
+}
+
+#[test]
+fn udp_analysis_checks() {
+    let exprs = ["(|| (+ (* (var a) (|| (var x))) (var y)))", "(|| (+ (* (var a) (var x)) (var y)))"];
+    let mut runner = Runner::default().with_analysis_checks();
+    for e in &exprs {
+        runner = runner.with_expr(&e.parse().unwrap());
+    }
+    let runner = runner.run(&rules());
+    assert_eq!(runner.egraph.find(runner.roots[0]), runner.egraph.find(runner.roots[1]));
+}
+
+#[test]
+fn udp_stats() {
+    let runner = Runner::default()
+        .with_stats()
+        .with_iter_limit(3)
+        .with_expr(&"(|| (+ (* (var a) (|| (var x))) (var y)))".parse().unwrap())
+        .run(&rules());
+    assert!(runner.iterations.iter().all(|i| i.stats.is_some()));
+    let stats = runner.iterations.last().unwrap().stats.as_ref().unwrap();
+    assert_eq!(stats.nodes, runner.egraph.total_number_of_nodes());
+    let vars = stats.nodes_by_op.iter().find(|(op, _)| op == "Var");
+    assert_eq!(vars.map(|(_, n)| *n), Some(3));
+}
+
+#[test]
+fn udp_count_terms() {
+    let runner = Runner::default()
+        .with_iter_limit(3)
+        .with_expr(&"(|| (+ (* (var a) (|| (var x))) (var y)))".parse().unwrap())
+        .run(&rules());
+    let root = runner.roots[0];
+    // the query has 10 nodes, and saturation found other ways to write it
+    let n = runner.egraph.count_terms(root, 10);
+    assert!(n > 1);
+    assert_eq!(runner.egraph.enumerate_terms(root, 10).count() as u64, n);
+}
+
 #[test]
 fn udp_spnf() {
     prove_eqs(&vec![
@@ -93,3 +155,345 @@ fn udp_spnf() {
                             ([] (>= (. t1 a) 12))))))))",
     ], &rules())
 }
+
+#[test]
+fn udp_ac_rules() {
+    // sums added separately are only unified by the assoc and comm rules
+    prove_eqs(&["(* (var a) (var b))", "(* (var b) (var a))"], &rules());
+    prove_eqs(&["(+ (+ (var a) (var b)) (var c))", "(+ (var c) (+ (var b) (var a)))"], &rules());
+}
+
+#[test]
+fn udp_ac_canonical() {
+    let product = "(sig t1 (sig t2 (sig t3
+        (* ([] (= t2 t))
+            (* ([] (= (. t1 k) (. t2 k)))
+                (* ([] (>= (. t1 a) 12))
+                    (* (R t3) (R t2))))))))";
+    let expr: RecExpr<USr> = product.parse().unwrap();
+    let flat = expr.flatten_ac();
+    assert!(flat.as_ref().len() < expr.as_ref().len());
+    assert_eq!(flat.binarize_ac().flatten_ac(), flat);
+
+    let mut plain = EGraph::<USr, UAnalysis>::default();
+    plain.add_expr(&expr);
+    let mut egraph = EGraph::<USr, UAnalysis>::default().with_ac_canonicalization();
+    egraph.add_expr(&expr);
+    assert!(egraph.total_size() < plain.total_size());
+
+    let a = egraph.add_expr(&"(* (var a) (* (var b) (var c)))".parse().unwrap());
+    let b = egraph.add_expr(&"(* (* (var c) (var a)) (var b))".parse().unwrap());
+    assert_eq!(a, b);
+
+    // binary rules still apply to the n-ary nodes
+    let squashed = "(|| (* ([] (= (var a) (var b))) (* (not (var c)) ([] (= (var c) (var d))))))";
+    let runner = Runner::default()
+        .with_egraph(EGraph::default().with_ac_canonicalization())
+        .with_expr(&squashed.parse().unwrap())
+        .run(&rules());
+    let (_, best) = Extractor::new(&runner.egraph, AstSize).find_best(runner.roots[0]);
+    assert!(matches!(best.as_ref().last(), Some(USr::Mul(cs)) if cs.len() == 3));
+}
+
+#[test]
+fn udp_rule_file() {
+    let text = "
+# the let rules of udp_rewrites::rules, as a rule file
+[let]
+let-const: (let ?v ?e ?c) => ?c if not_free(?v, ?c)
+let-var-same: (let ?v ?e (var ?v)) => ?e
+let-var-diff: (let ?v1 ?e (var ?v2)) => (var ?v2) if !match ?v1 in ?v2
+let-sig-diff-free: (let ?v1 ?e (sig ?v2 ?body))
+    => rename_sig(?fresh, (sig ?fresh (let ?v1 ?e (let ?v2 ?fresh ?body))))
+    if !match ?v1 in ?v2
+    if free(?v2, ?e)
+let-sig-diff-bound: (let ?v1 ?e (sig ?v2 ?body)) => (sig ?v2 (let ?v1 ?e ?body))
+    if !match ?v1 in ?v2
+    if not_free(?v2, ?e)
+let-mul: (let ?v ?e (* ?a ?b)) => (* (let ?v ?e ?a) (let ?v ?e ?b))
+
+[arith]
+add-0: (+ ?a 0) <=> ?a
+";
+    let registry = registry();
+    let rls = registry.parse_tagged_rules(text, "let").unwrap();
+    assert_eq!(rls.len(), 6);
+    assert_eq!(registry.parse_rules(text).unwrap().len(), 8);
+    prove_eqs(&[
+        "(let x (var y) (sig t (* (var x) (var t))))",
+        "(sig t (* (var y) (var t)))",
+    ], &rls);
+
+    let err = registry.parse_rules("\nlet-const: (let ?v ?e ?c) => ?c if not_free(?v)").unwrap_err();
+    assert_eq!(err.to_string(), "line 2: rule let-const: expected two variables");
+}
+
+#[test]
+fn udp_lint() {
+    let lints: Vec<String> = lint_rules(&rules()).iter().map(|l| l.to_string()).collect();
+    assert_eq!(lints, vec![
+        "rule add-zero matches every e-class",
+        "rule mul-one matches every e-class",
+        "rule 5-rev matches every e-class",
+        "rule 6-rev matches every e-class",
+        "rule 8 rewrites its left-hand side to itself",
+    ]);
+}
+
+#[test]
+fn udp_analysis_facts() {
+    let mut egraph = EGraph::<USr, UAnalysis>::default();
+    let sum = egraph.add_expr(&"(+ 2 (* 3 1))".parse().unwrap());
+    let pred = egraph.add_expr(&"(* ([] (= (var a) (var b))) (not (var c)))".parse().unwrap());
+    let nonzero = egraph.add_expr(&"(+ (R (var x)) (|| 1))".parse().unwrap());
+    egraph.rebuild();
+
+    assert_eq!(egraph[sum].data.constant, Some(5));
+    assert_eq!(Some(sum), egraph.lookup_expr(&"5".parse().unwrap()));
+    assert!(egraph[pred].data.pred);
+    assert!(!egraph[pred].data.nonzero);
+    assert!(egraph[nonzero].data.nonzero);
+    assert!(!egraph[nonzero].data.pred);
+    for name in UAnalysis::predicates() {
+        assert!(egraph.analysis.predicate(name, &egraph[sum].data).is_some(), "{}", name);
+    }
+
+    // an unsound union of two constants keeps one of them
+    let three = egraph.lookup_expr(&"3".parse().unwrap()).unwrap();
+    egraph.union(sum, three);
+    egraph.rebuild();
+    assert!(egraph[sum].data.constant.is_some());
+}
+
+#[test]
+fn udp_squash_predicates() {
+    prove_eqs(&vec![
+        "(|| (* ([] (= (var a) (var b))) (not (var c))))",
+        "(* ([] (= (var a) (var b))) (not (var c)))",
+    ], &rules());
+    prove_eqs(&vec!["(not (+ (var x) 1))", "0"], &rules());
+}
+
+#[test]
+fn udp_eval_database() {
+    use egg::udp_eval::*;
+    let mut db = Database::default();
+    db.domain = vec![vec![("a".into(), 3)], vec![("a".into(), 20)]];
+    let rel = db.relations.entry("R".into()).or_default();
+    rel.insert(vec![Value::Tuple(0)], 2);
+    rel.insert(vec![Value::Tuple(1)], 1);
+
+    let count = "(sig t (R (var t)))".parse().unwrap();
+    let filtered = "(sig t (* ([] (>= (. (var t) a) 12)) (R (var t))))".parse().unwrap();
+    let distinct = "(sig t (|| (R (var t))))".parse().unwrap();
+    let env = Default::default();
+    assert_eq!(db.eval(&count, &env), Ok(Value::Int(3)));
+    assert_eq!(db.eval(&filtered, &env), Ok(Value::Int(1)));
+    assert_eq!(db.eval(&distinct, &env), Ok(Value::Int(2)));
+
+    let big = "(* 2147483647 2147483647 2147483647)".parse().unwrap();
+    assert_eq!(db.eval(&big, &env), Err(EvalError::Overflow("product")));
+    let attr = "(. (var t) a)".parse().unwrap();
+    let env = vec![("t".into(), Value::Tuple(5))].into_iter().collect();
+    assert_eq!(db.eval(&attr, &env), Err(EvalError::NoTuple(5)));
+}
+
+#[test]
+fn udp_refute() {
+    use egg::udp_eval::*;
+    let bag = "(sig t (R (var t)))".parse().unwrap();
+    let set = "(|| (sig t (R (var t))))".parse().unwrap();
+    match check(&bag, &set, &rules(), &Config::default()) {
+        Verdict::Refuted(cex) => assert_ne!(cex.lhs, cex.rhs),
+        v => panic!("expected a counterexample, got {:?}", v),
+    }
+
+    let lhs = "(|| (+ (* (var a) (|| (var x))) (var y)))".parse().unwrap();
+    let rhs = "(|| (+ (* (var a) (var x)) (var y)))".parse().unwrap();
+    assert!(find_counterexample(&lhs, &rhs, &Config::default()).is_none());
+}
+
+#[test]
+fn udp_normalize_spnf() {
+    use egg::udp_spnf::*;
+    let nested: RecExpr<USr> = "(sig t1
+        (* (R (var t1))
+            (sig t2 (* ([] (= (var t1) (var t2))) (R (var t2))))))"
+        .parse()
+        .unwrap();
+    let err = check_spnf(&nested).unwrap_err();
+    assert_eq!(err.violation, Violation::SigUnderProduct);
+    assert!(err.to_string().contains("pull the summation to the top"));
+
+    let normal = normalize(&nested).unwrap();
+    check_spnf(&normal).unwrap();
+    assert!(matches!(normal.as_ref().last(), Some(USr::Sig(_))));
+
+    let sum: RecExpr<USr> = "(* (R (var t)) (+ (var a) (var b)))".parse().unwrap();
+    assert_eq!(check_spnf(&sum).unwrap_err().violation, Violation::SumUnderProduct);
+    check_spnf(&normalize(&sum).unwrap()).unwrap();
+}
+
+#[test]
+fn udp_sorts() {
+    use egg::udp_lang::*;
+    let schema = Schema::default();
+    assert!(parse("(sig t (* ([] (>= (. (var t) a) 12)) (R (var t))))", &schema).is_ok());
+    assert!(matches!(parse("(* (var t) (R t))", &schema), Err(ParseError::Sort(_))));
+    assert!(matches!(parse("(>= (R (var t)) 1)", &schema), Err(ParseError::Sort(_))));
+    assert!(matches!(parse("(R (+ 1 1))", &schema), Err(ParseError::Sort(_))));
+
+    let mut schema = Schema::default();
+    schema.relations.insert("R".into(), vec![Sort::Tuple]);
+    schema.attributes.insert("a".into());
+    assert!(parse("(R (var t) (var s))", &schema).is_err());
+    assert!(parse("(S (var t))", &schema).is_err());
+    assert!(parse("(. (var t) b)", &schema).is_err());
+
+    let mut egraph = EGraph::new(UAnalysis);
+    let bound = egraph.add_expr(&"(let u (var s) (var t))".parse().unwrap());
+    let rel = egraph.add_expr(&"(R t)".parse().unwrap());
+    let value = egraph.add_expr(&"(. (var t) a)".parse().unwrap());
+    let t = egraph.add_expr(&"t".parse().unwrap());
+    egraph.rebuild();
+    assert_eq!(egraph[rel].data.sort, Some(Sort::Semiring));
+    assert_eq!(egraph[value].data.sort, Some(Sort::Value));
+    assert_eq!(egraph[bound].data.sort, Some(Sort::Tuple));
+    // names get no sort, but aren't semiring elements either
+    assert_eq!(egraph[t].data.sort, None);
+    assert_eq!(egraph.analysis.predicate("semiring", &egraph[t].data), Some(false));
+    assert_eq!(egraph.analysis.predicate("semiring", &egraph[rel].data), Some(true));
+    assert_eq!(egraph.analysis.predicate("semiring", &egraph[value].data), Some(false));
+    // the analysis is made from the enodes alone, so it checks out
+    egraph.check_analysis().unwrap();
+}
+
+#[test]
+fn udp_print_math() {
+    use egg::udp_print::*;
+    let e: RecExpr<USr> = "(sig t1 (sig t2 (* ([] (>= (. (var t1) a) 12)) (|| (+ (R (var t1)) (not (S (var t2))))))))"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        to_math(&e, Notation::Unicode),
+        "Σ_t1 Σ_t2 [t1.a ≥ 12] × ‖R(t1) + not(S(t2))‖"
+    );
+    assert_eq!(
+        to_math(&e, Notation::Latex),
+        "\\sum_{t1} \\sum_{t2} [t1.a \\geq 12] \\times \\lVert R(t1) + \\operatorname{not}(S(t2)) \\rVert"
+    );
+    let e: RecExpr<USr> = "(* (sig t (R (var t))) (+ (var a) (var b)))".parse().unwrap();
+    assert_eq!(to_math(&e, Notation::Unicode), "(Σ_t R(t)) × (a + b)");
+}
+
+// Generates queries in the exact form `udp_print::to_sql` writes them
+struct SqlGen {
+    seed: u64,
+    aliases: usize,
+}
+
+impl SqlGen {
+    fn below(&mut self, n: u64) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed % n
+    }
+
+    fn operand(&mut self, scope: &[String]) -> String {
+        match self.below(3) {
+            0 => self.below(20).to_string(),
+            _ => {
+                let alias = &scope[self.below(scope.len() as u64) as usize];
+                format!("{}.{}", alias, ["a", "b"][self.below(2) as usize])
+            }
+        }
+    }
+
+    fn select(&mut self, scope: &[String], top: bool, depth: usize) -> String {
+        let mut scope = scope.to_vec();
+        let mut from = vec![];
+        for _ in 0..1 + self.below(2) {
+            let alias = format!("x{}", self.aliases);
+            self.aliases += 1;
+            from.push(format!("{} {}", ["R", "S"][self.below(2) as usize], alias));
+            scope.push(alias);
+        }
+        let mut conditions = vec![];
+        for _ in 0..self.below(3) {
+            if depth > 0 && self.below(3) == 0 {
+                let not = if self.below(2) == 0 { "NOT " } else { "" };
+                conditions.push(format!("{}EXISTS ({})", not, self.query(&scope, false, depth - 1)));
+            } else {
+                let op = ["=", "<>", ">=", "<=", ">", "<"][self.below(6) as usize];
+                conditions.push(format!("{} {} {}", self.operand(&scope), op, self.operand(&scope)));
+            }
+        }
+        let selected = match top {
+            true => format!("{}.*", scope[scope.len() - from.len() + self.below(from.len() as u64) as usize]),
+            false => "*".into(),
+        };
+        let distinct = if self.below(3) == 0 { "DISTINCT " } else { "" };
+        let mut sql = format!("SELECT {}{} FROM {}", distinct, selected, from.join(", "));
+        if !conditions.is_empty() {
+            sql += " WHERE ";
+            sql += &conditions.join(" AND ");
+        }
+        sql
+    }
+
+    fn query(&mut self, scope: &[String], top: bool, depth: usize) -> String {
+        let mut sql = self.select(scope, top, depth);
+        while self.below(4) == 0 {
+            sql = format!("{} UNION ALL {}", sql, self.select(scope, top, depth));
+        }
+        sql
+    }
+}
+
+#[test]
+fn udp_sql_round_trip() {
+    use egg::{udp_print, udp_sql};
+    let mut gen = SqlGen { seed: 0x2545_f491, aliases: 0 };
+    for _ in 0..300 {
+        gen.aliases = 0;
+        let sql = gen.query(&[], true, 2);
+        let term = udp_sql::parse(&sql, "t").unwrap_or_else(|e| panic!("{}: {}", sql, e));
+        let printed = udp_print::to_sql(&term, "t").unwrap_or_else(|e| panic!("{}: {}", term, e));
+        assert_eq!(printed, sql);
+        assert_eq!(udp_sql::parse(&printed, "t").unwrap(), term);
+    }
+}
+
+#[test]
+fn udp_sql_errors() {
+    use egg::{udp_print, udp_sql};
+    let err = udp_sql::parse("SELECT x.* FROM R x WHERE x.a = ", "t").unwrap_err();
+    assert_eq!(err.offset, Some(32));
+    assert!(udp_sql::parse("SELECT * FROM R x, S y", "t").is_err());
+    assert!(udp_sql::parse("SELECT x.* FROM r x", "t").is_err());
+    let err = udp_sql::parse("SELECT * FROM R t", "t").unwrap_err();
+    assert_eq!(err.offset, Some(16));
+    assert!(udp_sql::parse("SELECT * FROM R t", "u").is_ok());
+    let err = udp_sql::parse("SELECT x.* FROM R x WHERE x.a = 3000000000", "t").unwrap_err();
+    assert_eq!(err.offset, Some(32));
+
+    let e = "(sig x (* ([] (= (var t) (var x))) (* (R (var x)) (S (var x)))))".parse().unwrap();
+    let err = udp_print::to_sql(&e, "t").unwrap_err();
+    assert!(err.message.contains("one summed tuple"), "{}", err);
+}
+
+#[test]
+fn udp_corpus() {
+    use egg::udp_corpus::*;
+    let corpus: Corpus = include_str!("udp.corpus").parse().unwrap();
+    let report = run_corpus(&corpus, BackoffScheduler::default);
+    assert_eq!(report.unexpected().count(), 0, "\n{}", report);
+    #[cfg(feature = "reports")]
+    assert!(report.to_json().contains("\"stop_reason\""));
+
+    let err = "case a\n    lhs (+ 1\n".parse::<Corpus>().err().unwrap();
+    assert_eq!(err.line, 2);
+    let err = "case a\n    lhs 1\n    expect maybe\n".parse::<Corpus>().err().unwrap();
+    assert_eq!(err.line, 3);
+}